@@ -0,0 +1,95 @@
+#[macro_use]
+extern crate criterion;
+extern crate erl_pp;
+extern crate erl_tokenize;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use erl_pp::{IncrementalPreprocessor, Preprocessor};
+use erl_tokenize::Lexer;
+use std::fmt::Write;
+
+/// Generates a module which resembles generated code (e.g., protocol buffers bindings):
+/// many small functions, each of which calls several nested and overloaded macros.
+fn generate_module(functions: usize) -> String {
+    let mut text = String::from(
+        r#"-module(generated).
+-define(PAIR(A, B), {A, B}).
+-define(WRAP(X), ?PAIR(X, ?LINE)).
+-define(WRAP(X, Y), ?PAIR(?WRAP(X), ?WRAP(Y))).
+-define(CONST, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]).
+-define(LOG(Fmt, Args), io:format(?MODULE_STRING ++ ": " ++ Fmt, Args)).
+-define(FIELD(Name, Value), #{name => ??Name, value => Value}).
+-define(CASE(X, A, B), case X of true -> A; false -> B end).
+"#,
+    );
+    for i in 0..functions {
+        writeln!(
+            text,
+            r#"f{i}(X, Y) ->
+    ?LOG("~p ~p", [?WRAP(X, Y), ?CONST]),
+    ?CASE(X > Y, ?FIELD(f{i}, [X | ?CONST]), ?FIELD(g{i}, {{Y, ?WRAP(X)}})),
+    ?WRAP(?PAIR(fun(Z) -> Z + {i} end, begin X, Y end))."#,
+            i = i
+        )
+        .unwrap();
+    }
+    text
+}
+
+fn preprocess(c: &mut Criterion) {
+    let mut group = c.benchmark_group("preprocess");
+    group.sample_size(10);
+    for &functions in &[1_000, 10_000] {
+        let text = generate_module(functions);
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(functions), &text, |b, text| {
+            b.iter(|| Preprocessor::new(Lexer::new(text)).count())
+        });
+    }
+    group.finish();
+}
+
+fn forms(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forms");
+    group.sample_size(10);
+    for &functions in &[1_000, 10_000] {
+        let text = generate_module(functions);
+        group.throughput(Throughput::Bytes(text.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(functions), &text, |b, text| {
+            b.iter(|| Preprocessor::new(Lexer::new(text)).forms().count())
+        });
+    }
+    group.finish();
+}
+
+fn incremental(c: &mut Criterion) {
+    let mut group = c.benchmark_group("incremental");
+    group.sample_size(10);
+    let functions = 10_000;
+    let text = generate_module(functions);
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.bench_function("preprocess", |b| {
+        b.iter(|| {
+            IncrementalPreprocessor::new(text.as_str())
+                .preprocess()
+                .unwrap()
+        })
+    });
+
+    group.finish();
+
+    let mut group = c.benchmark_group("incremental_edit");
+    group.sample_size(10);
+    let mut pp = IncrementalPreprocessor::new(text.as_str());
+    pp.preprocess().unwrap();
+    let offset = text.rfind("?CONST]),").unwrap() + 1;
+    group.bench_function("edit_last_form", |b| {
+        b.iter(|| {
+            pp.edit(offset..offset + 5, "CONST").unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, preprocess, forms, incremental);
+criterion_main!(benches);
@@ -38,7 +38,7 @@ fn main() {
     }
 
     let mut src = String::new();
-    let mut file = File::open(&src_file).expect("Cannot open file");
+    let mut file = File::open(src_file).expect("Cannot open file");
     file.read_to_string(&mut src).expect("Cannot read file");
 
     let start_time = Instant::now();
@@ -55,11 +55,14 @@ fn main() {
     }
     preprocessor.macros_mut().insert(
         "MODULE".to_string(),
-        MacroDef::Dynamic(vec![AtomToken::from_value(
-            src_file.file_stem().unwrap().to_str().unwrap(),
-            Position::new(),
-        )
-        .into()]),
+        vec![MacroDef::Dynamic(
+            vec![AtomToken::from_value(
+                src_file.file_stem().unwrap().to_str().unwrap(),
+                Position::new(),
+            )
+            .into()]
+            .into(),
+        )],
     );
 
     for result in preprocessor {
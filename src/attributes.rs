@@ -0,0 +1,400 @@
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+use std::path::PathBuf;
+
+use crate::util;
+use crate::{Directive, Error, ErrorKind, Form, Preprocessor, Record, Result, Term};
+
+/// Module attribute extracted from the output of a `Preprocessor`.
+///
+/// See `Preprocessor::attributes`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{AttributeValue, Preprocessor};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let src = r#"-module(foo).
+/// -define(API, [start/0, stop/1]).
+/// -export(?API).
+/// -spec start() -> ok.
+/// start() -> ok."#;
+/// let mut pp = Preprocessor::new(Lexer::new(src));
+/// let attrs = pp.attributes().collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(attrs.iter().map(|a| &a.name[..]).collect::<Vec<_>>(),
+///            ["module", "export", "spec"]);
+///
+/// if let AttributeValue::Export(ref functions) = attrs[1].value {
+///     assert_eq!(functions[1].name, "stop");
+///     assert_eq!(functions[1].arity, 1);
+///     assert_eq!(functions[1].position.line(), 3); // The position of `?API`
+/// } else {
+///     panic!();
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    /// The name of the attribute (e.g., `"export"`).
+    pub name: String,
+
+    /// The value of the attribute.
+    pub value: AttributeValue,
+
+    /// The start position of the attribute in the source code (before expansion).
+    ///
+    /// If the attribute is in an included file, the position points to the file.
+    pub position: Position,
+}
+
+/// Value of a module attribute.
+///
+/// The positions in values are mapped back to the source code (see `Form::source_position`).
+#[derive(Debug, Clone)]
+pub enum AttributeValue {
+    /// `-module(Name).`
+    Module(String),
+
+    /// `-export([Name/Arity, ...]).`
+    Export(Vec<NameArity>),
+
+    /// `-export_type([Name/Arity, ...]).`
+    ExportType(Vec<NameArity>),
+
+    /// `-behaviour(Module).` or `-behavior(Module).`
+    Behaviour(String),
+
+    /// `-include(Path).` or `-include_lib(Path).`
+    ///
+    /// Only the directives which are actually processed are extracted.
+    Include {
+        /// The path written in the directive.
+        path: String,
+
+        /// The path of the included file.
+        resolved: PathBuf,
+    },
+
+    /// `-compile(Options).`
+    Compile(Term),
+
+    /// `-spec Name(Args) -> Result.` or `-callback Name(Args) -> Result.`
+    Spec {
+        /// The module name qualifying the function name (e.g., `-spec foo:bar() -> ok.`).
+        module: Option<String>,
+
+        /// The name of the function.
+        name: String,
+
+        /// The arity of the function.
+        arity: usize,
+
+        /// The tokens of the specification (excluding the attribute name and the dot).
+        tokens: Vec<LexicalToken>,
+    },
+
+    /// `-type Name(Vars) :: Type.` or `-opaque Name(Vars) :: Type.`
+    Type {
+        /// The name of the type.
+        name: String,
+
+        /// The number of the variables of the type.
+        arity: usize,
+
+        /// The tokens of the definition (excluding the attribute name and the dot).
+        tokens: Vec<LexicalToken>,
+    },
+
+    /// `-record(Name, {Fields}).`
+    Record(Record),
+
+    /// Other attributes.
+    ///
+    /// The tokens are the ones following the attribute name (excluding the dot).
+    Other(Vec<LexicalToken>),
+}
+
+/// `Name/Arity` pair in an `export` or `export_type` attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameArity {
+    /// The name of the function or type.
+    pub name: String,
+
+    /// The arity of the function or type.
+    pub arity: usize,
+
+    /// The position of the name in the source code.
+    pub position: Position,
+}
+
+impl Attribute {
+    /// Extracts an attribute from the given form.
+    ///
+    /// Returns `None` if the form is not an attribute (e.g., a function definition).
+    pub fn from_form(form: &Form) -> Result<Option<Self>> {
+        let tokens = form.tokens();
+        let name = match tokens {
+            [hyphen, name, .., _] if util::symbol(hyphen) == Some(Symbol::Hyphen) => {
+                match name.as_atom_token() {
+                    Some(name) => name.value().to_string(),
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+        let body = &tokens[2..tokens.len() - 1];
+        let value = match name.as_str() {
+            "module" => AttributeValue::Module(track!(atom(track!(parenthesized(body))?, 0))?),
+            "export" | "export_type" => {
+                let list = track!(parenthesized(body))?;
+                let offset = 3; // `-`, name and `(`
+                let entries = track!(name_arity_list(form, list, offset))?;
+                if name == "export" {
+                    AttributeValue::Export(entries)
+                } else {
+                    AttributeValue::ExportType(entries)
+                }
+            }
+            "behaviour" | "behavior" => {
+                AttributeValue::Behaviour(track!(atom(track!(parenthesized(body))?, 0))?)
+            }
+            "compile" => {
+                let options = track!(parenthesized(body))?.to_vec();
+                AttributeValue::Compile(track!(Term::parse_tokens(options))?)
+            }
+            "spec" | "callback" => track!(spec(strip_parens(body)))?,
+            "type" | "opaque" => track!(type_definition(strip_parens(body)))?,
+            "record" => {
+                let record = track!(Record::from_form(form))?;
+                AttributeValue::Record(record.expect("Never fails"))
+            }
+            _ => AttributeValue::Other(body.to_vec()),
+        };
+        Ok(Some(Attribute {
+            name,
+            value,
+            position: form.start_position(),
+        }))
+    }
+}
+
+/// An iterator over the module attributes in the output of a preprocessor.
+///
+/// This is created by `Preprocessor::attributes`.
+#[derive(Debug)]
+pub struct Attributes<'a, T: 'a, E: 'a> {
+    pp: &'a mut Preprocessor<T, E>,
+}
+impl<'a, T: 'a, E: 'a> Attributes<'a, T, E>
+where
+    T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+    E: Into<Error>,
+{
+    pub(crate) fn new(pp: &'a mut Preprocessor<T, E>) -> Self {
+        Attributes { pp }
+    }
+
+    fn next_attribute(&mut self) -> Result<Option<Attribute>> {
+        loop {
+            if let Some(form) = self.pp.take_form() {
+                if let Some(attr) = track!(Attribute::from_form(&form))? {
+                    return Ok(Some(attr));
+                }
+                continue;
+            }
+            let end = match track!(self.pp.process_form())? {
+                None => return Ok(None),
+                Some(end) => end,
+            };
+            if self.pp.ignore() {
+                continue;
+            }
+
+            // Include directives are not contained in the output
+            let directive = self.pp.directive_ending_at(&end);
+            let (name, path) = match directive {
+                Some(Directive::Include(d)) => ("include", d.path.value()),
+                Some(Directive::IncludeLib(d)) => ("include_lib", d.path.value()),
+                _ => continue,
+            };
+            let resolved = self.pp.included_files().last().cloned();
+            let resolved = track_assert_some!(resolved, ErrorKind::InvalidInput);
+            return Ok(Some(Attribute {
+                name: name.to_string(),
+                value: AttributeValue::Include {
+                    path: path.to_string(),
+                    resolved,
+                },
+                position: directive.expect("Never fails").start_position(),
+            }));
+        }
+    }
+}
+impl<'a, T: 'a, E: 'a> Iterator for Attributes<'a, T, E>
+where
+    T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+    E: Into<Error>,
+{
+    type Item = Result<Attribute>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match track!(self.next_attribute()) {
+            Err(e) => Some(Err(e)),
+            Ok(attr) => attr.map(Ok),
+        }
+    }
+}
+
+fn unexpected(tokens: &[LexicalToken], i: usize) -> Error {
+    match tokens.get(i) {
+        Some(t) => ErrorKind::UnexpectedToken(t.clone()).into(),
+        None => ErrorKind::UnexpectedEos.into(),
+    }
+}
+
+fn atom(tokens: &[LexicalToken], i: usize) -> Result<String> {
+    match tokens.get(i).and_then(|t| t.as_atom_token()) {
+        Some(a) => Ok(a.value().to_string()),
+        None => Err(track!(unexpected(tokens, i))),
+    }
+}
+
+/// Returns the tokens enclosed in the parentheses which span the whole `tokens`.
+fn parenthesized(tokens: &[LexicalToken]) -> Result<&[LexicalToken]> {
+    let inner = strip_parens(tokens);
+    if inner.len() == tokens.len() {
+        return Err(track!(unexpected(tokens, 0)));
+    }
+    Ok(inner)
+}
+
+/// Strips the parentheses which span the whole `tokens` if any
+/// (e.g., `-spec(foo() -> ok).`).
+fn strip_parens(tokens: &[LexicalToken]) -> &[LexicalToken] {
+    let len = tokens.len();
+    if len < 2
+        || util::symbol(&tokens[0]) != Some(Symbol::OpenParen)
+        || util::symbol(&tokens[len - 1]) != Some(Symbol::CloseParen)
+    {
+        return tokens;
+    }
+    let mut depth = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match util::symbol(t) {
+            Some(Symbol::OpenParen) => depth += 1,
+            Some(Symbol::CloseParen) => {
+                depth -= 1;
+                if depth == 0 && i != len - 1 {
+                    return tokens; // e.g., `(A) -> B`
+                }
+            }
+            _ => {}
+        }
+    }
+    &tokens[1..len - 1]
+}
+
+/// Parses `[Name/Arity, ...]`.
+///
+/// `offset` is the index of `tokens[0]` in the form.
+fn name_arity_list(form: &Form, tokens: &[LexicalToken], offset: usize) -> Result<Vec<NameArity>> {
+    let len = tokens.len();
+    if len < 2
+        || util::symbol(&tokens[0]) != Some(Symbol::OpenSquare)
+        || util::symbol(&tokens[len - 1]) != Some(Symbol::CloseSquare)
+    {
+        return Err(track!(unexpected(tokens, 0)));
+    }
+    let mut entries = Vec::new();
+    let mut i = 1;
+    while i < len - 1 {
+        let name = track!(atom(tokens, i))?;
+        if util::symbol(&tokens[i + 1]) != Some(Symbol::Slash) {
+            return Err(track!(unexpected(tokens, i + 1)));
+        }
+        let arity = match tokens.get(i + 2).and_then(|t| t.as_integer_token()) {
+            Some(a) => a.value().to_string().parse::<usize>().ok(),
+            None => None,
+        };
+        let arity = match arity {
+            Some(a) => a,
+            None => return Err(track!(unexpected(tokens, i + 2))),
+        };
+        entries.push(NameArity {
+            name,
+            arity,
+            position: form.source_position(offset + i).expect("Never fails"),
+        });
+        i += 3;
+        match tokens.get(i).and_then(util::symbol) {
+            Some(Symbol::Comma) if i + 1 < len - 1 => i += 1,
+            Some(Symbol::CloseSquare) if i == len - 1 => {}
+            _ => return Err(track!(unexpected(tokens, i))),
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the number of the comma separated elements in the parentheses at `tokens[0]`.
+fn count_args(tokens: &[LexicalToken]) -> Result<usize> {
+    if tokens.first().and_then(util::symbol) != Some(Symbol::OpenParen) {
+        return Err(track!(unexpected(tokens, 0)));
+    }
+    let mut depth = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match util::symbol(t) {
+            Some(Symbol::OpenParen)
+            | Some(Symbol::OpenSquare)
+            | Some(Symbol::OpenBrace)
+            | Some(Symbol::DoubleLeftAngle) => depth += 1,
+            Some(Symbol::CloseParen)
+            | Some(Symbol::CloseSquare)
+            | Some(Symbol::CloseBrace)
+            | Some(Symbol::DoubleRightAngle) => {
+                depth -= 1;
+                if depth == 0 {
+                    let args = &tokens[1..i];
+                    if args.is_empty() {
+                        return Ok(0);
+                    }
+                    return Ok(util::split_top_level(args, Symbol::Comma).count());
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(track!(unexpected(tokens, tokens.len())))
+}
+
+/// Parses `[Module:]Name(Args) -> Result ...`.
+fn spec(tokens: &[LexicalToken]) -> Result<AttributeValue> {
+    let (module, name, rest) = match tokens.get(1).and_then(util::symbol) {
+        Some(Symbol::Colon) => (
+            Some(track!(atom(tokens, 0))?),
+            track!(atom(tokens, 2))?,
+            &tokens[3..],
+        ),
+        _ => (None, track!(atom(tokens, 0))?, &tokens[1..]),
+    };
+    let arity = track!(count_args(rest))?;
+    Ok(AttributeValue::Spec {
+        module,
+        name,
+        arity,
+        tokens: tokens.to_vec(),
+    })
+}
+
+/// Parses `Name(Vars) :: Type`.
+fn type_definition(tokens: &[LexicalToken]) -> Result<AttributeValue> {
+    let name = track!(atom(tokens, 0))?;
+    let arity = track!(count_args(&tokens[1..]))?;
+    Ok(AttributeValue::Type {
+        name,
+        arity,
+        tokens: tokens.to_vec(),
+    })
+}
@@ -0,0 +1,288 @@
+use erl_tokenize::{Lexer, Position};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::header_cache::HeaderCache;
+use crate::util;
+use crate::{
+    Directive, Form, IncludePolicy, Limits, MacroCall, MacroDef, PathVariables, Preprocessor,
+    Result,
+};
+
+/// Preprocessor which preprocesses multiple files concurrently.
+///
+/// The files are distributed over a pool of worker threads.
+/// All the workers share the same header cache, code paths, include paths,
+/// predefined macros, resource limits, include policy and path variables.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// use erl_pp::BatchPreprocessor;
+///
+/// # fn main() {
+/// let mut batch = BatchPreprocessor::new();
+/// batch.set_threads(2);
+/// let results = batch.preprocess(&["tests/epp_suite/line.erl", "no_such_file.erl"]);
+/// assert_eq!(results.len(), 2);
+/// assert!(results[0].is_ok());
+/// assert!(results[1].is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct BatchPreprocessor {
+    code_paths: VecDeque<PathBuf>,
+    include_paths: VecDeque<PathBuf>,
+    macros: HashMap<String, Vec<MacroDef>>,
+    header_cache: HeaderCache,
+    allow_macro_redefinition: bool,
+    limits: Limits,
+    include_policy: IncludePolicy,
+    path_variables: PathVariables,
+    threads: usize,
+}
+impl BatchPreprocessor {
+    /// Makes a new `BatchPreprocessor` instance.
+    ///
+    /// The number of the worker threads defaults to the available parallelism of the machine.
+    pub fn new() -> Self {
+        BatchPreprocessor {
+            code_paths: VecDeque::new(),
+            include_paths: VecDeque::new(),
+            macros: HashMap::new(),
+            header_cache: HeaderCache::new(),
+            allow_macro_redefinition: false,
+            limits: Limits::default(),
+            include_policy: IncludePolicy::new(),
+            path_variables: PathVariables::new(),
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Returns the number of the worker threads.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Sets the number of the worker threads.
+    ///
+    /// If `threads` is `0`, `1` is used instead.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Returns a reference to the code path list which
+    /// will be used for handling `include_lib` directive.
+    pub fn code_paths(&self) -> &VecDeque<PathBuf> {
+        &self.code_paths
+    }
+
+    /// Returns a mutable reference to the code path list which
+    /// will be used for handling `include_lib` directive.
+    pub fn code_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        &mut self.code_paths
+    }
+
+    /// Returns a reference to the include path list which
+    /// will be used for handling `include` directive.
+    pub fn include_paths(&self) -> &VecDeque<PathBuf> {
+        &self.include_paths
+    }
+
+    /// Returns a mutable reference to the include path list which
+    /// will be used for handling `include` directive.
+    pub fn include_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        &mut self.include_paths
+    }
+
+    /// Returns a reference to the map containing the macros
+    /// which are defined before preprocessing each file.
+    pub fn macros(&self) -> &HashMap<String, Vec<MacroDef>> {
+        &self.macros
+    }
+
+    /// Returns a mutable reference to the map containing the macros
+    /// which are defined before preprocessing each file.
+    ///
+    /// This is useful for defining the macros given by `-D` options of `erlc`.
+    pub fn macros_mut(&mut self) -> &mut HashMap<String, Vec<MacroDef>> {
+        &mut self.macros
+    }
+
+    /// Returns the header cache shared by the worker threads.
+    pub fn header_cache(&self) -> &HeaderCache {
+        &self.header_cache
+    }
+
+    /// Sets the header cache shared by the worker threads.
+    pub fn set_header_cache(&mut self, cache: HeaderCache) {
+        self.header_cache = cache;
+    }
+
+    /// Returns `true` if the preprocessors allow to redefine macros, otherwise `false`.
+    pub fn allow_macro_redefinition(&self) -> bool {
+        self.allow_macro_redefinition
+    }
+
+    /// Sets whether the preprocessors allow to redefine macros.
+    ///
+    /// See `Preprocessor::set_allow_macro_redefinition` for more details.
+    pub fn set_allow_macro_redefinition(&mut self, allow: bool) {
+        self.allow_macro_redefinition = allow;
+    }
+
+    /// Returns the resource limits of the preprocessors.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Sets the resource limits of the preprocessors.
+    ///
+    /// The limits are applied to each file separately
+    /// (e.g., `Limits::max_duration` is measured from the start of preprocessing each file).
+    /// See `Limits` for more details.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Returns the policy which restricts the files included by the preprocessors.
+    pub fn include_policy(&self) -> &IncludePolicy {
+        &self.include_policy
+    }
+
+    /// Sets the policy which restricts the files included by the preprocessors.
+    ///
+    /// See `IncludePolicy` for more details.
+    pub fn set_include_policy(&mut self, policy: IncludePolicy) {
+        self.include_policy = policy;
+    }
+
+    /// Returns a reference to the variables which will be used for
+    /// substituting `$VAR` in the paths of `include` and `include_lib` directives.
+    pub fn path_variables(&self) -> &PathVariables {
+        &self.path_variables
+    }
+
+    /// Returns a mutable reference to the variables which will be used for
+    /// substituting `$VAR` in the paths of `include` and `include_lib` directives.
+    ///
+    /// See `PathVariables` for more details.
+    pub fn path_variables_mut(&mut self) -> &mut PathVariables {
+        &mut self.path_variables
+    }
+
+    /// Preprocesses the given files concurrently.
+    ///
+    /// The resulting vector contains the result for each file in the same order as `files`.
+    /// An error in a file does not affect the results of the other files.
+    pub fn preprocess<P>(&self, files: &[P]) -> Vec<Result<PreprocessedFile>>
+    where
+        P: AsRef<Path> + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new((0..files.len()).map(|_| None).collect::<Vec<_>>());
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(files.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= files.len() {
+                        break;
+                    }
+                    let result = track!(self.preprocess_file(files[i].as_ref()));
+                    results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .into_iter()
+            .map(|r| r.expect("Never fails"))
+            .collect()
+    }
+
+    fn preprocess_file(&self, path: &Path) -> Result<PreprocessedFile> {
+        let text = track!(util::read_file(path))?;
+        let mut lexer = Lexer::new(text);
+        lexer.set_filepath(path);
+
+        let mut pp = Preprocessor::new(lexer);
+        self.configure(&mut pp);
+        pp.code_paths_mut().extend(self.code_paths.iter().cloned());
+        pp.include_paths_mut()
+            .extend(self.include_paths.iter().cloned());
+        pp.macros_mut()
+            .extend(self.macros.iter().map(|(k, v)| (k.clone(), v.clone())));
+        pp.set_limits(self.limits.clone());
+        let forms = track!(pp.forms().collect::<Result<Vec<_>>>(), "path={:?}", path)?;
+        Ok(PreprocessedFile {
+            path: path.to_path_buf(),
+            forms,
+            directives: pp.take_directives(),
+            macro_calls: pp.take_macro_calls(),
+            included_files: pp.included_files().to_vec(),
+        })
+    }
+
+    /// Applies the settings shared by all the files (except the paths and the macros) to `pp`.
+    pub(crate) fn configure<T, E>(&self, pp: &mut Preprocessor<T, E>) {
+        pp.set_allow_macro_redefinition(self.allow_macro_redefinition);
+        pp.set_header_cache(self.header_cache.clone());
+        pp.set_include_policy(self.include_policy.clone());
+        *pp.path_variables_mut() = self.path_variables.clone();
+    }
+}
+impl Default for BatchPreprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The result of preprocessing a file by `BatchPreprocessor`.
+#[derive(Debug, Clone)]
+pub struct PreprocessedFile {
+    path: PathBuf,
+    forms: Vec<Form>,
+    directives: BTreeMap<Position, Directive>,
+    macro_calls: BTreeMap<Position, MacroCall>,
+    included_files: Vec<PathBuf>,
+}
+impl PreprocessedFile {
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the preprocessed top-level forms of the file (and the included files).
+    pub fn forms(&self) -> &[Form] {
+        &self.forms
+    }
+
+    /// Takes the ownership of the preprocessed forms.
+    pub fn into_forms(self) -> Vec<Form> {
+        self.forms
+    }
+
+    /// Returns the directives in the file (and the included files).
+    ///
+    /// The keys of this map are starting positions of the corresponding directives.
+    pub fn directives(&self) -> &BTreeMap<Position, Directive> {
+        &self.directives
+    }
+
+    /// Returns the top level macro calls in the file (and the included files).
+    ///
+    /// The keys of this map are starting positions of the corresponding macro calls.
+    pub fn macro_calls(&self) -> &BTreeMap<Position, MacroCall> {
+        &self.macro_calls
+    }
+
+    /// Returns the paths of the files included by the file.
+    pub fn included_files(&self) -> &[PathBuf] {
+        &self.included_files
+    }
+}
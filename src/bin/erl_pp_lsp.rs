@@ -0,0 +1,20 @@
+//! Language server for Erlang macros, which communicates over stdio.
+//!
+//! This binary requires the `lsp` feature:
+//!
+//! ```console
+//! $ cargo run --features lsp --bin erl_pp_lsp
+//! ```
+extern crate erl_pp;
+#[macro_use]
+extern crate trackable;
+
+use erl_pp::lsp::Server;
+use std::io;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut server = Server::new(stdin.lock(), stdout.lock());
+    track_try_unwrap!(server.run());
+}
@@ -0,0 +1,506 @@
+//! Reader of Erlang-term configuration files (e.g., `rebar.config`).
+use erl_tokenize::tokens::AtomToken;
+use erl_tokenize::values::{Keyword, Symbol};
+use erl_tokenize::{Lexer, LexicalToken, PositionRange};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+use crate::util;
+use crate::{Error, ErrorKind, MacroDef, Preprocessor, Result};
+
+/// Erlang term read from a configuration file.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::Term;
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let terms = Term::parse(Lexer::new(r#"{erl_opts, [{d, 'TEST'}, {i, "inc" "lude"}]}."#)).unwrap();
+/// let opts = terms[0].as_tuple().unwrap();
+/// assert_eq!(opts[0].as_atom(), Some("erl_opts"));
+///
+/// let opts = opts[1].as_list().unwrap();
+/// assert_eq!(opts[1].as_tuple().unwrap()[1].as_str(), Some("include"));
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Term {
+    value: TermValue,
+    tokens: Vec<LexicalToken>,
+}
+impl Term {
+    /// Reads the terms in the given file (like `file:consult/1`).
+    pub fn consult<P: AsRef<Path>>(path: P) -> Result<Vec<Term>> {
+        let text = track!(util::read_file(&path))?;
+        let mut lexer = Lexer::new(text);
+        lexer.set_filepath(path);
+        track!(Term::parse(lexer))
+    }
+
+    /// Parses a sequence of terms, each of which is terminated by a dot.
+    pub fn parse<I, E>(tokens: I) -> Result<Vec<Term>>
+    where
+        I: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+        E: Into<Error>,
+    {
+        let tokens = track!(tokens
+            .map(|t| t.map_err(Into::into))
+            .collect::<Result<Vec<_>>>())?;
+        let mut parser = Parser { tokens, next: 0 };
+        let mut terms = Vec::new();
+        while parser.next < parser.tokens.len() {
+            terms.push(track!(parser.parse_term())?);
+            track!(parser.expect(Symbol::Dot))?;
+        }
+        Ok(terms)
+    }
+
+    /// Parses a term which consists of the given tokens (without a terminating dot).
+    pub(crate) fn parse_tokens(tokens: Vec<LexicalToken>) -> Result<Term> {
+        let mut parser = Parser { tokens, next: 0 };
+        let term = track!(parser.parse_term())?;
+        if let Some(token) = parser.tokens.get(parser.next) {
+            track_panic!(ErrorKind::UnexpectedToken(token.clone()));
+        }
+        Ok(term)
+    }
+
+    /// Returns the name of the atom if this term is an atom.
+    pub fn as_atom(&self) -> Option<&str> {
+        match self.value {
+            TermValue::Atom(ref a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the string if this term is a string.
+    ///
+    /// Adjacent string literals (e.g., `"foo" "bar"`) are concatenated.
+    pub fn as_str(&self) -> Option<&str> {
+        match self.value {
+            TermValue::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements of the tuple if this term is a tuple.
+    pub fn as_tuple(&self) -> Option<&[Term]> {
+        match self.value {
+            TermValue::Tuple(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements of the list if this term is a proper list.
+    pub fn as_list(&self) -> Option<&[Term]> {
+        match self.value {
+            TermValue::List(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Returns the tokens which constitute this term.
+    pub fn tokens(&self) -> &[LexicalToken] {
+        &self.tokens
+    }
+}
+
+#[derive(Debug, Clone)]
+enum TermValue {
+    Atom(String),
+    String(String),
+    Tuple(Vec<Term>),
+    List(Vec<Term>),
+
+    /// Numbers, characters, binaries, maps, improper lists, etc.
+    Other,
+}
+
+struct Parser {
+    tokens: Vec<LexicalToken>,
+    next: usize,
+}
+impl Parser {
+    fn parse_term(&mut self) -> Result<Term> {
+        let start = self.next;
+        let token = track!(self.read_token())?;
+        let value = match token {
+            LexicalToken::Atom(_)
+                if self
+                    .tokens
+                    .get(self.next)
+                    .is_some_and(|t| is_symbol(t, Symbol::Slash)) =>
+            {
+                // `Name/Arity` (e.g., `{inline, [f/1]}` in compile options)
+                self.next += 1;
+                match self.tokens.get(self.next) {
+                    Some(LexicalToken::Integer(_)) => self.next += 1,
+                    Some(t) => track_panic!(ErrorKind::UnexpectedToken(t.clone())),
+                    None => track_panic!(ErrorKind::UnexpectedEos),
+                }
+                TermValue::Other
+            }
+            LexicalToken::Atom(ref a) => TermValue::Atom(a.value().to_string()),
+            LexicalToken::String(ref s) => {
+                let mut value = s.value().to_string();
+                while let Some(LexicalToken::String(s)) = self.tokens.get(self.next) {
+                    value.push_str(s.value());
+                    self.next += 1;
+                }
+                TermValue::String(value)
+            }
+            LexicalToken::Integer(_) | LexicalToken::Float(_) | LexicalToken::Char(_) => {
+                TermValue::Other
+            }
+            LexicalToken::Symbol(ref s) => match s.value() {
+                Symbol::OpenBrace => {
+                    TermValue::Tuple(track!(self.parse_elements(Symbol::CloseBrace))?)
+                }
+                Symbol::OpenSquare => track!(self.parse_list())?,
+                Symbol::Hyphen | Symbol::Plus => match self.tokens.get(self.next) {
+                    Some(LexicalToken::Integer(_)) | Some(LexicalToken::Float(_)) => {
+                        self.next += 1;
+                        TermValue::Other
+                    }
+                    _ => track_panic!(ErrorKind::UnexpectedToken(token.clone())),
+                },
+                Symbol::DoubleLeftAngle => {
+                    track!(self.skip_until(Symbol::DoubleRightAngle))?;
+                    TermValue::Other
+                }
+                Symbol::Sharp => {
+                    track!(self.expect(Symbol::OpenBrace))?;
+                    track!(self.skip_until(Symbol::CloseBrace))?;
+                    TermValue::Other
+                }
+                _ => track_panic!(ErrorKind::UnexpectedToken(token.clone())),
+            },
+            LexicalToken::Keyword(ref k) if k.value() == Keyword::Fun => {
+                // `fun M:F/A`
+                while let Some(t) = self.tokens.get(self.next) {
+                    if is_symbol(t, Symbol::Comma)
+                        || is_symbol(t, Symbol::CloseBrace)
+                        || is_symbol(t, Symbol::CloseSquare)
+                        || is_symbol(t, Symbol::Dot)
+                    {
+                        break;
+                    }
+                    self.next += 1;
+                }
+                TermValue::Other
+            }
+            _ => track_panic!(ErrorKind::UnexpectedToken(token.clone())),
+        };
+        Ok(Term {
+            value,
+            tokens: self.tokens[start..self.next].to_vec(),
+        })
+    }
+
+    fn parse_list(&mut self) -> Result<TermValue> {
+        if self.peek_symbol(Symbol::CloseSquare) {
+            self.next += 1;
+            return Ok(TermValue::List(Vec::new()));
+        }
+        let mut elements = Vec::new();
+        loop {
+            elements.push(track!(self.parse_term())?);
+            let token = track!(self.read_token())?;
+            if is_symbol(&token, Symbol::CloseSquare) {
+                return Ok(TermValue::List(elements));
+            } else if is_symbol(&token, Symbol::VerticalBar) {
+                track!(self.parse_term())?;
+                track!(self.expect(Symbol::CloseSquare))?;
+                return Ok(TermValue::Other);
+            } else if !is_symbol(&token, Symbol::Comma) {
+                track_panic!(ErrorKind::UnexpectedToken(token));
+            }
+        }
+    }
+
+    fn parse_elements(&mut self, close: Symbol) -> Result<Vec<Term>> {
+        let mut elements = Vec::new();
+        if self.peek_symbol(close) {
+            self.next += 1;
+            return Ok(elements);
+        }
+        loop {
+            elements.push(track!(self.parse_term())?);
+            let token = track!(self.read_token())?;
+            if is_symbol(&token, close) {
+                return Ok(elements);
+            } else if !is_symbol(&token, Symbol::Comma) {
+                track_panic!(ErrorKind::UnexpectedToken(token));
+            }
+        }
+    }
+
+    /// Skips tokens until the given closing symbol (taking nesting into account).
+    fn skip_until(&mut self, close: Symbol) -> Result<()> {
+        let mut depth = 0;
+        loop {
+            let token = track!(self.read_token())?;
+            if let LexicalToken::Symbol(ref s) = token {
+                match s.value() {
+                    v if v == close && depth == 0 => return Ok(()),
+                    Symbol::OpenBrace
+                    | Symbol::OpenSquare
+                    | Symbol::OpenParen
+                    | Symbol::DoubleLeftAngle => depth += 1,
+                    Symbol::CloseBrace
+                    | Symbol::CloseSquare
+                    | Symbol::CloseParen
+                    | Symbol::DoubleRightAngle => depth -= 1,
+                    Symbol::Dot => track_panic!(ErrorKind::UnexpectedToken(token.clone())),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: Symbol) -> Result<()> {
+        let token = track!(self.read_token())?;
+        track_assert!(
+            is_symbol(&token, expected),
+            ErrorKind::UnexpectedToken(token)
+        );
+        Ok(())
+    }
+
+    fn peek_symbol(&self, expected: Symbol) -> bool {
+        self.tokens
+            .get(self.next)
+            .is_some_and(|t| is_symbol(t, expected))
+    }
+
+    fn read_token(&mut self) -> Result<LexicalToken> {
+        let token = track_assert_some!(
+            self.tokens.get(self.next).cloned(),
+            ErrorKind::UnexpectedEos
+        );
+        self.next += 1;
+        Ok(token)
+    }
+}
+
+fn is_symbol(token: &LexicalToken, expected: Symbol) -> bool {
+    token
+        .as_symbol_token()
+        .is_some_and(|s| s.value() == expected)
+}
+
+/// Preprocessor related options in `erl_opts` (the compiler options used by rebar3 and `erl -make`).
+///
+/// The following options are recognized (the others are ignored):
+///
+/// - `{d, Name}`: defines the macro `Name` as `true`,
+/// - `{d, Name, Value}`: defines the macro `Name` as `Value`,
+/// - `{i, Dir}`: adds `Dir` to the include paths,
+/// - `{platform_define, Regex, Name}` and `{platform_define, Regex, Name, Value}`:
+///   like `d`, but only if the platform string (e.g., `"26.2-x86_64-pc-linux-gnu-64"`)
+///   matches `Regex`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{ErlOpts, Preprocessor, Term};
+/// use erl_tokenize::Lexer;
+/// use std::path::Path;
+///
+/// # fn main() {
+/// let config = r#"{erl_opts, [{d, 'VSN', "1.0"}, {platform_define, "^2[0-9]", 'OTP_2X'}]}."#;
+/// let terms = Term::parse(Lexer::new(config)).unwrap();
+/// let erl_opts = terms[0].as_tuple().unwrap()[1].as_list().unwrap();
+/// let opts = ErlOpts::from_terms(erl_opts, Path::new("."), Some("26.2-x86_64-pc-linux-gnu-64")).unwrap();
+///
+/// let mut pp = Preprocessor::new(Lexer::new("{?VSN, ?OTP_2X}."));
+/// opts.apply(&mut pp);
+/// let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+///            ["{", r#""1.0""#, ",", "true", "}", "."]);
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ErlOpts {
+    macros: Vec<(String, Vec<LexicalToken>)>,
+    include_paths: Vec<PathBuf>,
+}
+impl ErlOpts {
+    /// Makes a new empty `ErlOpts` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes a new `ErlOpts` from the elements of an `erl_opts` list.
+    ///
+    /// Relative include paths are resolved against `base_dir`.
+    /// `platform_define` options are ignored if `platform` is `None`.
+    pub fn from_terms(terms: &[Term], base_dir: &Path, platform: Option<&str>) -> Result<Self> {
+        let mut opts = ErlOpts::default();
+        for term in terms {
+            let elements = term.as_tuple().unwrap_or(&[]);
+            let (tag, rest) = match elements.split_first() {
+                Some((tag, rest)) => (tag.as_atom(), rest),
+                None => continue,
+            };
+            match (tag, rest) {
+                (Some("d"), [name]) => opts.define_true(name),
+                (Some("d"), [name, value]) => opts.define_term(name, value),
+                (Some("i"), [dir]) => {
+                    if let Some(dir) = dir.as_str() {
+                        opts.include_paths.push(base_dir.join(dir));
+                    }
+                }
+                (Some("platform_define"), [regex, name, value @ ..]) if value.len() <= 1 => {
+                    let (regex, platform) = match (regex.as_str(), platform) {
+                        (Some(regex), Some(platform)) => (regex, platform),
+                        _ => continue,
+                    };
+                    let regex = track!(Regex::new(regex).map_err(Error::from))?;
+                    if regex.is_match(platform) {
+                        match value.first() {
+                            None => opts.define_true(name),
+                            Some(value) => opts.define_term(name, value),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Reads the `erl_opts` entry in the given `rebar.config` file.
+    ///
+    /// If `profile` is specified, the `erl_opts` of the profile
+    /// (in the `profiles` entry) are appended.
+    pub fn from_rebar_config<P: AsRef<Path>>(
+        path: P,
+        profile: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        track!(Self::from_rebar_config_with_base_dir(
+            path, base_dir, profile, platform
+        ))
+    }
+
+    /// Reads the `erl_opts` entry in the given `rebar.config` file, and
+    /// resolves relative include paths against `base_dir`
+    /// (instead of the directory of the file).
+    ///
+    /// This is useful for the root `rebar.config` of an umbrella project,
+    /// whose `{i, Dir}` options are relative to the directory of each application.
+    pub fn from_rebar_config_with_base_dir<P: AsRef<Path>>(
+        path: P,
+        base_dir: &Path,
+        profile: Option<&str>,
+        platform: Option<&str>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let terms = track!(Term::consult(path))?;
+        let erl_opts = lookup(&terms, "erl_opts");
+        let mut opts = track!(ErlOpts::from_terms(erl_opts, base_dir, platform))?;
+        if let Some(profile) = profile {
+            let profile_opts = lookup(lookup(&terms, "profiles"), profile);
+            let erl_opts = lookup(profile_opts, "erl_opts");
+            opts.extend(track!(ErlOpts::from_terms(erl_opts, base_dir, platform))?);
+        }
+        Ok(opts)
+    }
+
+    /// Reads the options in the given `Emakefile`.
+    ///
+    /// An `Emakefile` consists of `{Modules, Options}` entries.
+    /// The options of all the entries are merged into one `ErlOpts`.
+    pub fn from_emakefile<P: AsRef<Path>>(path: P, platform: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut opts = ErlOpts::default();
+        for term in track!(Term::consult(path))? {
+            if let Some([_modules, options]) = term.as_tuple() {
+                let options = options.as_list().unwrap_or(&[]);
+                opts.extend(track!(ErlOpts::from_terms(options, base_dir, platform))?);
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Defines a macro.
+    ///
+    /// If a macro is defined more than once, the last definition wins (like `erlc`).
+    pub fn define<S: Into<String>>(&mut self, name: S, replacement: Vec<LexicalToken>) {
+        self.macros.push((name.into(), replacement));
+    }
+
+    /// Adds an include path.
+    pub fn add_include_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.include_paths.push(path.into());
+    }
+
+    /// Returns the include paths.
+    pub fn include_paths(&self) -> &[PathBuf] {
+        &self.include_paths
+    }
+
+    /// Appends the options in `other` to this.
+    pub fn extend(&mut self, other: ErlOpts) {
+        self.macros.extend(other.macros);
+        self.include_paths.extend(other.include_paths);
+    }
+
+    /// Returns an iterator over the macro definitions.
+    pub fn macro_defs(&self) -> impl Iterator<Item = (&str, MacroDef)> + '_ {
+        self.macros
+            .iter()
+            .enumerate()
+            .filter(move |(i, (name, _))| !self.macros[i + 1..].iter().any(|(n, _)| n == name))
+            .map(|(_, (name, tokens))| (name.as_str(), MacroDef::Dynamic(tokens.clone().into())))
+    }
+
+    /// Applies the options to the given preprocessor.
+    ///
+    /// The macros are defined (replacing existing definitions of the same names) and
+    /// the include paths are appended to those of the preprocessor.
+    pub fn apply<T, E>(&self, pp: &mut Preprocessor<T, E>) {
+        let macros = pp.macros_mut();
+        for (name, def) in self.macro_defs() {
+            macros.insert(name.to_string(), vec![def]);
+        }
+        pp.include_paths_mut()
+            .extend(self.include_paths.iter().cloned());
+    }
+
+    fn define_true(&mut self, name: &Term) {
+        if let Some(atom) = name.as_atom() {
+            let position = name.tokens()[0].start_position();
+            let value = AtomToken::from_text("true", position).expect("Never fails");
+            self.define(atom, vec![value.into()]);
+        }
+    }
+
+    fn define_term(&mut self, name: &Term, value: &Term) {
+        if let Some(atom) = name.as_atom() {
+            self.define(atom, value.tokens().to_vec());
+        }
+    }
+}
+
+/// Looks up the value associated with `key` in a list of `{Key, Value}` tuples.
+fn lookup<'a>(terms: &'a [Term], key: &str) -> &'a [Term] {
+    terms
+        .iter()
+        .filter_map(|t| match t.as_tuple() {
+            Some([k, v]) if k.as_atom() == Some(key) => v.as_list(),
+            _ => None,
+        })
+        .next()
+        .unwrap_or(&[])
+}
@@ -7,6 +7,7 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::path::{Component, PathBuf};
 
+use crate::path_variables::PathVariables;
 use crate::token_reader::{ReadFrom, TokenReader};
 use crate::types::{MacroName, MacroVariables};
 use crate::util;
@@ -29,10 +30,40 @@ pub struct Include {
 impl Include {
     /// Executes file inclusion.
     pub fn include(&self) -> Result<(PathBuf, String)> {
-        let path = track!(util::substitute_path_variables(self.path.value()))?;
+        let path = track!(self.resolve(&VecDeque::new(), &PathVariables::new()))?;
         let text = track!(util::read_file(&path))?;
         Ok((path, text))
     }
+
+    /// Resolves the path of the file to be included.
+    ///
+    /// Like `epp`, a relative path is first looked up in the directory of the including file,
+    /// then in the given include paths (in order), and finally in the current directory.
+    ///
+    /// The `$VAR` components of the path are replaced with the values in `variables`.
+    pub fn resolve(
+        &self,
+        include_paths: &VecDeque<PathBuf>,
+        variables: &PathVariables,
+    ) -> Result<PathBuf> {
+        let path = track!(variables.substitute(self.path.value(), &self.start_position()))?;
+        if path.is_relative() {
+            let including_dir = self
+                .path
+                .start_position()
+                .filepath()
+                .and_then(|f| f.parent().map(|d| d.to_path_buf()));
+            let candidate = including_dir
+                .iter()
+                .chain(include_paths.iter())
+                .map(|d| d.join(&path))
+                .find(|p| p.is_file());
+            if let Some(candidate) = candidate {
+                return Ok(candidate);
+            }
+        }
+        Ok(path)
+    }
 }
 impl PositionRange for Include {
     fn start_position(&self) -> Position {
@@ -81,7 +112,23 @@ pub struct IncludeLib {
 impl IncludeLib {
     /// Executes file inclusion.
     pub fn include_lib(&self, code_paths: &VecDeque<PathBuf>) -> Result<(PathBuf, String)> {
-        let mut path = track!(util::substitute_path_variables(self.path.value()))?;
+        let path = track!(self.resolve(code_paths, &PathVariables::new()))?;
+        let text = track!(util::read_file(&path))?;
+        Ok((path, text))
+    }
+
+    /// Resolves the path of the file to be included by using the given code paths.
+    ///
+    /// The first component of the path is regarded as an application name, and
+    /// is replaced with the directory of the application (`APP` or `APP-VSN`)
+    /// found in the code paths.
+    /// The `$VAR` components of the path are replaced with the values in `variables`.
+    pub fn resolve(
+        &self,
+        code_paths: &VecDeque<PathBuf>,
+        variables: &PathVariables,
+    ) -> Result<PathBuf> {
+        let mut path = track!(variables.substitute(self.path.value(), &self.start_position()))?;
 
         let temp_path = path.clone();
         let mut components = temp_path.components();
@@ -89,6 +136,12 @@ impl IncludeLib {
             let app_name = track_assert_some!(app_name.to_str(), ErrorKind::InvalidInput);
             let pattern = format!("{}-*", app_name);
             'root: for root in code_paths.iter() {
+                let app_dir = root.join(app_name);
+                if app_dir.is_dir() {
+                    path = app_dir;
+                    path.extend(components);
+                    break 'root;
+                }
                 let pattern = root.join(&pattern);
                 let pattern = track_assert_some!(pattern.to_str(), ErrorKind::InvalidInput);
                 if let Some(entry) = track!(glob(pattern).map_err(crate::Error::from))?.nth(0) {
@@ -100,9 +153,7 @@ impl IncludeLib {
                 }
             }
         }
-
-        let text = track!(util::read_file(&path))?;
-        Ok((path, text))
+        Ok(path)
     }
 }
 impl PositionRange for IncludeLib {
@@ -521,12 +572,15 @@ impl ReadFrom for Define {
                 replacement.push(_close_paren.into());
             } else {
                 let token = track!(reader.read_token())?;
-                track_assert!(
-                    token
-                        .as_symbol_token()
-                        .map_or(true, |s| s.value() != Symbol::Dot,),
-                    ErrorKind::InvalidInput
-                );
+                if token
+                    .as_symbol_token()
+                    .is_some_and(|s| s.value() == Symbol::Dot)
+                {
+                    track_assert!(
+                        !track!(reader.is_form_end(&token))?,
+                        ErrorKind::InvalidInput
+                    );
+                }
                 replacement.push(token);
             }
         }
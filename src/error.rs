@@ -1,18 +1,18 @@
-use erl_tokenize::{self, LexicalToken};
-use glob;
-use std;
+use erl_tokenize::{self, LexicalToken, Position};
+use std::path::PathBuf;
 use trackable::error::TrackableError;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt};
 
+use crate::Limit;
+
 /// This crate specific error type.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, TrackableError)]
 pub struct Error(TrackableError<ErrorKind>);
 impl Error {
     pub(crate) fn unexpected_token(token: LexicalToken) -> Self {
         ErrorKind::UnexpectedToken(token).into()
     }
 }
-derive_traits_for_trackable_error_newtype!(Error, ErrorKind);
 impl From<erl_tokenize::Error> for Error {
     fn from(e: erl_tokenize::Error) -> Self {
         let kind = match *e.kind() {
@@ -37,6 +37,17 @@ impl From<glob::PatternError> for Error {
         ErrorKind::InvalidInput.cause(f).into()
     }
 }
+impl From<regex::Error> for Error {
+    fn from(f: regex::Error) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
+#[cfg(feature = "lsp")]
+impl From<serde_json::Error> for Error {
+    fn from(f: serde_json::Error) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
 impl From<glob::GlobError> for Error {
     fn from(f: glob::GlobError) -> Self {
         ErrorKind::InvalidInput.cause(f).into()
@@ -54,5 +65,117 @@ pub enum ErrorKind {
 
     /// Unexpected End-Of-String.
     UnexpectedEos,
+
+    /// A user defined macro is redefined with the same arity.
+    MacroRedefinition {
+        /// The name of the macro.
+        name: String,
+
+        /// The position of the original definition.
+        ///
+        /// This is `None` if the original definition was not made by a `define` directive.
+        original: Option<Position>,
+
+        /// The position of the new definition.
+        redefinition: Position,
+    },
+
+    /// A predefined macro (e.g., `?LINE`) is redefined.
+    PredefinedMacroRedefinition {
+        /// The name of the macro.
+        name: String,
+
+        /// The position of the new definition.
+        redefinition: Position,
+    },
+
+    /// `??Name` is applied to a name that is not a variable of the macro being expanded.
+    StringifyNonParameter {
+        /// The name of the variable.
+        name: String,
+
+        /// The position of the `??Name`.
+        position: Position,
+    },
+
+    /// `?` is not followed by a macro name.
+    InvalidMacroCall {
+        /// The position of the `?`.
+        position: Position,
+    },
+
+    /// An undefined macro is called.
+    UndefinedMacro {
+        /// The name of the macro.
+        name: String,
+
+        /// The number of the arguments of the call.
+        arity: Option<usize>,
+
+        /// The position of the call.
+        position: Position,
+    },
+
+    /// There is no definition of the macro which takes the given number of arguments,
+    /// or some of the arguments are empty.
+    MacroArgumentMismatch {
+        /// The name of the macro.
+        name: String,
+
+        /// The position of the call.
+        position: Position,
+    },
+
+    /// The arguments of a macro call are badly formed.
+    InvalidMacroArgument {
+        /// The name of the macro.
+        name: String,
+
+        /// The position of the call.
+        position: Position,
+    },
+
+    /// A macro is defined circularly.
+    CircularMacro {
+        /// The name of the macro.
+        name: String,
+
+        /// The arity of the definition.
+        arity: Option<usize>,
+
+        /// The position of the call.
+        position: Position,
+    },
+
+    /// A limit set by `Preprocessor::set_limits` is exceeded.
+    LimitExceeded(Limit),
+
+    /// A file which is not allowed by the `IncludePolicy` is included.
+    IncludeNotAllowed {
+        /// The (resolved) path of the file.
+        path: PathBuf,
+
+        /// The position of the directive.
+        position: Position,
+    },
+
+    /// A path variable (e.g., `$ROOT`) used in an `include` or `include_lib` directive
+    /// is not defined.
+    UndefinedPathVariable {
+        /// The name of the variable (without the leading `$`).
+        name: String,
+
+        /// The position of the directive.
+        position: Position,
+    },
+
+    /// A path variable which is not allowed by the `IncludePolicy` is used.
+    PathVariableNotAllowed {
+        /// The name of the variable (without the leading `$`).
+        name: String,
+
+        /// The position of the directive.
+        position: Position,
+    },
 }
 impl TrackableErrorKind for ErrorKind {}
@@ -0,0 +1,660 @@
+//! Macro expansion engine which follows the algorithm of `epp`.
+//!
+//! A form is expanded from left to right.
+//! When a macro call `?NAME` is found, the following steps are taken:
+//!
+//! 1. The arity of the call is determined by counting the arguments which follow
+//!    the macro name (if the name is not followed by `(`, the arity is "none").
+//!    Arguments are split on top-level commas, where `()`, `[]`, `{}`, `<<>>` and
+//!    the keyword blocks (`begin`, `case`, `if`, `fun`, `receive`, `try` and `cond`,
+//!    each closed by `end`) are treated as nesting.
+//! 2. The definition is looked up by the name and the arity.
+//!    If the macro has only a definition without variables, that definition is used
+//!    regardless of the arity (the following tokens are left untouched).
+//! 3. If the definition has no variables, its replacement is expanded in isolation and
+//!    the result is pushed back in front of the remaining tokens.
+//! 4. Otherwise, the (unexpanded) arguments are bound to the variables and substituted
+//!    into the replacement (`??Var` is replaced with the stringified argument).
+//!    The result is pushed back in front of the remaining tokens.
+//!
+//! Because expansion results are re-scanned together with the remaining tokens,
+//! macro calls in arguments are expanded lazily (after substitution),
+//! and a macro call at the end of a replacement can take its arguments from
+//! the tokens following the outer call.
+use erl_tokenize::tokens::{AtomToken, IntegerToken, StringToken, SymbolToken, VariableToken};
+use erl_tokenize::values::{Keyword, Symbol};
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+
+use crate::limits::Usage;
+use crate::macros;
+use crate::types::{List, MacroArg, MacroArgs, MacroName, Tail};
+use crate::util;
+use crate::{ErrorKind, MacroCall, MacroDef, PredefinedMacro, Result};
+
+/// A token in the middle of expansion.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub token: LexicalToken,
+
+    /// The position of the outermost macro call which produced this token.
+    ///
+    /// `None` means that this token comes from the input directly.
+    pub site: Option<Position>,
+}
+impl Item {
+    pub fn new(token: LexicalToken) -> Self {
+        Item { token, site: None }
+    }
+    fn symbol(&self) -> Option<Symbol> {
+        util::symbol(&self.token)
+    }
+}
+
+#[derive(Debug)]
+pub struct Expander<'a> {
+    macros: &'a HashMap<String, Vec<MacroDef>>,
+    acyclic: &'a mut AcyclicMacros,
+    usage: &'a mut Usage,
+    calls: Vec<MacroCall>,
+    sites: Vec<(Range<usize>, Position)>,
+}
+impl<'a> Expander<'a> {
+    pub fn new(
+        macros: &'a HashMap<String, Vec<MacroDef>>,
+        acyclic: &'a mut AcyclicMacros,
+        usage: &'a mut Usage,
+    ) -> Self {
+        Expander {
+            macros,
+            acyclic,
+            usage,
+            calls: Vec::new(),
+            sites: Vec::new(),
+        }
+    }
+
+    /// Returns the macro calls which directly appeared in the input of the expansion,
+    /// and the ranges of the output tokens produced by each of the calls
+    /// (see `Form::source_position`).
+    pub fn into_parts(self) -> (Vec<MacroCall>, Vec<(Range<usize>, Position)>) {
+        (self.calls, self.sites)
+    }
+
+    /// Expands all macro calls in the given tokens.
+    pub fn expand(&mut self, tokens: Vec<LexicalToken>) -> Result<Vec<LexicalToken>> {
+        if !tokens
+            .iter()
+            .any(|t| util::symbol(t) == Some(Symbol::Question))
+        {
+            track!(self.usage.add_output_tokens(tokens.len()))?;
+            return Ok(tokens);
+        }
+        let expanded = track!(self.expand_items(tokens.into_iter().map(Item::new).collect()))?;
+        for (i, item) in expanded.iter().enumerate() {
+            let site = match item.site {
+                None => continue,
+                Some(ref site) => site,
+            };
+            match self.sites.last_mut() {
+                Some((ref mut range, ref last)) if range.end == i && last == site => {
+                    range.end += 1;
+                }
+                _ => self.sites.push((i..i + 1, site.clone())),
+            }
+        }
+        Ok(expanded.into_iter().map(|i| i.token).collect())
+    }
+
+    /// Expands the given top-level macro call.
+    ///
+    /// If `recursive` is `false`, the macro calls in the result are left unexpanded.
+    pub fn expand_call(&mut self, call: &MacroCall, recursive: bool) -> Result<Vec<LexicalToken>> {
+        let site = call.start_position();
+        let arity = call.args.as_ref().map(|a| a.len());
+        let definition = track!(lookup(self.macros, &call.name, arity, &site))?;
+        let items = match *definition {
+            MacroDef::Predefined(ref predefined) => {
+                let token = track!(expand_predefined_macro(predefined, &site))?;
+                return Ok(vec![token]);
+            }
+            MacroDef::Dynamic(ref replacement) => replacement
+                .iter()
+                .map(|t| Item {
+                    token: t.clone(),
+                    site: Some(site.clone()),
+                })
+                .collect(),
+            MacroDef::Static(ref d) if d.variables.is_none() => {
+                track!(substitute(&d.replacement, &mut Bindings::default(), &site))?
+            }
+            MacroDef::Static(ref d) => {
+                let args = track_assert_some!(call.args.as_ref(), ErrorKind::InvalidInput);
+                let variables = d.variables.iter().flat_map(|v| v.iter());
+                let values = args.iter().map(|a| Cow::Borrowed(&a.tokens[..]));
+                let mut bindings = track!(Bindings::new(
+                    &d.replacement,
+                    variables,
+                    values,
+                    &call.name,
+                    &site
+                ))?;
+                track!(substitute(&d.replacement, &mut bindings, &site))?
+            }
+        };
+        let items = if recursive {
+            track!(self.check_circular(call.name.value(), definition.arity(), &site))?;
+            track!(self.expand_items(items))?
+        } else {
+            Vec::from(items)
+        };
+        Ok(items.into_iter().map(|i| i.token).collect())
+    }
+
+    fn expand_items(&mut self, mut input: VecDeque<Item>) -> Result<Vec<Item>> {
+        let mut output = Vec::new();
+        while let Some(item) = input.pop_front() {
+            if item.symbol() != Some(Symbol::Question) {
+                track!(self.usage.add_output_tokens(1))?;
+                output.push(item);
+                continue;
+            }
+            track!(self.usage.add_expansion_step())?;
+
+            let question = item;
+            let name = match input.pop_front() {
+                Some(Item {
+                    token: LexicalToken::Atom(t),
+                    ..
+                }) => MacroName::Atom(t),
+                Some(Item {
+                    token: LexicalToken::Variable(t),
+                    ..
+                }) => MacroName::Variable(t),
+                _ => track_panic!(ErrorKind::InvalidMacroCall {
+                    position: question.token.start_position(),
+                }),
+            };
+            let site = question
+                .site
+                .clone()
+                .unwrap_or_else(|| question.token.start_position());
+            let layout = track!(scan_args(input.iter().map(|i| &i.token), &name, &site))?;
+            let arity = layout.as_ref().map(|l| l.args.len());
+            let definition = track!(lookup(self.macros, &name, arity, &site))?;
+            track!(self.check_circular(name.value(), definition.arity(), &site))?;
+
+            let top_level = question.site.is_none();
+            let mut args = None;
+
+            // The replacements of macros without variables are expanded in isolation,
+            // so only the results of substitutions need to be re-scanned.
+            match *definition {
+                MacroDef::Predefined(ref predefined) => {
+                    let token = track!(expand_predefined_macro(predefined, &site))?;
+                    track!(self.usage.add_output_tokens(1))?;
+                    output.push(Item {
+                        token,
+                        site: Some(site),
+                    });
+                }
+                MacroDef::Dynamic(ref replacement) => {
+                    let replacement = replacement
+                        .iter()
+                        .map(|t| Item {
+                            token: t.clone(),
+                            site: Some(site.clone()),
+                        })
+                        .collect();
+                    output.extend(track!(self.expand_items(replacement))?);
+                }
+                MacroDef::Static(ref d) if d.variables.is_none() => {
+                    let replacement =
+                        track!(substitute(&d.replacement, &mut Bindings::default(), &site))?;
+                    output.extend(track!(self.expand_items(replacement))?);
+                }
+                MacroDef::Static(ref d) => {
+                    let layout = layout.expect("Never fails");
+                    let tokens = input
+                        .drain(..=layout.close)
+                        .map(|i| i.token)
+                        .collect::<Vec<_>>();
+
+                    // The arguments of a top-level call are kept in the `MacroCall`,
+                    // otherwise they are moved into the replacement.
+                    let mut owned = Vec::new();
+                    if top_level {
+                        args = Some(to_macro_args(tokens, &layout));
+                    } else {
+                        owned = split_args(tokens, &layout);
+                    }
+                    let values = match args {
+                        Some(ref a) => a.iter().map(|a| Cow::Borrowed(&a.tokens[..])).collect(),
+                        None => owned.into_iter().map(Cow::Owned).collect::<Vec<_>>(),
+                    };
+                    let variables = d.variables.iter().flat_map(|v| v.iter());
+                    let mut bindings = track!(Bindings::new(
+                        &d.replacement,
+                        variables,
+                        values.into_iter(),
+                        &name,
+                        &site
+                    ))?;
+                    let expanded = track!(substitute(&d.replacement, &mut bindings, &site))?;
+                    for item in expanded.into_iter().rev() {
+                        input.push_front(item);
+                    }
+                }
+            }
+            if top_level {
+                self.calls.push(MacroCall {
+                    _question: question.token.into_symbol_token().expect("Never fails"),
+                    name,
+                    args,
+                });
+            }
+        }
+        Ok(output)
+    }
+
+    fn check_circular(&mut self, name: &str, arity: Option<usize>, site: &Position) -> Result<()> {
+        let name = match self.macros.get_key_value(name) {
+            Some((name, _)) => name.as_str(),
+            None => return Ok(()),
+        };
+        if is_acyclic(self.acyclic, name, arity) {
+            return Ok(());
+        }
+        let mut path = Vec::new();
+        if track!(self.is_circular((name, arity), &mut path))? {
+            track_panic!(ErrorKind::CircularMacro {
+                name: name.to_string(),
+                arity,
+                position: site.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn is_circular(
+        &mut self,
+        key: (&'a str, Option<usize>),
+        path: &mut Vec<(&'a str, Option<usize>)>,
+    ) -> Result<bool> {
+        if path.contains(&key) {
+            return Ok(true);
+        }
+        if is_acyclic(self.acyclic, key.0, key.1) {
+            return Ok(false);
+        }
+
+        let replacement = match self.macros.get(key.0).and_then(|defs| {
+            defs.iter()
+                .find(|d| d.arity() == key.1 && !matches!(d, MacroDef::Predefined(_)))
+        }) {
+            Some(MacroDef::Static(d)) => &d.replacement[..],
+            Some(MacroDef::Dynamic(tokens)) => &tokens[..],
+            _ => return Ok(false),
+        };
+
+        path.push(key);
+        for (i, token) in replacement.iter().enumerate() {
+            if token.as_symbol_token().map(|s| s.value()) != Some(Symbol::Question) {
+                continue;
+            }
+            let name = match replacement.get(i + 1) {
+                Some(LexicalToken::Atom(t)) => MacroName::Atom(t.clone()),
+                Some(LexicalToken::Variable(t)) => MacroName::Variable(t.clone()),
+                _ => continue,
+            };
+            let position = token.start_position();
+            let arity = scan_args(replacement[i + 2..].iter(), &name, &position)
+                .ok()
+                .and_then(|layout| layout.map(|l| l.args.len()));
+            let used = match self.macros.get_key_value(name.value()) {
+                Some((used, _)) => match lookup(self.macros, &name, arity, &position) {
+                    Ok(d) => (used.as_str(), d.arity()),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            if track!(self.is_circular(used, path))? {
+                return Ok(true);
+            }
+        }
+        path.pop();
+        self.acyclic
+            .entry(key.0.to_string())
+            .or_default()
+            .insert(key.1);
+        Ok(false)
+    }
+}
+
+/// Names and arities of the macros which are known not to be circular.
+pub type AcyclicMacros = HashMap<String, HashSet<Option<usize>>>;
+
+fn is_acyclic(acyclic: &AcyclicMacros, name: &str, arity: Option<usize>) -> bool {
+    acyclic.get(name).is_some_and(|a| a.contains(&arity))
+}
+
+/// Looks up the definition of a macro in the same manner as `epp`.
+pub fn lookup<'a>(
+    macros: &'a HashMap<String, Vec<MacroDef>>,
+    name: &MacroName,
+    arity: Option<usize>,
+    position: &Position,
+) -> Result<&'a MacroDef> {
+    let defs = macros.get(name.value()).map_or(&[][..], |d| &d[..]);
+    match defs {
+        [] => track_panic!(ErrorKind::UndefinedMacro {
+            name: name.value().to_string(),
+            arity,
+            position: position.clone(),
+        }),
+        [d] if d.arity().is_none() => Ok(d),
+        _ => {
+            if let Some(d) = defs.iter().find(|d| d.arity() == arity) {
+                Ok(d)
+            } else {
+                track_panic!(ErrorKind::MacroArgumentMismatch {
+                    name: name.value().to_string(),
+                    position: position.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// The layout of the arguments of a macro call.
+///
+/// All indices are relative to the opening parenthesis.
+#[derive(Debug)]
+pub struct ArgsLayout {
+    pub args: Vec<Range<usize>>,
+    pub close: usize,
+}
+
+/// Scans the arguments of a macro call (`epp`'s `count_args/3`).
+///
+/// Returns `None` if the tokens do not start with `(`.
+pub fn scan_args<'a, I>(
+    tokens: I,
+    name: &MacroName,
+    position: &Position,
+) -> Result<Option<ArgsLayout>>
+where
+    I: Iterator<Item = &'a LexicalToken>,
+{
+    let mut tokens = tokens.peekable();
+    if tokens.peek().and_then(|t| util::symbol(t)) != Some(Symbol::OpenParen) {
+        return Ok(None);
+    }
+    tokens.next();
+
+    let error = || ErrorKind::InvalidMacroArgument {
+        name: name.value().to_string(),
+        position: position.clone(),
+    };
+    let mut args = Vec::new();
+    let mut i = 1;
+    match tokens.peek().and_then(|t| util::symbol(t)) {
+        Some(Symbol::CloseParen) => return Ok(Some(ArgsLayout { args, close: i })),
+        Some(Symbol::Comma) => track_panic!(error()),
+        _ => {}
+    }
+    loop {
+        let start = i;
+        i += skip_macro_arg(&mut tokens);
+        args.push(start..i);
+        match tokens.next().and_then(util::symbol) {
+            Some(Symbol::CloseParen) => return Ok(Some(ArgsLayout { args, close: i })),
+            Some(Symbol::Comma)
+                if tokens.peek().and_then(|t| util::symbol(t)) != Some(Symbol::CloseParen) =>
+            {
+                i += 1;
+            }
+            _ => track_panic!(error()),
+        }
+    }
+}
+
+/// Skips a macro argument (`epp`'s `macro_arg/3`) and returns the number of the skipped tokens.
+fn skip_macro_arg<'a, I>(tokens: &mut std::iter::Peekable<I>) -> usize
+where
+    I: Iterator<Item = &'a LexicalToken>,
+{
+    #[derive(PartialEq)]
+    enum Close {
+        Symbol(Symbol),
+        End,
+    }
+
+    let mut expected = Vec::new();
+    let mut count = 0;
+    let mut prev_is_fun = false;
+    while let Some(token) = tokens.peek() {
+        let fun_head = prev_is_fun;
+        prev_is_fun = false;
+        match **token {
+            LexicalToken::Symbol(ref s) => match s.value() {
+                Symbol::Comma | Symbol::CloseParen if expected.is_empty() => break,
+                Symbol::OpenParen => {
+                    if fun_head {
+                        expected.push(Close::End);
+                    }
+                    expected.push(Close::Symbol(Symbol::CloseParen))
+                }
+                Symbol::DoubleLeftAngle => expected.push(Close::Symbol(Symbol::DoubleRightAngle)),
+                Symbol::OpenSquare => expected.push(Close::Symbol(Symbol::CloseSquare)),
+                Symbol::OpenBrace => expected.push(Close::Symbol(Symbol::CloseBrace)),
+                v => {
+                    if expected.last() == Some(&Close::Symbol(v)) {
+                        expected.pop();
+                    }
+                }
+            },
+            LexicalToken::Keyword(ref k) => match k.value() {
+                Keyword::Begin
+                | Keyword::If
+                | Keyword::Case
+                | Keyword::Receive
+                | Keyword::Try
+                | Keyword::Cond => expected.push(Close::End),
+                Keyword::Fun => prev_is_fun = true,
+                Keyword::End if expected.last() == Some(&Close::End) => {
+                    expected.pop();
+                }
+                _ => {}
+            },
+            LexicalToken::Variable(_) if fun_head => {
+                // Named fun: `fun Name(...) -> ... end`
+                prev_is_fun = true;
+            }
+            _ => {}
+        }
+        tokens.next();
+        count += 1;
+    }
+    count
+}
+
+/// Arguments bound to the variables of a macro.
+#[derive(Debug, Default)]
+struct Bindings<'b> {
+    entries: Vec<Binding<'b>>,
+}
+impl<'b> Bindings<'b> {
+    fn new<V, A>(
+        replacement: &[LexicalToken],
+        variables: V,
+        values: A,
+        name: &MacroName,
+        site: &Position,
+    ) -> Result<Self>
+    where
+        V: Iterator<Item = &'b VariableToken>,
+        A: Iterator<Item = Cow<'b, [LexicalToken]>>,
+    {
+        let mut entries = Vec::new();
+        for (var, tokens) in variables.zip(values) {
+            if tokens.is_empty() {
+                track_panic!(ErrorKind::MacroArgumentMismatch {
+                    name: name.value().to_string(),
+                    position: site.clone(),
+                });
+            }
+            let uses = replacement
+                .iter()
+                .filter(|t| {
+                    t.as_variable_token()
+                        .is_some_and(|v| v.value() == var.value())
+                })
+                .count();
+            entries.push(Binding {
+                name: var.value(),
+                tokens,
+                uses,
+            });
+        }
+        Ok(Bindings { entries })
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Binding<'b>> {
+        self.entries.iter_mut().rev().find(|b| b.name == name)
+    }
+}
+
+#[derive(Debug)]
+struct Binding<'b> {
+    name: &'b str,
+    tokens: Cow<'b, [LexicalToken]>,
+
+    /// The number of the remaining occurrences of the variable in the replacement.
+    ///
+    /// The tokens are moved (instead of cloned) to the last occurrence.
+    uses: usize,
+}
+
+/// Substitutes the variables in the replacement tokens with the bound arguments.
+fn substitute(
+    replacement: &[LexicalToken],
+    bindings: &mut Bindings,
+    site: &Position,
+) -> Result<VecDeque<Item>> {
+    let item = |token: LexicalToken| Item {
+        token,
+        site: Some(site.clone()),
+    };
+    let mut expanded = VecDeque::with_capacity(replacement.len());
+    let mut i = 0;
+    while i < replacement.len() {
+        let token = &replacement[i];
+        i += 1;
+        if let LexicalToken::Variable(ref v) = *token {
+            if let Some(binding) = bindings.get_mut(v.value()) {
+                binding.uses -= 1;
+                if binding.uses == 0 {
+                    let tokens = std::mem::take(&mut binding.tokens).into_owned();
+                    expanded.extend(tokens.into_iter().map(item));
+                } else {
+                    expanded.extend(binding.tokens.iter().cloned().map(item));
+                }
+                continue;
+            }
+        } else if util::symbol(token) == Some(Symbol::DoubleQuestion) {
+            if let Some(LexicalToken::Variable(v)) = replacement.get(i) {
+                i += 1;
+                let binding = track_assert_some!(
+                    bindings.get_mut(v.value()),
+                    ErrorKind::StringifyNonParameter {
+                        name: v.value().to_string(),
+                        position: token.start_position(),
+                    }
+                );
+                binding.uses -= 1;
+                let string = track!(macros::stringify(&binding.tokens, site.clone()))?;
+                expanded.push_back(item(string.into()));
+                continue;
+            }
+        }
+        expanded.push_back(item(token.clone()));
+    }
+    Ok(expanded)
+}
+
+/// Expands a predefined macro called at `site`.
+pub fn expand_predefined_macro(
+    predefined: &PredefinedMacro,
+    site: &Position,
+) -> Result<LexicalToken> {
+    let position = site.clone();
+    let expanded = match *predefined {
+        PredefinedMacro::File => {
+            let file = track_assert_some!(position.filepath(), ErrorKind::InvalidInput);
+            let file = track_assert_some!(file.to_str(), ErrorKind::InvalidInput);
+            StringToken::from_value(file, position.clone()).into()
+        }
+        PredefinedMacro::Line => {
+            let line = position.line();
+            IntegerToken::from_value(line.into(), position).into()
+        }
+        PredefinedMacro::Machine => AtomToken::from_value("BEAM", position).into(),
+        PredefinedMacro::Module(ref m) | PredefinedMacro::BaseModule(ref m) => {
+            AtomToken::from_value(m.value(), position).into()
+        }
+        PredefinedMacro::ModuleString(ref m) | PredefinedMacro::BaseModuleString(ref m) => {
+            StringToken::from_value(m.value(), position).into()
+        }
+    };
+    Ok(expanded)
+}
+
+fn to_macro_args(mut tokens: Vec<LexicalToken>, layout: &ArgsLayout) -> MacroArgs {
+    let symbol = |t: Option<LexicalToken>| {
+        t.and_then(|t| t.into_symbol_token().ok())
+            .expect("Never fails")
+    };
+    tokens.truncate(layout.close + 1);
+    let _close_paren: SymbolToken = symbol(tokens.pop());
+
+    let mut list = List::Null;
+    let mut tail = Tail::Null;
+    for (i, range) in layout.args.iter().enumerate().rev() {
+        let head = MacroArg {
+            tokens: tokens.split_off(range.start),
+        };
+        if i == 0 {
+            list = List::Cons { head, tail };
+            break;
+        }
+        tail = Tail::Cons {
+            _comma: symbol(tokens.pop()),
+            head,
+            tail: Box::new(tail),
+        };
+    }
+    let _open_paren: SymbolToken = symbol(tokens.pop());
+    MacroArgs {
+        _open_paren,
+        list,
+        _close_paren,
+    }
+}
+
+/// Splits the tokens of macro arguments (including the parentheses and the commas).
+fn split_args(mut tokens: Vec<LexicalToken>, layout: &ArgsLayout) -> Vec<Vec<LexicalToken>> {
+    let mut args = layout
+        .args
+        .iter()
+        .rev()
+        .map(|range| {
+            tokens.truncate(range.end);
+            tokens.split_off(range.start)
+        })
+        .collect::<Vec<_>>();
+    args.reverse();
+    args
+}
@@ -0,0 +1,136 @@
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::{Error, Preprocessor, Result};
+
+/// Preprocessed top-level form.
+///
+/// A form consists of the expanded tokens of a top-level form (excluding directives)
+/// in the source code or in an included file.
+#[derive(Debug, Clone)]
+pub struct Form {
+    tokens: Vec<LexicalToken>,
+    start: Position,
+    end: Position,
+
+    /// The ranges of the tokens produced by macro calls, and the positions of the calls.
+    sites: Vec<(Range<usize>, Position)>,
+}
+impl Form {
+    pub(crate) fn new(
+        tokens: Vec<LexicalToken>,
+        start: Position,
+        end: Position,
+        sites: Vec<(Range<usize>, Position)>,
+    ) -> Self {
+        Form {
+            tokens,
+            start,
+            end,
+            sites,
+        }
+    }
+
+    /// Returns the expanded tokens of this form.
+    ///
+    /// The last token is the dot which terminates this form, except for the last form of
+    /// an input which ends without a dot (such a form is yielded as it is).
+    pub fn tokens(&self) -> &[LexicalToken] {
+        &self.tokens
+    }
+
+    /// Takes the ownership of the expanded tokens of this form.
+    pub fn into_tokens(self) -> Vec<LexicalToken> {
+        self.tokens
+    }
+
+    /// Returns the position in the source code from which the `i`-th token of this form originates.
+    ///
+    /// A token produced by a macro call is mapped to the position of the (outermost) call
+    /// in this form, unless the token itself is located in this form
+    /// (e.g., an argument of the call).
+    /// Otherwise, the position of the token is returned as it is.
+    ///
+    /// Returns `None` if `i` is out of range.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::{Lexer, PositionRange};
+    ///
+    /// # fn main() {
+    /// let src = "-define(F(X), {X, 1}). foo() -> ?F(a).";
+    /// let mut pp = Preprocessor::new(Lexer::new(src));
+    /// let form = pp.forms().next().unwrap().unwrap();
+    /// let tokens = form.tokens();
+    ///
+    /// assert_eq!(tokens[4].text(), "{");
+    /// assert_eq!(tokens[4].start_position().offset(), 14);
+    /// assert_eq!(form.source_position(4).unwrap().offset(), 32);
+    ///
+    /// assert_eq!(tokens[5].text(), "a");
+    /// assert_eq!(form.source_position(5).unwrap().offset(), 35);
+    /// # }
+    /// ```
+    pub fn source_position(&self, i: usize) -> Option<Position> {
+        let position = self.tokens.get(i)?.start_position();
+        if position.filepath() == self.start.filepath()
+            && self.start.offset() <= position.offset()
+            && position.offset() < self.end.offset()
+        {
+            return Some(position);
+        }
+        let site = self
+            .sites
+            .iter()
+            .find(|(r, _)| r.contains(&i))
+            .map(|(_, site)| site.clone());
+        Some(site.unwrap_or(position))
+    }
+
+    /// Returns the path of the file in which this form is located.
+    pub fn filepath(&self) -> Option<&PathBuf> {
+        self.start.filepath()
+    }
+}
+impl PositionRange for Form {
+    /// Returns the start position of this form in the source code (before expansion).
+    fn start_position(&self) -> Position {
+        self.start.clone()
+    }
+
+    /// Returns the end position of this form in the source code (before expansion).
+    fn end_position(&self) -> Position {
+        self.end.clone()
+    }
+}
+
+/// An iterator over the preprocessed top-level forms.
+///
+/// This is created by `Preprocessor::forms`.
+#[derive(Debug)]
+pub struct Forms<'a, T: 'a, E: 'a> {
+    pp: &'a mut Preprocessor<T, E>,
+}
+impl<'a, T: 'a, E: 'a> Forms<'a, T, E> {
+    pub(crate) fn new(pp: &'a mut Preprocessor<T, E>) -> Self {
+        Forms { pp }
+    }
+}
+impl<'a, T: 'a, E: 'a> Iterator for Forms<'a, T, E>
+where
+    T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+    E: Into<Error>,
+{
+    type Item = Result<Form>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match track!(self.pp.next_form()) {
+            Err(e) => Some(Err(e)),
+            Ok(form) => form.map(Ok),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{Lexer, LexicalToken, PositionRange};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::limits::ReadBudget;
+use crate::token_reader::TokenReader;
+use crate::util;
+use crate::{Directive, Error, Result};
+
+/// Thread-safe cache of the header files included by preprocessors.
+///
+/// Each header is lexed (and its directives are parsed) only once,
+/// and the result is reused by every `Preprocessor` which shares this cache
+/// (see `Preprocessor::set_header_cache`).
+///
+/// Entries are keyed by resolved paths.
+/// A cached entry is reused as long as the modification time of the file is unchanged,
+/// or the content of the file has the same hash value.
+///
+/// Cloning a `HeaderCache` is cheap and the clones share the same entries.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{HeaderCache, Preprocessor};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let cache = HeaderCache::new();
+/// for src in &[r#"-include("tests/bar.hrl"). foo."#, r#"-include("tests/bar.hrl"). baz."#] {
+///     let mut pp = Preprocessor::new(Lexer::new(src));
+///     pp.set_header_cache(cache.clone());
+///     let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+///     assert_eq!(tokens[0].text(), "bar");
+/// }
+/// assert_eq!(cache.len(), 1);
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HeaderCache {
+    entries: Arc<Mutex<HashMap<PathBuf, Entry>>>,
+}
+impl HeaderCache {
+    /// Makes a new empty `HeaderCache` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cached header of the given path.
+    ///
+    /// If there is no valid entry for the path, the file is read, lexed and added to this cache.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<Arc<CachedHeader>> {
+        track!(self.load_with_budget(path.as_ref(), &ReadBudget::default()))
+    }
+
+    /// Same as `load()` except that `budget` is charged for the size of the header.
+    ///
+    /// If the header is read from the file, the bytes are charged as they are read.
+    pub(crate) fn load_with_budget(
+        &self,
+        path: &Path,
+        budget: &ReadBudget,
+    ) -> Result<Arc<CachedHeader>> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let cached = self
+            .lock()
+            .get(path)
+            .filter(|entry| modified.is_some() && entry.modified == modified)
+            .map(|entry| Arc::clone(&entry.header));
+        if let Some(header) = cached {
+            track!(budget.charge(header.size))?;
+            return Ok(header);
+        }
+
+        // NOTE: The lock is not held here so that other threads can load other headers
+        let text = track!(util::read_file_with_budget(path, budget))?;
+        let hash = hash_text(&text);
+        let mut entries = self.lock();
+        if let Some(entry) = entries.get_mut(path) {
+            if entry.header.hash == hash {
+                entry.modified = modified;
+                return Ok(Arc::clone(&entry.header));
+            }
+        }
+        let header = Arc::new(track!(CachedHeader::new(path.to_path_buf(), hash, &text))?);
+        entries.insert(
+            path.to_path_buf(),
+            Entry {
+                modified,
+                header: Arc::clone(&header),
+            },
+        );
+        Ok(header)
+    }
+
+    /// Returns the number of the cached headers.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns `true` if this cache has no entries, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Removes the entry of the given path.
+    pub fn invalidate<P: AsRef<Path>>(&self, path: P) {
+        self.lock().remove(path.as_ref());
+    }
+
+    /// Removes all the entries.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, HashMap<PathBuf, Entry>> {
+        // The entries are always left in a consistent state, so a poisoned lock can be recovered
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    modified: Option<SystemTime>,
+    header: Arc<CachedHeader>,
+}
+
+/// Pre-lexed header file held by `HeaderCache`.
+#[derive(Debug)]
+pub struct CachedHeader {
+    path: PathBuf,
+    hash: u64,
+    size: u64,
+    tokens: Vec<LexicalToken>,
+    directives: BTreeMap<usize, (Directive, usize)>,
+}
+impl CachedHeader {
+    fn new(path: PathBuf, hash: u64, text: &str) -> Result<Self> {
+        let mut lexer = Lexer::new(text);
+        lexer.set_filepath(&path);
+        let tokens = track!(lexer.collect::<::std::result::Result<Vec<_>, _>>())?;
+        let directives = parse_directives(&tokens);
+        Ok(CachedHeader {
+            path,
+            hash,
+            size: text.len() as u64,
+            tokens,
+            directives,
+        })
+    }
+
+    /// Returns the path of this header.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the hash value of the content of this header.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Returns the tokens of this header.
+    pub fn tokens(&self) -> &[LexicalToken] {
+        &self.tokens
+    }
+
+    /// Returns an iterator over the directives which start at the beginning of forms in this header.
+    pub fn directives(&self) -> impl Iterator<Item = &Directive> {
+        self.directives.values().map(|(d, _)| d)
+    }
+
+    /// Returns the directive starting at the `index`-th token and
+    /// the index of the token following the directive.
+    pub(crate) fn directive_at(&self, index: usize) -> Option<(&Directive, usize)> {
+        self.directives.get(&index).map(|(d, end)| (d, *end))
+    }
+}
+
+fn hash_text(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Parses the directives which start at the beginning of forms.
+///
+/// The parsing stops at the first malformed directive
+/// (the error will be reported by the preprocessor which reads it).
+fn parse_directives(tokens: &[LexicalToken]) -> BTreeMap<usize, (Directive, usize)> {
+    let index_of = |offset: usize| tokens.partition_point(|t| t.start_position().offset() < offset);
+
+    let mut directives = BTreeMap::new();
+    let mut reader = TokenReader::new(tokens.iter().cloned().map(Ok::<_, Error>));
+    loop {
+        match reader.try_read::<Directive>() {
+            Err(_) => break,
+            Ok(Some(d)) => {
+                let start = index_of(d.start_position().offset());
+                let end = index_of(d.end_position().offset());
+                directives.insert(start, (d, end));
+                continue;
+            }
+            Ok(None) => {}
+        }
+
+        // Skips the remaining tokens of the current form
+        loop {
+            match reader.try_read_token() {
+                Ok(Some(t)) => {
+                    let is_dot = t
+                        .as_symbol_token()
+                        .is_some_and(|s| s.value() == Symbol::Dot);
+                    if is_dot && reader.is_form_end(&t).unwrap_or(true) {
+                        break;
+                    }
+                }
+                Ok(None) | Err(_) => return directives,
+            }
+        }
+    }
+    directives
+}
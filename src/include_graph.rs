@@ -0,0 +1,583 @@
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+use crate::reader_lexer::ReaderLexer;
+use crate::{Directive, Error, MacroCall, Preprocessor, Project, Result};
+
+/// Graph of the `include` and `include_lib` directives in a set of files.
+///
+/// The nodes are the modules and the included files, and
+/// each edge corresponds to an include directive.
+///
+/// The directives in the branches which are not entered (e.g., `-ifdef(UNDEFINED).`) are
+/// also recorded (as inactive edges), and their paths are resolved on a best-effort basis.
+/// The files included by inactive directives are scanned as well, and
+/// the directives in them are also recorded as inactive edges.
+///
+/// The paths of the files are normalized lexically (i.e., `.` and `..` components are removed),
+/// so that a file reached by different paths (e.g., `src/../include/a.hrl` and `include/a.hrl`)
+/// is a single node. The paths given to the query methods are normalized in the same way.
+/// Symbolic links are not resolved.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// use erl_pp::IncludeGraph;
+/// use std::path::Path;
+///
+/// # fn main() {
+/// let graph = IncludeGraph::from_project(&erl_pp::Project::open("tests/project").unwrap())
+///     .unwrap();
+///
+/// let header = Path::new("tests/project/apps/sub/include/sub.hrl");
+/// let modules = graph.modules_including(header, false);
+/// assert_eq!(modules, [
+///     Path::new("tests/project/apps/sub/src/sub.erl"),
+///     Path::new("tests/project/src/top.erl"),
+/// ]);
+///
+/// assert!(graph.to_dot().starts_with("digraph includes {"));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IncludeGraph {
+    modules: BTreeSet<PathBuf>,
+    files: BTreeSet<PathBuf>,
+    edges: Vec<IncludeEdge>,
+    edge_index: HashMap<(PathBuf, usize, PathBuf), usize>,
+    defined_macros: BTreeMap<PathBuf, BTreeSet<String>>,
+    used_macros: BTreeMap<PathBuf, BTreeSet<String>>,
+}
+impl IncludeGraph {
+    /// Makes a new empty `IncludeGraph` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the graph of all the modules in the given project.
+    ///
+    /// The modules are preprocessed by the preprocessors made by `Project::preprocessor`.
+    pub fn from_project(project: &Project) -> Result<Self> {
+        let mut graph = Self::new();
+        for module in project.modules() {
+            let mut pp = track!(project.preprocessor(module))?;
+            track!(graph.add_module(module, &mut pp), "module={:?}", module)?;
+        }
+        Ok(graph)
+    }
+
+    /// Adds the include directives encountered by preprocessing `module` with `pp`.
+    ///
+    /// `pp` is consumed until the end of the input.
+    /// If the same directive is added more than once (e.g., a header included by
+    /// multiple modules), the edge is regarded as active if it is active in any of them.
+    ///
+    /// The macros defined in each file and the ones used by `module` are also recorded
+    /// (see `IncludeGraph::affected_modules_using`).
+    ///
+    /// The files included by inactive directives are scanned by preprocessors
+    /// configured like `pp` (i.e., the same paths, policy and limits).
+    /// Since such a file is scanned without the context of the including file
+    /// (e.g., the macros defined before the directive), the scanning is best-effort:
+    /// the scanning of a file stops at the first error, which is not reported.
+    pub fn add_module<T, E>(&mut self, module: &Path, pp: &mut Preprocessor<T, E>) -> Result<()>
+    where
+        T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+        E: Into<Error>,
+    {
+        let module = &normalize(module);
+        self.modules.insert(module.clone());
+        self.files.insert(module.clone());
+        let mut inactive_targets = Vec::new();
+        track!(self.add_edges(module, pp, true, &mut inactive_targets))?;
+        self.add_macro_usage(module, pp.directives(), pp.macro_calls().values());
+
+        let mut scanned = BTreeSet::new();
+        while let Some((file, position)) = inactive_targets.pop() {
+            if !scanned.insert(normalize(&file)) {
+                continue;
+            }
+            let _ = self.scan_inactive_file(file, &position, pp, &mut inactive_targets);
+        }
+        Ok(())
+    }
+
+    /// Merges the nodes and the edges of `other` into this graph.
+    ///
+    /// This is useful for combining the graphs built with different configurations
+    /// (e.g., rebar3 profiles).
+    pub fn merge(&mut self, other: IncludeGraph) {
+        self.modules.extend(other.modules);
+        self.files.extend(other.files);
+        for (file, names) in other.defined_macros {
+            self.defined_macros.entry(file).or_default().extend(names);
+        }
+        for (module, names) in other.used_macros {
+            self.used_macros.entry(module).or_default().extend(names);
+        }
+        for edge in other.edges {
+            self.add_edge(edge);
+        }
+    }
+
+    /// Returns the paths of the modules in this graph.
+    pub fn modules(&self) -> impl Iterator<Item = &Path> {
+        self.modules.iter().map(|p| p.as_path())
+    }
+
+    /// Returns the paths of all the files (i.e., modules and included files) in this graph.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|p| p.as_path())
+    }
+
+    /// Returns the edges of this graph.
+    pub fn edges(&self) -> &[IncludeEdge] {
+        &self.edges
+    }
+
+    /// Returns the edges of the directives in `file`.
+    pub fn includes<'a>(&'a self, file: &'a Path) -> impl Iterator<Item = &'a IncludeEdge> + 'a {
+        let file = normalize(file);
+        self.edges.iter().filter(move |e| e.from == file)
+    }
+
+    /// Returns the edges of the directives including `file`.
+    pub fn included_by<'a>(&'a self, file: &'a Path) -> impl Iterator<Item = &'a IncludeEdge> + 'a {
+        let file = normalize(file);
+        self.edges.iter().filter(move |e| e.to == file)
+    }
+
+    /// Returns the files which directly or indirectly include `file` (sorted by path).
+    ///
+    /// If `active_only` is `true`, inactive edges are ignored.
+    pub fn transitive_includers(&self, file: &Path, active_only: bool) -> Vec<&Path> {
+        let file = normalize(file);
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![file.as_path()];
+        while let Some(file) = stack.pop() {
+            for edge in self.edges.iter().filter(|e| e.to == file) {
+                if (edge.active || !active_only) && visited.insert(edge.from.as_path()) {
+                    stack.push(&edge.from);
+                }
+            }
+        }
+        visited.remove(file.as_path());
+        visited.into_iter().collect()
+    }
+
+    /// Returns the modules which directly or indirectly include `file` (sorted by path).
+    ///
+    /// If `active_only` is `true`, inactive edges are ignored.
+    pub fn modules_including(&self, file: &Path, active_only: bool) -> Vec<&Path> {
+        self.transitive_includers(file, active_only)
+            .into_iter()
+            .filter(|f| self.modules.contains(*f))
+            .collect()
+    }
+
+    /// Returns the modules which are affected by a change of `header` (sorted by path).
+    ///
+    /// This is the same as `modules_including(header, false)`, i.e., the modules which
+    /// include `header` in any configuration recorded in this graph.
+    /// To take multiple configurations (e.g., rebar3 profiles) into account,
+    /// merge the graphs built with them (see `IncludeGraph::merge`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// use erl_pp::{IncludeGraph, Project};
+    /// use std::path::Path;
+    ///
+    /// # fn main() {
+    /// let graph = IncludeGraph::from_project(&Project::open("tests/project").unwrap()).unwrap();
+    /// let header = Path::new("tests/project/apps/sub/include/sub.hrl");
+    /// assert_eq!(graph.affected_modules(header).len(), 2);
+    /// assert_eq!(graph.affected_modules_using(header, ["UNUSED"]).len(), 0);
+    /// assert_eq!(graph.affected_modules_using(header, ["SUB_NAME"]).len(), 2);
+    /// # }
+    /// ```
+    pub fn affected_modules(&self, header: &Path) -> Vec<&Path> {
+        self.modules_including(header, false)
+    }
+
+    /// Returns the modules which are affected by a change of `header` and
+    /// use any of the given `macros` (sorted by path).
+    ///
+    /// A module is regarded as using a macro if the macro is called
+    /// (directly or via other macros) while preprocessing the module, or
+    /// if the name of the macro is checked by an `ifdef`, `ifndef` or `undef` directive.
+    /// Note that the calls in the branches which are not entered are not recorded.
+    ///
+    /// See also `IncludeGraph::defined_macros` for the macros defined in `header`.
+    pub fn affected_modules_using<I, S>(&self, header: &Path, macros: I) -> Vec<&Path>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let macros = macros
+            .into_iter()
+            .map(|m| m.as_ref().to_string())
+            .collect::<BTreeSet<_>>();
+        self.affected_modules(header)
+            .into_iter()
+            .filter(|m| {
+                self.used_macros
+                    .get(*m)
+                    .is_some_and(|used| !used.is_disjoint(&macros))
+            })
+            .collect()
+    }
+
+    /// Returns the names of the macros defined in `file` (sorted by name).
+    ///
+    /// The definitions in the branches which are not entered are also included.
+    pub fn defined_macros(&self, file: &Path) -> impl Iterator<Item = &str> {
+        self.defined_macros
+            .get(&normalize(file))
+            .into_iter()
+            .flat_map(|names| names.iter().map(|n| n.as_str()))
+    }
+
+    /// Returns the names of the macros used by `module` (sorted by name).
+    ///
+    /// See `IncludeGraph::affected_modules_using` for what is regarded as a use.
+    pub fn used_macros(&self, module: &Path) -> impl Iterator<Item = &str> {
+        self.used_macros
+            .get(&normalize(module))
+            .into_iter()
+            .flat_map(|names| names.iter().map(|n| n.as_str()))
+    }
+
+    /// Exports this graph in the DOT format of Graphviz.
+    ///
+    /// Modules are drawn as boxes, and inactive edges are drawn with dashed lines.
+    /// Each edge is labeled with the line number of the directive.
+    pub fn to_dot(&self) -> String {
+        let mut s = String::new();
+        s.push_str("digraph includes {\n");
+        for file in &self.files {
+            let _ = write!(s, "    {}", quote(&file.to_string_lossy()));
+            if self.modules.contains(file) {
+                s.push_str(" [shape=box]");
+            }
+            s.push_str(";\n");
+        }
+        for edge in &self.edges {
+            let _ = write!(
+                s,
+                "    {} -> {} [label=\"{}\"",
+                quote(&edge.from.to_string_lossy()),
+                quote(&edge.to.to_string_lossy()),
+                edge.position.line()
+            );
+            if !edge.active {
+                s.push_str(", style=dashed");
+            }
+            s.push_str("];\n");
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// Exports this graph in JSON.
+    ///
+    /// The resulting object has the following form:
+    ///
+    /// ```json
+    /// {
+    ///   "nodes": [{"path": "src/foo.erl", "module": true}, ...],
+    ///   "edges": [{"from": "src/foo.erl", "to": "include/foo.hrl", "path": "foo.hrl",
+    ///              "include_lib": false, "line": 2, "column": 1, "active": true}, ...]
+    /// }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let nodes = self
+            .files
+            .iter()
+            .map(|f| {
+                format!(
+                    r#"{{"path":{},"module":{}}}"#,
+                    quote(&f.to_string_lossy()),
+                    self.modules.contains(f)
+                )
+            })
+            .collect::<Vec<_>>();
+        let edges = self
+            .edges
+            .iter()
+            .map(|e| {
+                format!(
+                    r#"{{"from":{},"to":{},"path":{},"include_lib":{},"line":{},"column":{},"active":{}}}"#,
+                    quote(&e.from.to_string_lossy()),
+                    quote(&e.to.to_string_lossy()),
+                    quote(&e.path),
+                    e.include_lib,
+                    e.position.line(),
+                    e.position.column(),
+                    e.active
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            r#"{{"nodes":[{}],"edges":[{}]}}"#,
+            nodes.join(","),
+            edges.join(",")
+        )
+    }
+
+    /// Adds the edges of the include directives processed by `pp` (until the end of the input).
+    ///
+    /// If `active` is `false`, all the edges are regarded as inactive.
+    /// The resolved paths of the inactive directives are pushed to `inactive_targets`
+    /// together with the positions of the directives.
+    fn add_edges<T, E>(
+        &mut self,
+        file: &Path,
+        pp: &mut Preprocessor<T, E>,
+        active: bool,
+        inactive_targets: &mut Vec<(PathBuf, Position)>,
+    ) -> Result<()>
+    where
+        T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+        E: Into<Error>,
+    {
+        while let Some(end) = track!(pp.process_form())? {
+            pp.take_form();
+
+            let entered = !pp.ignore();
+            let (path, include_lib, to) = match pp.directive_ending_at(&end) {
+                Some(Directive::Include(d)) => {
+                    let to = if entered {
+                        pp.included_files().last().cloned()
+                    } else {
+                        d.resolve(pp.include_paths(), pp.path_variables()).ok()
+                    };
+                    (d.path.value().to_string(), false, to)
+                }
+                Some(Directive::IncludeLib(d)) => {
+                    let to = if entered {
+                        pp.included_files().last().cloned()
+                    } else {
+                        d.resolve(pp.code_paths(), pp.path_variables()).ok()
+                    };
+                    (d.path.value().to_string(), true, to)
+                }
+                _ => continue,
+            };
+            let position = pp
+                .directive_ending_at(&end)
+                .expect("Never fails")
+                .start_position();
+            if let (false, Some(to)) = (entered, to.as_ref()) {
+                inactive_targets.push((to.clone(), position.clone()));
+            }
+            let to = to.unwrap_or_else(|| PathBuf::from(&path));
+            let from = position
+                .filepath()
+                .map_or_else(|| file.to_path_buf(), |f| f.to_path_buf());
+            self.add_edge(IncludeEdge {
+                from,
+                to,
+                path,
+                include_lib,
+                position,
+                active: active && entered,
+            });
+        }
+        Ok(())
+    }
+
+    /// Scans `file` included by the inactive directive at `position`.
+    ///
+    /// The edges of the directives in `file` (and the files included by it) are added as
+    /// inactive edges, and the macros defined in them are recorded.
+    fn scan_inactive_file<T, E>(
+        &mut self,
+        file: PathBuf,
+        position: &Position,
+        including_pp: &Preprocessor<T, E>,
+        inactive_targets: &mut Vec<(PathBuf, Position)>,
+    ) -> Result<()> {
+        // NOTE: The path is kept as it is (rather than canonicalized) to be consistent with the edges
+        track!(including_pp
+            .include_policy()
+            .check_path(file.clone(), position))?;
+        let reader = track!(File::open(&file).map_err(Error::from), "path={:?}", file)?;
+        let mut lexer = ReaderLexer::new(reader);
+        lexer.set_filepath(&file);
+
+        let mut pp = Preprocessor::new(lexer);
+        *pp.include_paths_mut() = including_pp.include_paths().clone();
+        *pp.code_paths_mut() = including_pp.code_paths().clone();
+        *pp.path_variables_mut() = including_pp.path_variables().clone();
+        pp.set_include_policy(including_pp.include_policy().clone());
+        pp.set_allow_macro_redefinition(including_pp.allow_macro_redefinition());
+        if let Some(cache) = including_pp.header_cache() {
+            pp.set_header_cache(cache.clone());
+        }
+        pp.set_limits(including_pp.limits().clone());
+
+        let result = track!(self.add_edges(&file, &mut pp, false, inactive_targets));
+        for d in pp.directives().values() {
+            if let Directive::Define(ref d) = *d {
+                let file = d
+                    .start_position()
+                    .filepath()
+                    .map_or_else(|| normalize(&file), |f| normalize(f));
+                self.defined_macros
+                    .entry(file)
+                    .or_default()
+                    .insert(d.name.value().to_string());
+            }
+        }
+        result
+    }
+
+    fn add_macro_usage<'a, I>(
+        &mut self,
+        module: &Path,
+        directives: &BTreeMap<Position, Directive>,
+        calls: I,
+    ) where
+        I: Iterator<Item = &'a MacroCall>,
+    {
+        let mut replacements = HashMap::<&str, Vec<&[LexicalToken]>>::new();
+        let mut used = calls.map(|c| c.name.value()).collect::<BTreeSet<_>>();
+        for d in directives.values() {
+            match *d {
+                Directive::Define(ref d) => {
+                    let file = d
+                        .start_position()
+                        .filepath()
+                        .map_or_else(|| module.to_path_buf(), |f| normalize(f));
+                    let name = d.name.value();
+                    self.defined_macros
+                        .entry(file)
+                        .or_default()
+                        .insert(name.to_string());
+                    replacements.entry(name).or_default().push(&d.replacement);
+                }
+                Directive::Ifdef(ref d) => {
+                    used.insert(d.name.value());
+                }
+                Directive::Ifndef(ref d) => {
+                    used.insert(d.name.value());
+                }
+                Directive::Undef(ref d) => {
+                    used.insert(d.name.value());
+                }
+                _ => {}
+            }
+        }
+
+        // The macros called in the replacements of the used macros are also used
+        let mut stack = used.iter().cloned().collect::<Vec<_>>();
+        while let Some(name) = stack.pop() {
+            for tokens in replacements.get(name).into_iter().flatten() {
+                for pair in tokens.windows(2) {
+                    let is_call = pair[0]
+                        .as_symbol_token()
+                        .is_some_and(|s| s.value() == Symbol::Question);
+                    let callee = match pair[1] {
+                        LexicalToken::Atom(ref a) => a.value(),
+                        LexicalToken::Variable(ref v) => v.value(),
+                        _ => continue,
+                    };
+                    if is_call && used.insert(callee) {
+                        stack.push(callee);
+                    }
+                }
+            }
+        }
+        self.used_macros
+            .entry(module.to_path_buf())
+            .or_default()
+            .extend(used.into_iter().map(|n| n.to_string()));
+    }
+
+    fn add_edge(&mut self, mut edge: IncludeEdge) {
+        edge.from = normalize(&edge.from);
+        edge.to = normalize(&edge.to);
+        let key = (edge.from.clone(), edge.position.offset(), edge.to.clone());
+        if let Some(&i) = self.edge_index.get(&key) {
+            self.edges[i].active |= edge.active;
+            return;
+        }
+        self.files.insert(edge.from.clone());
+        self.files.insert(edge.to.clone());
+        self.edge_index.insert(key, self.edges.len());
+        self.edges.push(edge);
+    }
+}
+
+/// Edge of an `IncludeGraph`.
+#[derive(Debug, Clone)]
+pub struct IncludeEdge {
+    /// The file containing the directive.
+    pub from: PathBuf,
+
+    /// The included file.
+    ///
+    /// If the path of an inactive directive cannot be resolved,
+    /// this is the path written in the directive.
+    pub to: PathBuf,
+
+    /// The path written in the directive.
+    pub path: String,
+
+    /// `true` if the directive is `include_lib`.
+    pub include_lib: bool,
+
+    /// The start position of the directive.
+    pub position: Position,
+
+    /// `false` if the directive is in a branch which is not entered.
+    pub active: bool,
+}
+
+/// Removes `.` and `..` components from `path` without accessing the file system.
+///
+/// A `..` component which follows a normal component cancels it out, and
+/// the ones at the beginning of a relative path are kept.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Quotes `s` as a string literal of JSON (which is also valid in DOT).
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,154 @@
+use erl_tokenize::Position;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::{Error, ErrorKind, Result};
+
+/// Policy which restricts the files included by a `Preprocessor`.
+///
+/// The default policy imposes no restrictions.
+/// A restricted policy is useful for preprocessing untrusted input
+/// (e.g., `-include("/etc/passwd").` or `-include("$HOME/.ssh/id_rsa").`).
+///
+/// - If allowed roots are set, the path of an included file is canonicalized
+///   (i.e., symbolic links and `..` components are resolved), and
+///   must be located under one of the (canonicalized) roots.
+///   A violation is reported as `ErrorKind::IncludeNotAllowed`.
+/// - If allowed variables are set, only the listed variables can be used in
+///   the paths of `include` and `include_lib` directives (e.g., `$ROOT/foo.hrl`).
+///   A violation is reported as `ErrorKind::PathVariableNotAllowed`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{ErrorKind, IncludePolicy, Preprocessor};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let policy = IncludePolicy::sandboxed(["tests"]);
+///
+/// let mut pp = Preprocessor::new(Lexer::new(r#"-include("tests/bar.hrl")."#));
+/// pp.set_include_policy(policy.clone());
+/// assert!(pp.collect::<Result<Vec<_>, _>>().is_ok());
+///
+/// let mut pp = Preprocessor::new(Lexer::new(r#"-include("tests/../Cargo.toml")."#));
+/// pp.set_include_policy(policy.clone());
+/// let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+/// assert!(matches!(*e.kind(), ErrorKind::IncludeNotAllowed { .. }));
+///
+/// let mut pp = Preprocessor::new(Lexer::new(r#"-include("$HOME/foo.hrl")."#));
+/// pp.set_include_policy(policy);
+/// let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+/// assert!(matches!(*e.kind(), ErrorKind::PathVariableNotAllowed { .. }));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncludePolicy {
+    allowed_roots: Option<Vec<PathBuf>>,
+    allowed_variables: Option<HashSet<String>>,
+}
+impl IncludePolicy {
+    /// Makes a new `IncludePolicy` instance which imposes no restrictions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes a new `IncludePolicy` instance which only allows
+    /// the files under `roots` and prohibits all the path variables.
+    pub fn sandboxed<I, P>(roots: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut policy = Self::new();
+        for root in roots {
+            policy.allow_root(root);
+        }
+        policy.set_allowed_variables(Some(HashSet::new()));
+        policy
+    }
+
+    /// Returns the root directories under which included files must be located.
+    ///
+    /// `None` means that any file can be included.
+    pub fn allowed_roots(&self) -> Option<&[PathBuf]> {
+        self.allowed_roots.as_ref().map(|r| &r[..])
+    }
+
+    /// Adds a root directory under which included files can be located.
+    ///
+    /// Note that once this method is called, the files outside of the allowed roots
+    /// can no longer be included.
+    pub fn allow_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.allowed_roots
+            .get_or_insert_with(Vec::new)
+            .push(root.as_ref().to_path_buf());
+    }
+
+    /// Returns the names of the variables which can be used in the paths of included files.
+    ///
+    /// `None` means that any variable can be used.
+    pub fn allowed_variables(&self) -> Option<&HashSet<String>> {
+        self.allowed_variables.as_ref()
+    }
+
+    /// Sets the names of the variables which can be used in the paths of included files.
+    ///
+    /// `Some(HashSet::new())` disables variable substitution entirely,
+    /// and `None` allows any variable.
+    pub fn set_allowed_variables(&mut self, variables: Option<HashSet<String>>) {
+        self.allowed_variables = variables;
+    }
+
+    /// Checks that the path variables (e.g., `$ROOT`) in `path` are allowed by this policy.
+    ///
+    /// `position` is the position of the directive which contains `path`.
+    pub fn check_variables(&self, path: &str, position: &Position) -> Result<()> {
+        let allowed = match self.allowed_variables {
+            None => return Ok(()),
+            Some(ref allowed) => allowed,
+        };
+        for c in Path::new(path).components() {
+            let name = match c.as_os_str().to_str().and_then(|s| s.strip_prefix('$')) {
+                None => continue,
+                Some(name) => name,
+            };
+            track_assert!(
+                allowed.contains(name),
+                ErrorKind::PathVariableNotAllowed {
+                    name: name.to_string(),
+                    position: position.clone(),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that the (resolved) path of an included file is allowed by this policy.
+    ///
+    /// If allowed roots are set, the canonicalized path is returned.
+    /// Otherwise, `path` is returned as it is.
+    ///
+    /// `position` is the position of the directive which includes `path`.
+    pub fn check_path(&self, path: PathBuf, position: &Position) -> Result<PathBuf> {
+        let roots = match self.allowed_roots {
+            None => return Ok(path),
+            Some(ref roots) => roots,
+        };
+        let canonical = track!(path.canonicalize().map_err(Error::from), "path={:?}", path)?;
+        for root in roots {
+            // A root which does not exist allows nothing
+            if let Ok(root) = root.canonicalize() {
+                if canonical.starts_with(root) {
+                    return Ok(canonical);
+                }
+            }
+        }
+        track_panic!(ErrorKind::IncludeNotAllowed {
+            path,
+            position: position.clone(),
+        });
+    }
+}
@@ -0,0 +1,284 @@
+use erl_tokenize::Position;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::header_cache::HeaderCache;
+use crate::preprocessor::State;
+use crate::util::{self, ResumedLexer};
+use crate::{Directive, ErrorKind, Form, MacroCall, Preprocessor, Result};
+
+/// Preprocessor which efficiently re-preprocesses a source text after edits.
+///
+/// This keeps the state of the preprocessor (e.g., the macro table) at every boundary of
+/// the top-level forms of the text.
+/// When the text is edited, only the part following the last boundary before the edit
+/// is lexed and expanded again.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// use erl_pp::IncrementalPreprocessor;
+///
+/// # fn main() {
+/// let mut pp = IncrementalPreprocessor::new("-define(A, 1). foo() -> ?A. bar() -> ?A.");
+/// pp.preprocess().unwrap();
+/// assert_eq!(pp.forms().count(), 2);
+///
+/// let changes = pp.edit(24..26, "{?A}").unwrap();
+/// assert_eq!(changes.removed, 0..1);
+/// assert_eq!(changes.inserted, 0..1);
+///
+/// let texts = pp.forms().next().unwrap().tokens().iter().map(|t| t.text()).collect::<Vec<_>>();
+/// assert_eq!(texts, ["foo", "(", ")", "->", "{", "1", "}", "."]);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct IncrementalPreprocessor {
+    text: String,
+    filepath: Option<PathBuf>,
+    code_paths: VecDeque<PathBuf>,
+    include_paths: VecDeque<PathBuf>,
+    header_cache: Option<HeaderCache>,
+    allow_macro_redefinition: bool,
+    segments: Vec<Segment>,
+}
+impl IncrementalPreprocessor {
+    /// Makes a new `IncrementalPreprocessor` instance.
+    ///
+    /// Note that the text is not preprocessed until `preprocess()` is called.
+    pub fn new<S: Into<String>>(text: S) -> Self {
+        IncrementalPreprocessor {
+            text: text.into(),
+            filepath: None,
+            code_paths: VecDeque::new(),
+            include_paths: VecDeque::new(),
+            header_cache: None,
+            allow_macro_redefinition: false,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Returns the current source text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the file path of the source text.
+    pub fn filepath(&self) -> Option<&Path> {
+        self.filepath.as_ref().map(|p| p.as_ref())
+    }
+
+    /// Sets the file path of the source text.
+    pub fn set_filepath<P: AsRef<Path>>(&mut self, filepath: P) {
+        self.filepath = Some(filepath.as_ref().to_path_buf());
+        self.segments.clear();
+    }
+
+    /// Returns a mutable reference to the code path list which
+    /// will be used for handling `include_lib` directive.
+    pub fn code_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        self.segments.clear();
+        &mut self.code_paths
+    }
+
+    /// Returns a mutable reference to the include path list which
+    /// will be used for handling `include` directive.
+    pub fn include_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        self.segments.clear();
+        &mut self.include_paths
+    }
+
+    /// Sets the header cache used for loading included files.
+    pub fn set_header_cache(&mut self, cache: HeaderCache) {
+        self.header_cache = Some(cache);
+    }
+
+    /// Sets whether the preprocessor allows to redefine macros.
+    ///
+    /// See `Preprocessor::set_allow_macro_redefinition` for more details.
+    pub fn set_allow_macro_redefinition(&mut self, allow: bool) {
+        self.allow_macro_redefinition = allow;
+        self.segments.clear();
+    }
+
+    /// Returns an iterator over the expanded forms of the source text.
+    ///
+    /// The forms in included files are also contained.
+    pub fn forms(&self) -> impl Iterator<Item = &Form> {
+        self.segments.iter().flat_map(|s| s.forms.iter())
+    }
+
+    /// Returns an iterator over the directives in the source text (and in the included files).
+    pub fn directives(&self) -> impl Iterator<Item = &Directive> {
+        self.segments.iter().flat_map(|s| s.directives.values())
+    }
+
+    /// Returns an iterator over the top level macro calls in the source text
+    /// (and in the included files).
+    pub fn macro_calls(&self) -> impl Iterator<Item = &MacroCall> {
+        self.segments.iter().flat_map(|s| s.macro_calls.values())
+    }
+
+    /// Preprocesses the whole source text.
+    ///
+    /// All the forms are reported as changed.
+    ///
+    /// If an error occurs, the forms preceding the erroneous one are kept.
+    pub fn preprocess(&mut self) -> Result<FormChanges> {
+        let old = self.forms().count();
+        self.segments.clear();
+        track!(self.run(None))?;
+        Ok(FormChanges {
+            removed: 0..old,
+            inserted: 0..self.forms().count(),
+        })
+    }
+
+    /// Replaces the `range` (byte offsets) of the source text with `text`,
+    /// and preprocesses the text again.
+    ///
+    /// Only the part of the source text following the last boundary of top-level forms
+    /// before the edit is preprocessed again.
+    ///
+    /// If an error occurs, the forms preceding the erroneous one are kept.
+    pub fn edit(&mut self, range: Range<usize>, text: &str) -> Result<FormChanges> {
+        track_assert!(
+            range.start <= range.end
+                && self.text.is_char_boundary(range.start)
+                && self.text.is_char_boundary(range.end),
+            ErrorKind::InvalidInput,
+            "Invalid edit range: {:?}",
+            range
+        );
+        self.text.replace_range(range.clone(), text);
+
+        // The form preceding the edit is also processed again,
+        // because the edit may change the end of the form (e.g., `1.` => `1.5`).
+        let from = self
+            .segments
+            .iter()
+            .rposition(|s| s.start.offset() < range.start)
+            .unwrap_or(0);
+        let resume = self
+            .segments
+            .get(from)
+            .map(|s| (s.start.clone(), Arc::clone(&s.state)));
+        let base = self.segments[..from]
+            .iter()
+            .map(|s| s.forms.len())
+            .sum::<usize>();
+        let old = self
+            .segments
+            .drain(from..)
+            .flat_map(|s| s.forms)
+            .collect::<Vec<_>>();
+        let result = track!(self.run(resume));
+
+        let new = self.segments[from..]
+            .iter()
+            .flat_map(|s| s.forms.iter())
+            .collect::<Vec<_>>();
+        let same = |a: &Form, b: &Form| {
+            let (a, b) = (a.tokens(), b.tokens());
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.text() == b.text())
+        };
+        let prefix = old
+            .iter()
+            .zip(new.iter())
+            .take_while(|(a, b)| same(a, b))
+            .count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| same(a, b))
+            .count();
+        result?;
+        Ok(FormChanges {
+            removed: base + prefix..base + old.len() - suffix,
+            inserted: base + prefix..base + new.len() - suffix,
+        })
+    }
+
+    /// Preprocesses the source text from the given position (or the beginning of the text).
+    fn run(&mut self, resume: Option<(Position, Arc<State>)>) -> Result<()> {
+        let (mut start, mut state) = resume.unwrap_or_else(|| {
+            let start = util::start_position(self.filepath.as_ref().map(|p| p.as_ref()));
+            (
+                start,
+                Arc::new(State::new(
+                    self.code_paths.clone(),
+                    self.include_paths.clone(),
+                )),
+            )
+        });
+
+        let mut pp = Preprocessor::with_state(ResumedLexer::new(&self.text, start.clone()), &state);
+        pp.set_allow_macro_redefinition(self.allow_macro_redefinition);
+        if let Some(ref cache) = self.header_cache {
+            pp.set_header_cache(cache.clone());
+        }
+        let mut generation = pp.generation();
+        loop {
+            let mut segment = Segment {
+                start: start.clone(),
+                state: Arc::clone(&state),
+                forms: Vec::new(),
+                directives: BTreeMap::new(),
+                macro_calls: BTreeMap::new(),
+            };
+            let mut eos = false;
+            loop {
+                match track!(pp.process_form())? {
+                    None => eos = true,
+                    Some(end) => {
+                        if end.filepath() == start.filepath() {
+                            start = end;
+                        }
+                    }
+                }
+                segment.forms.extend(pp.take_form());
+                if eos || pp.include_depth() == 0 {
+                    break;
+                }
+            }
+            segment.directives = pp.take_directives();
+            segment.macro_calls = pp.take_macro_calls();
+            self.segments.push(segment);
+            if eos {
+                return Ok(());
+            }
+
+            if pp.generation() != generation {
+                generation = pp.generation();
+                state = Arc::new(pp.state());
+            }
+        }
+    }
+}
+
+/// Changes of the expanded forms caused by an edit.
+///
+/// `removed` is the index range of the old forms which have been replaced, and
+/// `inserted` is the index range of the new forms which replaced them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormChanges {
+    /// Index range of the removed forms (in the forms before the edit).
+    pub removed: Range<usize>,
+
+    /// Index range of the inserted forms (in the forms after the edit).
+    pub inserted: Range<usize>,
+}
+
+/// A part of the source text which starts at a boundary of top-level forms.
+#[derive(Debug)]
+struct Segment {
+    start: Position,
+    state: Arc<State>,
+    forms: Vec<Form>,
+    directives: BTreeMap<Position, Directive>,
+    macro_calls: BTreeMap<Position, MacroCall>,
+}
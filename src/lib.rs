@@ -24,23 +24,60 @@
 //! - [Erlang Reference Manual -- Preprocessor](http://erlang.org/doc/reference_manual/macros.html)
 //!
 #![warn(missing_docs)]
+#![allow(clippy::result_large_err)]
 extern crate erl_tokenize;
 extern crate glob;
+extern crate regex;
+#[cfg(feature = "lsp")]
+extern crate serde_json;
 #[macro_use]
 extern crate trackable;
 
+pub use crate::attributes::{Attribute, AttributeValue, Attributes, NameArity};
+pub use crate::batch::{BatchPreprocessor, PreprocessedFile};
+pub use crate::config::{ErlOpts, Term};
 pub use crate::directive::Directive;
 pub use crate::error::{Error, ErrorKind};
-pub use crate::macros::{MacroCall, MacroDef};
+pub use crate::form::{Form, Forms};
+pub use crate::header_cache::{CachedHeader, HeaderCache};
+pub use crate::include_graph::{IncludeEdge, IncludeGraph};
+pub use crate::include_policy::IncludePolicy;
+pub use crate::incremental::{FormChanges, IncrementalPreprocessor};
+pub use crate::limits::{Limit, Limits};
+pub use crate::macros::{
+    MacroCall, MacroCompletion, MacroDef, MacroEvent, MacroExpansion, PredefinedMacro,
+};
+pub use crate::path_variables::PathVariables;
 pub use crate::preprocessor::Preprocessor;
+pub use crate::project::{Application, Project};
+pub use crate::reader_lexer::ReaderLexer;
+pub use crate::records::{Record, RecordField, Records};
+pub use crate::snapshot::Snapshot;
 
 pub mod directives;
+#[cfg(feature = "lsp")]
+pub mod lsp;
 pub mod types;
 
+mod attributes;
+mod batch;
+mod config;
 mod directive;
 mod error;
+mod expander;
+mod form;
+mod header_cache;
+mod include_graph;
+mod include_policy;
+mod incremental;
+mod limits;
 mod macros;
+mod path_variables;
 mod preprocessor;
+mod project;
+mod reader_lexer;
+mod records;
+mod snapshot;
 mod token_reader;
 mod util;
 
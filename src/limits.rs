@@ -0,0 +1,221 @@
+use std::cmp;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{ErrorKind, Result};
+
+/// Limits on the resources consumed by a `Preprocessor`.
+///
+/// These are useful for preprocessing untrusted input,
+/// where a chain of macro definitions may expand exponentially
+/// (e.g., `-define(A, {?B, ?B}). -define(B, {?C, ?C}). ...`).
+///
+/// A field which is `None` means that the resource is unlimited (the default).
+/// When a limit is exceeded, the preprocessor reports an `ErrorKind::LimitExceeded` error.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{ErrorKind, Limit, Limits, Preprocessor};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let src = "-define(A, {x, x}). -define(B, {?A, ?A}). -define(C, {?B, ?B}). ?C.";
+/// let mut pp = Preprocessor::new(Lexer::new(src));
+/// pp.set_limits(Limits {
+///     max_output_tokens: Some(20),
+///     ..Limits::default()
+/// });
+/// let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+/// assert!(matches!(*e.kind(), ErrorKind::LimitExceeded(Limit::OutputTokens)));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of the tokens produced by the preprocessor.
+    pub max_output_tokens: Option<usize>,
+
+    /// The maximum number of the macro calls expanded by the preprocessor
+    /// (including the ones nested in replacements).
+    pub max_expansion_steps: Option<usize>,
+
+    /// The maximum number of `include` and `include_lib` directives processed by the preprocessor.
+    pub max_includes: Option<usize>,
+
+    /// The maximum total size (in bytes) of the files included by the preprocessor.
+    ///
+    /// The bytes are counted as they are read, so the files whose size is not known
+    /// in advance (e.g., FIFOs) are also limited.
+    pub max_included_bytes: Option<u64>,
+
+    /// The maximum duration of the preprocessing.
+    ///
+    /// The duration is measured from the call of `Preprocessor::set_limits`, and
+    /// is checked while reading the input and the included files as well as while expanding macros.
+    pub max_duration: Option<Duration>,
+}
+
+/// Kind of the limits defined by `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// `Limits::max_output_tokens`.
+    OutputTokens,
+
+    /// `Limits::max_expansion_steps`.
+    ExpansionSteps,
+
+    /// `Limits::max_includes`.
+    Includes,
+
+    /// `Limits::max_included_bytes`.
+    IncludedBytes,
+
+    /// `Limits::max_duration`.
+    Duration,
+}
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Limit::OutputTokens => "max_output_tokens",
+            Limit::ExpansionSteps => "max_expansion_steps",
+            Limit::Includes => "max_includes",
+            Limit::IncludedBytes => "max_included_bytes",
+            Limit::Duration => "max_duration",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Resources consumed by a preprocessor so far.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Usage {
+    limits: Limits,
+    deadline: Option<Instant>,
+    output_tokens: usize,
+    expansion_steps: usize,
+    includes: usize,
+    read_budget: ReadBudget,
+}
+impl Usage {
+    pub fn new(limits: Limits) -> Self {
+        let deadline = limits.max_duration.map(|d| Instant::now() + d);
+        Usage {
+            deadline,
+            read_budget: ReadBudget {
+                used: Arc::new(AtomicU64::new(0)),
+                max_bytes: limits.max_included_bytes,
+                deadline,
+                max_duration: limits.max_duration,
+            },
+            limits,
+            ..Usage::default()
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn add_output_tokens(&mut self, count: usize) -> Result<()> {
+        self.output_tokens += count;
+        track!(check(
+            self.output_tokens,
+            self.limits.max_output_tokens,
+            Limit::OutputTokens
+        ))
+    }
+
+    pub fn add_expansion_step(&mut self) -> Result<()> {
+        self.expansion_steps += 1;
+        track!(check(
+            self.expansion_steps,
+            self.limits.max_expansion_steps,
+            Limit::ExpansionSteps
+        ))?;
+        track!(self.check_deadline())
+    }
+
+    pub fn add_include(&mut self) -> Result<()> {
+        self.includes += 1;
+        track!(check(
+            self.includes,
+            self.limits.max_includes,
+            Limit::Includes
+        ))
+    }
+
+    /// Returns the budget which is charged for the bytes read from included files.
+    pub fn read_budget(&self) -> &ReadBudget {
+        &self.read_budget
+    }
+
+    pub fn check_deadline(&self) -> Result<()> {
+        track!(check_deadline(self.deadline, self.limits.max_duration))
+    }
+}
+
+/// Budget for reading the input, shared by a preprocessor and the readers of its included files.
+///
+/// The bytes read from included files are charged as they are read.
+/// The deadline of the preprocessor is also checked while reading.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReadBudget {
+    used: Arc<AtomicU64>,
+    max_bytes: Option<u64>,
+    deadline: Option<Instant>,
+    max_duration: Option<Duration>,
+}
+impl ReadBudget {
+    /// Charges `bytes` read from an included file.
+    pub fn charge(&self, bytes: u64) -> Result<()> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        track!(check(used, self.max_bytes, Limit::IncludedBytes))?;
+        track!(self.check_deadline())
+    }
+
+    /// Returns the number of the bytes to be read next, which is at most `size`.
+    ///
+    /// At most one byte beyond the limit is read, so that reading stops as soon as
+    /// the limit is exceeded.
+    pub fn read_size(&self, size: usize) -> usize {
+        if let Some(max) = self.max_bytes {
+            let remaining = max.saturating_sub(self.used.load(Ordering::Relaxed));
+            cmp::min(size as u64, remaining + 1) as usize
+        } else {
+            size
+        }
+    }
+
+    pub fn check_deadline(&self) -> Result<()> {
+        track!(check_deadline(self.deadline, self.max_duration))
+    }
+}
+
+fn check_deadline(deadline: Option<Instant>, max_duration: Option<Duration>) -> Result<()> {
+    if let Some(deadline) = deadline {
+        track_assert!(
+            Instant::now() <= deadline,
+            ErrorKind::LimitExceeded(Limit::Duration),
+            "max_duration={:?}",
+            max_duration
+        );
+    }
+    Ok(())
+}
+
+fn check<N: PartialOrd + fmt::Debug>(value: N, max: Option<N>, limit: Limit) -> Result<()> {
+    if let Some(max) = max {
+        track_assert!(
+            value <= max,
+            ErrorKind::LimitExceeded(limit),
+            "{}={:?}",
+            limit,
+            max
+        );
+    }
+    Ok(())
+}
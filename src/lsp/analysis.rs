@@ -0,0 +1,423 @@
+//! Analysis of an opened document.
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange, Token};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use super::protocol::LineIndex;
+use crate::directives::Define;
+use crate::expander::{self, AcyclicMacros, Expander};
+use crate::limits::Usage;
+use crate::token_reader::TokenReader;
+use crate::types::MacroName;
+use crate::util::{self, ResumedLexer};
+use crate::{
+    Directive, Error, ErrorKind, MacroCall, MacroDef, MacroExpansion, PathVariables, Preprocessor,
+    Result,
+};
+
+/// Settings used for preprocessing a document.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub include_paths: VecDeque<PathBuf>,
+    pub code_paths: VecDeque<PathBuf>,
+    pub macros: HashMap<String, Vec<MacroDef>>,
+    pub path_variables: PathVariables,
+}
+
+/// A directive or a form in the document.
+#[derive(Debug)]
+pub struct Item {
+    /// The byte offset of the end of the previous item.
+    pub start: usize,
+
+    /// The byte offset of the end of this item.
+    pub end: usize,
+
+    /// Whether this item is in a branch which is not entered.
+    pub inactive: bool,
+
+    /// The macros defined before this item.
+    pub macros: Arc<HashMap<String, Vec<MacroDef>>>,
+}
+
+/// A macro call (including the ones in the arguments of other calls and in replacements).
+#[derive(Debug)]
+pub struct Call {
+    pub question: Position,
+    pub name: MacroName,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub start: usize,
+    pub end: usize,
+    pub severity: u8,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct Analysis {
+    pub text: String,
+    pub path: Option<PathBuf>,
+    pub lines: LineIndex,
+    pub settings: Settings,
+    pub items: Vec<Item>,
+    pub directives: BTreeMap<Position, Directive>,
+    pub calls: Vec<Call>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+impl Analysis {
+    pub fn new(text: String, path: Option<PathBuf>, settings: Settings) -> Self {
+        let lines = LineIndex::new(&text);
+        let mut analysis = Analysis {
+            text,
+            path,
+            lines,
+            settings,
+            items: Vec::new(),
+            directives: BTreeMap::new(),
+            calls: Vec::new(),
+            diagnostics: Vec::new(),
+        };
+        analysis.preprocess();
+        analysis
+    }
+
+    fn preprocess(&mut self) {
+        let text = self.text.clone();
+        let mut lexer = Lexer::new(&text[..]);
+        if let Some(ref path) = self.path {
+            lexer.set_filepath(path);
+        }
+        let mut pp = Preprocessor::new(lexer);
+        pp.set_record_timeline(true);
+        *pp.include_paths_mut() = self.settings.include_paths.clone();
+        *pp.code_paths_mut() = self.settings.code_paths.clone();
+        *pp.path_variables_mut() = self.settings.path_variables.clone();
+        pp.macros_mut().extend(self.settings.macros.clone());
+
+        let mut start = 0;
+        let mut error = None;
+        loop {
+            let ignored = pp.ignore();
+            let macros = pp.macro_table();
+            match track!(pp.process_form()) {
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+                Ok(None) => break,
+                Ok(Some(end)) => {
+                    pp.take_form();
+                    if !self.is_local(&end) {
+                        continue;
+                    }
+                    self.items.push(Item {
+                        start,
+                        end: end.offset(),
+                        inactive: ignored && pp.ignore(),
+                        macros,
+                    });
+                    start = end.offset();
+                }
+            }
+        }
+        self.directives = pp.take_directives();
+        let top_level_calls = pp.take_macro_calls();
+
+        for call in top_level_calls.values() {
+            self.calls.push(Call {
+                question: call.start_position(),
+                name: call.name.clone(),
+            });
+            for arg in call.args.iter().flat_map(|a| a.iter()) {
+                self.calls.extend(nested_calls(&arg.tokens));
+            }
+        }
+        for d in self.directives.values() {
+            if let Directive::Define(ref d) = *d {
+                self.calls.extend(nested_calls(&d.replacement));
+            }
+        }
+        self.calls.sort_by_key(|c| c.question.clone());
+
+        for d in self.directives.values() {
+            let (severity, message) = match *d {
+                Directive::Error(ref d) => (1, d.message.value()),
+                Directive::Warning(ref d) => (2, d.message.value()),
+                _ => continue,
+            };
+            let start = d.start_position();
+            if self.is_local(&start) && !self.item_at(start.offset()).is_some_and(|i| i.inactive) {
+                self.diagnostics.push(Diagnostic {
+                    start: start.offset(),
+                    end: d.end_position().offset(),
+                    severity,
+                    message: message.to_string(),
+                });
+            }
+        }
+        if let Some(e) = error {
+            let diagnostic = self.error_diagnostic(&e, start);
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn error_diagnostic(&self, e: &Error, unprocessed: usize) -> Diagnostic {
+        let (position, message) = describe(e);
+        let start = match position {
+            Some(p) if self.is_local(&p) => p.offset(),
+            _ => {
+                // Points to the beginning of the item which caused the error
+                let rest = &self.text[unprocessed..];
+                unprocessed + (rest.len() - rest.trim_start().len())
+            }
+        };
+        let end = self.token_end(start).unwrap_or(start);
+        Diagnostic {
+            start,
+            end,
+            severity: 1,
+            message,
+        }
+    }
+
+    /// Returns `true` if the given position is in this document.
+    pub fn is_local(&self, position: &Position) -> bool {
+        position.filepath() == self.path.as_ref()
+    }
+
+    /// Returns the item which contains the given offset.
+    pub fn item_at(&self, offset: usize) -> Option<&Item> {
+        let i = self.items.partition_point(|i| i.end <= offset);
+        self.items.get(i).filter(|i| i.start <= offset)
+    }
+
+    /// Returns the macro call whose `?` or name contains the given offset.
+    pub fn call_at(&self, offset: usize) -> Option<&Call> {
+        self.calls.iter().find(|c| {
+            self.is_local(&c.question)
+                && c.question.offset() <= offset
+                && offset <= c.name.end_position().offset()
+        })
+    }
+
+    /// Returns the `define` directive whose name contains the given offset.
+    pub fn define_at(&self, offset: usize) -> Option<&Define> {
+        self.local_directives().find_map(|d| match *d {
+            Directive::Define(ref d)
+                if d.name.start_position().offset() <= offset
+                    && offset <= d.name.end_position().offset() =>
+            {
+                Some(d)
+            }
+            _ => None,
+        })
+    }
+
+    /// Returns the `include` or `include_lib` directive whose path contains the given offset.
+    pub fn include_at(&self, offset: usize) -> Option<&Directive> {
+        self.local_directives().find(|d| {
+            let path = match **d {
+                Directive::Include(ref d) => &d.path,
+                Directive::IncludeLib(ref d) => &d.path,
+                _ => return false,
+            };
+            path.start_position().offset() <= offset && offset <= path.end_position().offset()
+        })
+    }
+
+    fn local_directives(&self) -> impl Iterator<Item = &Directive> {
+        self.directives
+            .values()
+            .filter(move |d| self.is_local(&d.start_position()))
+    }
+
+    /// Returns the definition of the macro called by `call`.
+    pub fn definition(&self, call: &Call) -> Result<&MacroDef> {
+        let item = track_assert_some!(
+            self.item_at(call.question.offset()),
+            ErrorKind::InvalidInput
+        );
+        let arity = track!(self.call_tokens(call, item))?.1;
+        track!(expander::lookup(
+            &item.macros,
+            &call.name,
+            arity,
+            &call.question
+        ))
+    }
+
+    /// Expands the given macro call in the macro environment at the call.
+    pub fn expand(&self, call: &Call) -> Result<MacroExpansion> {
+        let item = track_assert_some!(
+            self.item_at(call.question.offset()),
+            ErrorKind::InvalidInput
+        );
+        let (mut tokens, arity) = track!(self.call_tokens(call, item))?;
+        let definition = track!(expander::lookup(
+            &item.macros,
+            &call.name,
+            arity,
+            &call.question
+        ))?;
+        if !definition.has_variables() {
+            tokens.truncate(2);
+        }
+        let mut reader = TokenReader::new(tokens.into_iter().map(Ok::<_, Error>));
+        let macro_call: MacroCall = track!(reader.read())?;
+
+        let mut acyclic = AcyclicMacros::new();
+        let mut usage = Usage::default();
+        let mut expander = Expander::new(&item.macros, &mut acyclic, &mut usage);
+        let one_step = track!(expander.expand_call(&macro_call, false))?;
+        let full = track!(expander.expand_call(&macro_call, true))?;
+        Ok(MacroExpansion::new(
+            macro_call,
+            definition.clone(),
+            one_step,
+            full,
+        ))
+    }
+
+    /// Returns the tokens of the given call (the arguments are included if any) and its arity.
+    fn call_tokens(&self, call: &Call, item: &Item) -> Result<(Vec<LexicalToken>, Option<usize>)> {
+        let text = &self.text[..item.end];
+        let mut tokens = track!(ResumedLexer::new(text, call.question.clone())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(Error::from))?;
+        track_assert!(tokens.len() >= 2, ErrorKind::InvalidInput);
+        let layout = track!(expander::scan_args(
+            tokens[2..].iter(),
+            &call.name,
+            &call.question
+        ))?;
+        match layout {
+            None => {
+                tokens.truncate(2);
+                Ok((tokens, None))
+            }
+            Some(layout) => {
+                tokens.truncate(2 + layout.close + 1);
+                Ok((tokens, Some(layout.args.len())))
+            }
+        }
+    }
+
+    /// Returns the end offset of the token which starts at the given offset.
+    pub fn token_end(&self, offset: usize) -> Option<usize> {
+        let text = self.text.get(offset..)?;
+        let start = util::start_position(self.path.as_deref());
+        let token = Token::from_text(text, start).ok()?;
+        Some(offset + token.text().len())
+    }
+}
+
+fn nested_calls(tokens: &[LexicalToken]) -> impl Iterator<Item = Call> + '_ {
+    tokens.windows(2).filter_map(|w| {
+        if util::symbol(&w[0]) != Some(Symbol::Question) {
+            return None;
+        }
+        let name = match w[1] {
+            LexicalToken::Atom(ref t) => MacroName::Atom(t.clone()),
+            LexicalToken::Variable(ref t) => MacroName::Variable(t.clone()),
+            _ => return None,
+        };
+        Some(Call {
+            question: w[0].start_position(),
+            name,
+        })
+    })
+}
+
+/// Returns the position and the human readable description of an error.
+pub fn describe(e: &Error) -> (Option<Position>, String) {
+    let arity = |a: &Option<usize>| a.map_or(String::new(), |a| format!("/{}", a));
+    match *e.kind() {
+        ErrorKind::UnexpectedToken(ref t) => (
+            Some(t.start_position()),
+            format!("unexpected token: {}", t.text()),
+        ),
+        ErrorKind::UnexpectedEos => (None, "unexpected end of input".to_string()),
+        ErrorKind::MacroRedefinition {
+            ref name,
+            ref redefinition,
+            ..
+        }
+        | ErrorKind::PredefinedMacroRedefinition {
+            ref name,
+            ref redefinition,
+        } => (
+            Some(redefinition.clone()),
+            format!("redefining macro '{}'", name),
+        ),
+        ErrorKind::StringifyNonParameter {
+            ref name,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("'{}' is not a macro variable", name),
+        ),
+        ErrorKind::InvalidMacroCall { ref position } => {
+            (Some(position.clone()), "invalid macro call".to_string())
+        }
+        ErrorKind::UndefinedMacro {
+            ref name,
+            arity: ref a,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("undefined macro '{}{}'", name, arity(a)),
+        ),
+        ErrorKind::MacroArgumentMismatch {
+            ref name,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("argument mismatch for macro '{}'", name),
+        ),
+        ErrorKind::InvalidMacroArgument {
+            ref name,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("badly formed argument for macro '{}'", name),
+        ),
+        ErrorKind::CircularMacro {
+            ref name,
+            arity: ref a,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("circular macro '{}{}'", name, arity(a)),
+        ),
+        ErrorKind::LimitExceeded(limit) => (None, format!("limit exceeded: {}", limit)),
+        ErrorKind::IncludeNotAllowed {
+            ref path,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("including {:?} is not allowed", path),
+        ),
+        ErrorKind::UndefinedPathVariable {
+            ref name,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("undefined path variable '${}'", name),
+        ),
+        ErrorKind::PathVariableNotAllowed {
+            ref name,
+            ref position,
+        } => (
+            Some(position.clone()),
+            format!("path variable '${}' is not allowed", name),
+        ),
+        ErrorKind::InvalidInput => match std::error::Error::source(e) {
+            Some(cause) => (None, format!("invalid input: {}", cause)),
+            None => (None, "invalid input".to_string()),
+        },
+    }
+}
@@ -0,0 +1,479 @@
+//! Language server for Erlang macros.
+//!
+//! This module is available only if the `lsp` feature is enabled.
+//! `Server` speaks the [Language Server Protocol][LSP] over a pair of streams
+//! (the `erl_pp_lsp` binary runs it over stdio), and provides the following features:
+//!
+//! - Hover: shows the definition and the fully expanded text of the macro called by `?MACRO`.
+//! - Go to definition: jumps to the definition of a macro, or to the file included by
+//!   `include` and `include_lib` directives.
+//! - Find references: lists the macro calls which have the same name.
+//! - Diagnostics: reports preprocessing errors and `-error`/`-warning` directives.
+//! - Semantic tokens: marks the regions skipped by conditional directives as `comment`.
+//!
+//! Only full text synchronization is supported.
+//! If the workspace root is given by the client, the include paths, the code paths and
+//! the macros of each document are taken from the `Project` at the root.
+//! They can be extended by the `includePaths` and `codePaths` initialization options,
+//! and the variables in include paths (e.g., `$ROOT`) can be given by the `pathVariables`
+//! initialization option (relative paths are resolved against the root).
+//! Malformed messages are answered with a parse error, and the server keeps serving.
+//!
+//! [LSP]: https://microsoft.github.io/language-server-protocol/
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate erl_pp;
+//! use erl_pp::lsp::Server;
+//!
+//! # fn main() {
+//! let mut input = Vec::new();
+//! for message in &[
+//!     r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+//!     r#"{"jsonrpc":"2.0","method":"exit"}"#,
+//! ] {
+//!     input.extend(format!("Content-Length: {}\r\n\r\n{}", message.len(), message).bytes());
+//! }
+//!
+//! let mut output = Vec::new();
+//! Server::new(&input[..], &mut output).run().unwrap();
+//! assert!(String::from_utf8(output).unwrap().contains(r#""hoverProvider":true"#));
+//! # }
+//! ```
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use self::analysis::{Analysis, Call, Settings};
+use crate::util;
+use crate::{Directive, MacroDef, PathVariables, Project, Result};
+use erl_tokenize::{Position, PositionRange};
+
+mod analysis;
+mod protocol;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const REQUEST_FAILED: i64 = -32803;
+
+/// Language server for Erlang macros.
+///
+/// See the [module level documentation](index.html) for more details.
+#[derive(Debug)]
+pub struct Server<R, W> {
+    reader: R,
+    writer: W,
+    root: Option<PathBuf>,
+    project: Option<Project>,
+    include_paths: Vec<PathBuf>,
+    code_paths: Vec<PathBuf>,
+    path_variables: PathVariables,
+    documents: HashMap<String, Analysis>,
+}
+impl<R: BufRead, W: Write> Server<R, W> {
+    /// Makes a new `Server` instance which reads messages from `reader` and
+    /// writes messages to `writer`.
+    pub fn new(reader: R, writer: W) -> Self {
+        Server {
+            reader,
+            writer,
+            root: None,
+            project: None,
+            include_paths: Vec::new(),
+            code_paths: Vec::new(),
+            path_variables: PathVariables::new(),
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Serves the client until an `exit` notification is received or the input is exhausted.
+    pub fn run(&mut self) -> Result<()> {
+        while let Some(message) = track!(protocol::read_message(&mut self.reader))? {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    let response = error_response(&Value::Null, PARSE_ERROR, &e.to_string());
+                    track!(protocol::write_message(&mut self.writer, &response))?;
+                    continue;
+                }
+            };
+            let method = match message.get("method").and_then(|m| m.as_str()) {
+                Some(method) => method.to_string(),
+                None => continue, // A response to a request made by the server
+            };
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            if let Some(id) = message.get("id") {
+                let response = match self.handle_request(&method, &params) {
+                    Some(Ok(result)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    Some(Err(e)) => error_response(id, REQUEST_FAILED, &e.to_string()),
+                    None => error_response(id, METHOD_NOT_FOUND, &method),
+                };
+                track!(protocol::write_message(&mut self.writer, &response))?;
+            } else if method == "exit" {
+                break;
+            } else {
+                track!(self.handle_notification(&method, &params))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, method: &str, params: &Value) -> Option<Result<Value>> {
+        let result = match method {
+            "initialize" => Ok(self.initialize(params)),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/hover" => track!(self.hover(params)),
+            "textDocument/definition" => track!(self.definition(params)),
+            "textDocument/references" => track!(self.references(params)),
+            "textDocument/semanticTokens/full" => track!(self.semantic_tokens(params)),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value) -> Result<()> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                track!(self.update(uri, text.to_string()))?;
+            }
+            "textDocument/didChange" => {
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    track!(self.update(uri, text.to_string()))?;
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                track!(self.notify(
+                    "textDocument/publishDiagnostics",
+                    json!({"uri": uri, "diagnostics": []})
+                ))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn initialize(&mut self, params: &Value) -> Value {
+        self.root = params["rootUri"]
+            .as_str()
+            .and_then(protocol::uri_to_path)
+            .or_else(|| params["rootPath"].as_str().map(PathBuf::from));
+        self.project = self.root.as_ref().and_then(|r| Project::open(r).ok());
+
+        let options = &params["initializationOptions"];
+        let root = self.root.clone().unwrap_or_default();
+        let paths = |key: &str| -> Vec<PathBuf> {
+            options[key]
+                .as_array()
+                .map(|a| a.iter().filter_map(|p| p.as_str()).map(|p| root.join(p)))
+                .into_iter()
+                .flatten()
+                .collect()
+        };
+        self.include_paths = paths("includePaths");
+        self.code_paths = paths("codePaths");
+        if let Some(variables) = options["pathVariables"].as_object() {
+            for (name, value) in variables {
+                if let Some(value) = value.as_str() {
+                    self.path_variables.insert(name.clone(), root.join(value));
+                }
+            }
+        }
+
+        json!({
+            "capabilities": {
+                "textDocumentSync": 1,
+                "hoverProvider": true,
+                "definitionProvider": true,
+                "referencesProvider": true,
+                "semanticTokensProvider": {
+                    "legend": {"tokenTypes": ["comment"], "tokenModifiers": []},
+                    "full": true,
+                },
+            },
+            "serverInfo": {"name": "erl_pp_lsp", "version": env!("CARGO_PKG_VERSION")},
+        })
+    }
+
+    fn settings(&self, path: Option<&Path>) -> Settings {
+        let mut settings = Settings::default();
+        let app = path.and_then(|path| {
+            let apps = self.project.as_ref()?.applications();
+            apps.iter()
+                .filter(|a| path.starts_with(a.root()))
+                .max_by_key(|a| a.root().components().count())
+        });
+        if let Some(app) = app {
+            settings.include_paths = app.include_paths().clone();
+            settings.macros = app.macros().clone();
+        } else if let Some(dir) = path.and_then(|p| p.parent()) {
+            settings.include_paths.push_back(dir.join("../include"));
+        }
+        if let Some(ref project) = self.project {
+            settings.code_paths = project.code_paths().clone();
+        }
+        settings
+            .include_paths
+            .extend(self.include_paths.iter().cloned());
+        settings.code_paths.extend(self.code_paths.iter().cloned());
+        settings.path_variables = self.path_variables.clone();
+        settings
+    }
+
+    fn update(&mut self, uri: String, text: String) -> Result<()> {
+        let path = protocol::uri_to_path(&uri);
+        let settings = self.settings(path.as_deref());
+        let analysis = Analysis::new(text, path, settings);
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|d| {
+                json!({
+                    "range": protocol::range(
+                        analysis.lines.position(&analysis.text, d.start),
+                        analysis.lines.position(&analysis.text, d.end)
+                    ),
+                    "severity": d.severity,
+                    "source": "erl_pp",
+                    "message": d.message,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.documents.insert(uri.clone(), analysis);
+        track!(self.notify(
+            "textDocument/publishDiagnostics",
+            json!({"uri": uri, "diagnostics": diagnostics})
+        ))
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> Result<()> {
+        let message = json!({"jsonrpc": "2.0", "method": method, "params": params});
+        track!(protocol::write_message(&mut self.writer, &message))
+    }
+
+    /// Returns the document and the offset specified by `TextDocumentPositionParams`.
+    fn document_position<'a>(&self, params: &'a Value) -> Result<(&'a str, &Analysis, usize)> {
+        let uri = track_assert_some!(
+            params["textDocument"]["uri"].as_str(),
+            crate::ErrorKind::InvalidInput
+        );
+        let analysis = track_assert_some!(
+            self.documents.get(uri),
+            crate::ErrorKind::InvalidInput,
+            "Unknown document: {}",
+            uri
+        );
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        let offset = analysis.lines.offset(&analysis.text, line, character);
+        Ok((uri, analysis, offset))
+    }
+
+    fn hover(&self, params: &Value) -> Result<Value> {
+        let (_, analysis, offset) = track!(self.document_position(params))?;
+        let call = match analysis.call_at(offset) {
+            Some(call) => call,
+            None => return Ok(Value::Null),
+        };
+        let mut contents = match analysis.definition(call) {
+            Ok(definition) => format!(
+                "```erlang\n{}\n```",
+                render_definition(call.name.text(), definition)
+            ),
+            Err(e) => return Ok(hover_value(analysis, call, analysis::describe(&e).1)),
+        };
+        match analysis.expand(call) {
+            Ok(ref expansion) if expansion.one_step_text() != expansion.full_text() => {
+                contents.push_str(&format!(
+                    "\n\nOne-step expansion:\n\n```erlang\n{}\n```\n\nFull expansion:\n\n```erlang\n{}\n```",
+                    expansion.one_step_text(),
+                    expansion.full_text()
+                ))
+            }
+            Ok(expansion) => contents.push_str(&format!(
+                "\n\nExpansion:\n\n```erlang\n{}\n```",
+                expansion.full_text()
+            )),
+            Err(e) => contents.push_str(&format!(
+                "\n\nExpansion failed: {}",
+                analysis::describe(&e).1
+            )),
+        }
+        if let Ok(MacroDef::Static(d)) = analysis.definition(call) {
+            let position = d.start_position();
+            let file = position
+                .filepath()
+                .map_or("".to_string(), |f| f.display().to_string());
+            contents.push_str(&format!("\n\nDefined at `{}:{}`", file, position.line()));
+        }
+        Ok(hover_value(analysis, call, contents))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value> {
+        let (uri, analysis, offset) = track!(self.document_position(params))?;
+        if let Some(call) = analysis.call_at(offset) {
+            if let Ok(MacroDef::Static(d)) = analysis.definition(call) {
+                let name = &d.name;
+                return Ok(location(
+                    uri,
+                    analysis,
+                    &name.start_position(),
+                    &name.end_position(),
+                ));
+            }
+        } else if let Some(d) = analysis.define_at(offset) {
+            let name = &d.name;
+            return Ok(location(
+                uri,
+                analysis,
+                &name.start_position(),
+                &name.end_position(),
+            ));
+        } else if let Some(d) = analysis.include_at(offset) {
+            let path = match *d {
+                Directive::Include(ref d) => d.resolve(
+                    &analysis.settings.include_paths,
+                    &analysis.settings.path_variables,
+                ),
+                Directive::IncludeLib(ref d) => d.resolve(
+                    &analysis.settings.code_paths,
+                    &analysis.settings.path_variables,
+                ),
+                _ => unreachable!(),
+            };
+            if let Some(path) = path.ok().filter(|p| p.is_file()) {
+                let path = path.canonicalize().unwrap_or(path);
+                return Ok(json!({
+                    "uri": protocol::path_to_uri(&path),
+                    "range": protocol::range((0, 0), (0, 0)),
+                }));
+            }
+        }
+        Ok(Value::Null)
+    }
+
+    fn references(&self, params: &Value) -> Result<Value> {
+        let (uri, analysis, offset) = track!(self.document_position(params))?;
+        let name = if let Some(call) = analysis.call_at(offset) {
+            call.name.value().to_string()
+        } else if let Some(d) = analysis.define_at(offset) {
+            d.name.value().to_string()
+        } else {
+            return Ok(Value::Null);
+        };
+
+        let mut locations = Vec::new();
+        if params["context"]["includeDeclaration"].as_bool() == Some(true) {
+            for d in analysis.directives.values() {
+                if let Directive::Define(ref d) = *d {
+                    if d.name.value() == name {
+                        let (start, end) = (d.name.start_position(), d.name.end_position());
+                        locations.push(location(uri, analysis, &start, &end));
+                    }
+                }
+            }
+        }
+        for call in analysis.calls.iter().filter(|c| c.name.value() == name) {
+            let (start, end) = (call.question.clone(), call.name.end_position());
+            locations.push(location(uri, analysis, &start, &end));
+        }
+        Ok(Value::Array(locations))
+    }
+
+    fn semantic_tokens(&self, params: &Value) -> Result<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let analysis = track_assert_some!(
+            self.documents.get(uri),
+            crate::ErrorKind::InvalidInput,
+            "Unknown document: {}",
+            uri
+        );
+        let text = &analysis.text;
+        let mut data = Vec::new();
+        let mut prev = (0, 0);
+        for item in analysis.items.iter().filter(|i| i.inactive) {
+            for (start, end) in analysis.lines.lines(item.start, item.end) {
+                let segment = match text.get(start..end) {
+                    Some(s) if !s.trim().is_empty() => s,
+                    _ => continue,
+                };
+                let start = start + (segment.len() - segment.trim_start().len());
+                let length = segment.trim().encode_utf16().count();
+                let (line, character) = analysis.lines.position(text, start);
+                let delta_character = if line == prev.0 {
+                    character - prev.1
+                } else {
+                    character
+                };
+                data.extend_from_slice(&[line - prev.0, delta_character, length, 0, 0]);
+                prev = (line, character);
+            }
+        }
+        Ok(json!({ "data": data }))
+    }
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": code, "message": message},
+    })
+}
+
+fn position(analysis: &Analysis, position: &Position) -> (usize, usize) {
+    if analysis.is_local(position) {
+        analysis.lines.position(&analysis.text, position.offset())
+    } else {
+        protocol::file_position(position)
+    }
+}
+
+fn location(uri: &str, analysis: &Analysis, start: &Position, end: &Position) -> Value {
+    let uri = match start.filepath() {
+        Some(path) if !analysis.is_local(start) => protocol::path_to_uri(path),
+        _ => uri.to_string(),
+    };
+    json!({
+        "uri": uri,
+        "range": protocol::range(position(analysis, start), position(analysis, end)),
+    })
+}
+
+fn hover_value(analysis: &Analysis, call: &Call, contents: String) -> Value {
+    json!({
+        "contents": {"kind": "markdown", "value": contents},
+        "range": protocol::range(
+            position(analysis, &call.question),
+            position(analysis, &call.name.end_position())
+        ),
+    })
+}
+
+fn render_definition(name: &str, definition: &MacroDef) -> String {
+    match *definition {
+        MacroDef::Static(ref d) => format!(
+            "-define({}{}, {}).",
+            d.name.text(),
+            d.variables
+                .as_ref()
+                .map_or(String::new(), |v| v.to_string()),
+            util::format_tokens(&d.replacement)
+        ),
+        MacroDef::Dynamic(ref tokens) => {
+            format!("-define({}, {}).", name, util::format_tokens(tokens))
+        }
+        MacroDef::Predefined(_) => format!("%% Predefined macro: ?{}", name),
+    }
+}
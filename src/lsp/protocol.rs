@@ -0,0 +1,188 @@
+//! Low-level parts of the protocol: message framing, URIs and text positions.
+use erl_tokenize::Position;
+use serde_json::{json, Value};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use trackable::error::ErrorKindExt;
+
+use crate::{Error, ErrorKind, Result};
+
+/// Reads a message (the content part of a `Content-Length` framed message).
+///
+/// Returns `None` if the input is exhausted.
+/// If the message is malformed (e.g., it has no `Content-Length` header or the content is not
+/// valid JSON), the inner result is an error. The message has been consumed in that case,
+/// so the next message can be read.
+pub fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<Result<Value>>> {
+    let mut content_length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if track!(reader.read_line(&mut line).map_err(Error::from))? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = Some(value.trim().parse::<usize>().map_err(|e| {
+                    track!(
+                        Error::from(ErrorKind::InvalidInput.cause(e)),
+                        "line={:?}",
+                        line
+                    )
+                }));
+            }
+        }
+    }
+    let length = match content_length {
+        Some(Ok(length)) => length,
+        Some(Err(e)) => return Ok(Some(Err(e))),
+        None => {
+            let e = ErrorKind::InvalidInput.cause("No Content-Length header");
+            return Ok(Some(Err(track!(Error::from(e)))));
+        }
+    };
+    let mut content = Vec::new();
+    track!(reader
+        .take(length as u64)
+        .read_to_end(&mut content)
+        .map_err(Error::from))?;
+    if content.len() < length {
+        let e = ErrorKind::InvalidInput.cause("Truncated content");
+        return Ok(Some(Err(track!(Error::from(e)))));
+    }
+    Ok(Some(
+        serde_json::from_slice(&content).map_err(|e| track!(Error::from(e))),
+    ))
+}
+
+/// Writes a message with a `Content-Length` header.
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> Result<()> {
+    let content = message.to_string();
+    track!(write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )
+    .map_err(Error::from))?;
+    track!(writer.flush().map_err(Error::from))?;
+    Ok(())
+}
+
+/// Converts a `file://` URI to a path.
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = path
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+/// Converts a path to a `file://` URI.
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+/// Conversion table between byte offsets and LSP positions (zero-based lines and UTF-16 columns).
+#[derive(Debug)]
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { line_starts }
+    }
+
+    /// Returns the `(line, character)` pair of the given offset.
+    pub fn position(&self, text: &str, offset: usize) -> (usize, usize) {
+        let offset = offset.min(text.len());
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let start = self.line_starts[line];
+        let character = text[start..offset].encode_utf16().count();
+        (line, character)
+    }
+
+    /// Returns the byte offset of the given `(line, character)` pair.
+    pub fn offset(&self, text: &str, line: usize, character: usize) -> usize {
+        let start = match self.line_starts.get(line) {
+            Some(&start) => start,
+            None => return text.len(),
+        };
+        let mut units = 0;
+        for (i, c) in text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        text.len()
+    }
+
+    /// Returns the byte ranges of the lines which overlap the given range.
+    pub fn lines(&self, start: usize, end: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let first = match self.line_starts.binary_search(&start) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        self.line_starts[first..]
+            .iter()
+            .enumerate()
+            .take_while(move |&(_, &s)| s < end)
+            .map(move |(i, &s)| {
+                let line_end = self
+                    .line_starts
+                    .get(first + i + 1)
+                    .map_or(end, |&e| (e - 1).min(end));
+                (s.max(start), line_end)
+            })
+    }
+}
+
+/// Makes an LSP range object.
+pub fn range(start: (usize, usize), end: (usize, usize)) -> Value {
+    json!({
+        "start": {"line": start.0, "character": start.1},
+        "end": {"line": end.0, "character": end.1},
+    })
+}
+
+/// Returns the `(line, character)` pair of a position in a file other than the document.
+///
+/// Columns are counted in bytes since the text of the file is not at hand.
+pub fn file_position(position: &Position) -> (usize, usize) {
+    (
+        position.line().saturating_sub(1),
+        position.column().saturating_sub(1),
+    )
+}
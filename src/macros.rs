@@ -1,29 +1,77 @@
-use erl_tokenize::tokens::{SymbolToken, VariableToken};
+use erl_tokenize::tokens::{AtomToken, StringToken, SymbolToken};
 use erl_tokenize::values::Symbol;
 use erl_tokenize::{LexicalToken, Position, PositionRange};
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 
 use crate::directives::Define;
+use crate::expander;
 use crate::token_reader::{ReadFrom, TokenReader};
 use crate::types::{MacroArgs, MacroName};
+use crate::util;
 use crate::Result;
 
 /// Macro Definition.
+///
+/// The bodies of the definitions are shared,
+/// so cloning a definition (or a whole macro table) is cheap.
 #[derive(Debug, Clone)]
 #[allow(missing_docs)]
-#[allow(clippy::large_enum_variant)]
 pub enum MacroDef {
-    Static(Define),
-    Dynamic(Vec<LexicalToken>),
+    Static(Arc<Define>),
+    Dynamic(Arc<[LexicalToken]>),
+    Predefined(PredefinedMacro),
 }
 impl MacroDef {
     /// Returns `true` if this macro has variables, otherwise `false`.
     pub fn has_variables(&self) -> bool {
         match *self {
             MacroDef::Static(ref d) => d.variables.is_some(),
-            MacroDef::Dynamic(_) => false,
+            MacroDef::Dynamic(_) | MacroDef::Predefined(_) => false,
         }
     }
+
+    /// Returns the number of the variables of this macro.
+    ///
+    /// If this macro has no variables (i.e., it is defined as `-define(FOO, ...)`),
+    /// this method returns `None`.
+    pub fn arity(&self) -> Option<usize> {
+        match *self {
+            MacroDef::Static(ref d) => d.variables.as_ref().map(|v| v.len()),
+            MacroDef::Dynamic(_) | MacroDef::Predefined(_) => None,
+        }
+    }
+}
+
+/// Predefined macro.
+///
+/// See [9.3 Predefined Macros][predefined] for detailed information.
+///
+/// [predefined]: http://erlang.org/doc/reference_manual/macros.html#predefined-macros
+#[derive(Debug, Clone)]
+pub enum PredefinedMacro {
+    /// `?FILE`: the path of the file in which the macro is called.
+    File,
+
+    /// `?LINE`: the line number at which the macro is called.
+    Line,
+
+    /// `?MACHINE`: the atom `'BEAM'`.
+    Machine,
+
+    /// `?MODULE`: the name of the current module.
+    Module(AtomToken),
+
+    /// `?MODULE_STRING`: the name of the current module as a string.
+    ModuleString(AtomToken),
+
+    /// `?BASE_MODULE`: the name of the first module declared by a `module` attribute.
+    BaseModule(AtomToken),
+
+    /// `?BASE_MODULE_STRING`: the name of the first module declared by a `module` attribute
+    /// as a string.
+    BaseModuleString(AtomToken),
 }
 
 /// Macro call.
@@ -69,51 +117,340 @@ impl ReadFrom for MacroCall {
     }
 }
 
+/// A change of the macro definitions made by a directive.
+///
+/// See `Preprocessor::macro_events`.
 #[derive(Debug, Clone)]
-pub struct NoArgsMacroCall {
-    pub _question: SymbolToken,
-    pub name: MacroName,
+pub enum MacroEvent {
+    /// A macro is defined by a `define` directive.
+    ///
+    /// `?MODULE` and its family are defined by a `module` attribute
+    /// (`position` is the start position of the attribute in that case).
+    Define {
+        /// The start position of the directive.
+        position: Position,
+
+        /// The name of the macro.
+        name: String,
+
+        /// The new definition.
+        definition: MacroDef,
+    },
+
+    /// A macro is removed by an `undef` directive.
+    Undef {
+        /// The start position of the directive.
+        position: Position,
+
+        /// The name of the macro.
+        name: String,
+    },
 }
-impl ReadFrom for NoArgsMacroCall {
-    fn read_from<T, E>(reader: &mut TokenReader<T, E>) -> Result<Self>
-    where
-        T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
-        E: Into<crate::Error>,
-    {
-        Ok(NoArgsMacroCall {
-            _question: track!(reader.read_expected(&Symbol::Question))?,
-            name: track!(reader.read())?,
-        })
+impl MacroEvent {
+    /// Returns the start position of the directive which caused this event.
+    pub fn position(&self) -> &Position {
+        match *self {
+            MacroEvent::Define { ref position, .. } | MacroEvent::Undef { ref position, .. } => {
+                position
+            }
+        }
+    }
+
+    /// Returns the name of the macro changed by this event.
+    pub fn name(&self) -> &str {
+        match *self {
+            MacroEvent::Define { ref name, .. } | MacroEvent::Undef { ref name, .. } => name,
+        }
+    }
+
+    /// Applies this event to the given macro table in the same manner as `Preprocessor`.
+    pub(crate) fn apply(&self, macros: &mut HashMap<String, Vec<MacroDef>>) {
+        match *self {
+            MacroEvent::Define {
+                ref name,
+                definition: ref d @ MacroDef::Predefined(_),
+                ..
+            } => {
+                macros.insert(name.clone(), vec![d.clone()]);
+            }
+            MacroEvent::Define {
+                ref name,
+                ref definition,
+                ..
+            } => {
+                let defs = macros.entry(name.clone()).or_default();
+                let arity = definition.arity();
+                defs.retain(|m| m.arity() != arity && !matches!(m, MacroDef::Predefined(_)));
+                defs.push(definition.clone());
+            }
+            MacroEvent::Undef { ref name, .. } => {
+                macros.remove(name);
+            }
+        }
     }
 }
 
+/// A completion candidate for a macro call.
+///
+/// See `Preprocessor::macro_completions`.
 #[derive(Debug, Clone)]
-pub struct Stringify {
-    pub _double_question: SymbolToken,
-    pub name: VariableToken,
+pub struct MacroCompletion {
+    /// The name of the macro.
+    pub name: String,
+
+    /// The number of the variables of the macro (see `MacroDef::arity`).
+    pub arity: Option<usize>,
+
+    /// The names of the variables of the macro.
+    pub parameters: Vec<String>,
+
+    /// The start position of the `define` directive of the macro.
+    ///
+    /// `None` for predefined macros and the macros defined via `Preprocessor::macros_mut`
+    /// (i.e., `MacroDef::Dynamic`).
+    pub position: Option<Position>,
+
+    /// The replacement of the macro formatted as Erlang source code text.
+    ///
+    /// For predefined macros, this is the value at the position of the completion
+    /// (e.g., the line number for `?LINE`).
+    pub preview: String,
+
+    /// The definition of the macro.
+    pub definition: MacroDef,
 }
-impl PositionRange for Stringify {
-    fn start_position(&self) -> Position {
-        self._double_question.start_position()
-    }
-    fn end_position(&self) -> Position {
-        self.name.end_position()
+impl MacroCompletion {
+    pub(crate) fn new(name: &str, definition: &MacroDef, position: &Position) -> Self {
+        let mut completion = MacroCompletion {
+            name: name.to_string(),
+            arity: definition.arity(),
+            parameters: Vec::new(),
+            position: None,
+            preview: String::new(),
+            definition: definition.clone(),
+        };
+        match *definition {
+            MacroDef::Static(ref d) => {
+                completion.parameters = d
+                    .variables
+                    .iter()
+                    .flat_map(|v| v.iter())
+                    .map(|v| v.value().to_string())
+                    .collect();
+                completion.position = Some(d.start_position());
+                completion.preview = util::format_tokens(&d.replacement);
+            }
+            MacroDef::Dynamic(ref replacement) => {
+                completion.preview = util::format_tokens(replacement);
+            }
+            MacroDef::Predefined(ref m) => {
+                // `?FILE` cannot be expanded if the position has no file path
+                if let Ok(token) = expander::expand_predefined_macro(m, position) {
+                    completion.preview = token.text().to_string();
+                }
+            }
+        }
+        completion
     }
 }
-impl fmt::Display for Stringify {
+impl fmt::Display for MacroCompletion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "??{}", self.name.text())
+        write!(f, "?{}", self.name)?;
+        if self.arity.is_some() {
+            write!(f, "({})", self.parameters.join(", "))?;
+        }
+        Ok(())
     }
 }
-impl ReadFrom for Stringify {
-    fn read_from<T, E>(reader: &mut TokenReader<T, E>) -> Result<Self>
-    where
-        T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
-        E: Into<crate::Error>,
-    {
-        Ok(Stringify {
-            _double_question: track!(reader.read_expected(&Symbol::DoubleQuestion))?,
-            name: track!(reader.read())?,
+
+/// Expansions of a macro call.
+///
+/// This is returned by `Preprocessor::expand_macro_at`.
+#[derive(Debug, Clone)]
+pub struct MacroExpansion {
+    call: MacroCall,
+    definition: MacroDef,
+    one_step: Vec<LexicalToken>,
+    full: Vec<LexicalToken>,
+}
+impl MacroExpansion {
+    pub(crate) fn new(
+        call: MacroCall,
+        definition: MacroDef,
+        one_step: Vec<LexicalToken>,
+        full: Vec<LexicalToken>,
+    ) -> Self {
+        MacroExpansion {
+            call,
+            definition,
+            one_step,
+            full,
+        }
+    }
+
+    /// Returns the expanded macro call.
+    pub fn call(&self) -> &MacroCall {
+        &self.call
+    }
+
+    /// Returns the definition of the called macro.
+    pub fn definition(&self) -> &MacroDef {
+        &self.definition
+    }
+
+    /// Returns the result of expanding the call only once.
+    ///
+    /// The macro calls in the replacement of the macro are left unexpanded.
+    pub fn one_step(&self) -> &[LexicalToken] {
+        &self.one_step
+    }
+
+    /// Returns the result of expanding the call recursively.
+    pub fn full(&self) -> &[LexicalToken] {
+        &self.full
+    }
+
+    /// Returns `MacroExpansion::one_step` as Erlang source code text.
+    pub fn one_step_text(&self) -> String {
+        util::format_tokens(&self.one_step)
+    }
+
+    /// Returns `MacroExpansion::full` as Erlang source code text.
+    pub fn full_text(&self) -> String {
+        util::format_tokens(&self.full)
+    }
+}
+
+/// Converts the given tokens to a string token in the same manner as `epp` does for `??Arg`.
+///
+/// Each token is printed by the `io_lib:write/1` rules and the results are joined with a space.
+pub fn stringify(tokens: &[LexicalToken], position: Position) -> Result<StringToken> {
+    let mut s = String::new();
+    for (i, t) in tokens.iter().enumerate() {
+        if i > 0 {
+            s.push(' ');
+        }
+        s.push_str(&token_src(t));
+    }
+    let token = track!(StringToken::from_text(&write_string(&s, '"'), position))?;
+    Ok(token)
+}
+
+fn token_src(token: &LexicalToken) -> String {
+    match *token {
+        LexicalToken::Atom(ref t) => write_atom(t.value()),
+        LexicalToken::Char(ref t) => {
+            let mut s = "$".to_string();
+            if t.value() == ' ' {
+                s.push_str("\\s");
+            } else {
+                write_char(&mut s, t.value(), None);
+            }
+            s
+        }
+        LexicalToken::Float(ref t) => write_float(t.value()),
+        LexicalToken::Integer(ref t) => t.value().to_string(),
+        LexicalToken::String(ref t) => write_string(t.value(), '"'),
+        LexicalToken::Keyword(_) | LexicalToken::Symbol(_) | LexicalToken::Variable(_) => {
+            token.text().to_string()
+        }
+    }
+}
+
+fn write_atom(atom: &str) -> String {
+    let mut chars = atom.chars();
+    let unquoted = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || is_latin1_lowercase(c))
+        && chars.all(|c| {
+            c.is_ascii_alphanumeric()
+                || c == '_'
+                || c == '@'
+                || is_latin1_lowercase(c)
+                || (('\u{c0}'..='\u{de}').contains(&c) && c != '\u{d7}')
         })
+        && !RESERVED_WORDS.contains(&atom);
+    if unquoted {
+        atom.to_string()
+    } else {
+        write_string(atom, '\'')
+    }
+}
+
+fn is_latin1_lowercase(c: char) -> bool {
+    ('\u{df}'..='\u{ff}').contains(&c) && c != '\u{f7}'
+}
+
+fn write_string(value: &str, quote: char) -> String {
+    let mut s = String::with_capacity(value.len() + 2);
+    s.push(quote);
+    for c in value.chars() {
+        write_char(&mut s, c, Some(quote));
     }
+    s.push(quote);
+    s
 }
+
+fn write_char(s: &mut String, c: char, quote: Option<char>) {
+    match c {
+        _ if Some(c) == quote => {
+            s.push('\\');
+            s.push(c);
+        }
+        '\\' => s.push_str("\\\\"),
+        ' '..='~' => s.push(c),
+        '\n' => s.push_str("\\n"),
+        '\r' => s.push_str("\\r"),
+        '\t' => s.push_str("\\t"),
+        '\u{b}' => s.push_str("\\v"),
+        '\u{8}' => s.push_str("\\b"),
+        '\u{c}' => s.push_str("\\f"),
+        '\u{1b}' => s.push_str("\\e"),
+        '\u{7f}' => s.push_str("\\d"),
+        _ if c < '\u{a0}' => s.push_str(&format!("\\{:03o}", c as u32)),
+        _ => s.push(c),
+    }
+}
+
+fn write_float(f: f64) -> String {
+    if f == 0.0 {
+        return "0.0".to_string();
+    }
+
+    // The shortest digits which uniquely identify `f` (like `io_lib_format:fwrite_g/1`)
+    let sci = format!("{:e}", f);
+    let e = sci.find('e').expect("Never fails");
+    let digits = sci[..e]
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>();
+    let exp = sci[e + 1..].parse::<i64>().expect("Never fails");
+
+    let place = exp + 1;
+    let len = digits.len() as i64;
+    if place == 0 {
+        format!("0.{}", digits)
+    } else if place < 0 || place >= len {
+        let exp = (place - 1).to_string();
+        let exp_dot = if len == 1 { 2 } else { 1 };
+        let exp_cost = exp.len() as i64 + 1 + exp_dot;
+        if place < 0 && 2 - place <= exp_cost {
+            format!("0.{}{}", "0".repeat(-place as usize), digits)
+        } else if place >= 0 && place - len + 2 <= exp_cost {
+            format!("{}{}.0", digits, "0".repeat((place - len) as usize))
+        } else if len == 1 {
+            format!("{}.0e{}", digits, exp)
+        } else {
+            format!("{}.{}e{}", &digits[..1], &digits[1..], exp)
+        }
+    } else {
+        let (int, frac) = digits.split_at(place as usize);
+        format!("{}.{}", int, frac)
+    }
+}
+
+const RESERVED_WORDS: &[&str] = &[
+    "after", "and", "andalso", "band", "begin", "bnot", "bor", "bsl", "bsr", "bxor", "case",
+    "catch", "cond", "div", "end", "fun", "if", "let", "not", "of", "or", "orelse", "receive",
+    "rem", "try", "when", "xor",
+];
@@ -0,0 +1,121 @@
+use erl_tokenize::Position;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::{ErrorKind, Result};
+
+/// Variables which are substituted for `$VAR` components in the paths of
+/// `include` and `include_lib` directives.
+///
+/// A variable is looked up in this map first, and then in the environment of the process
+/// (unless `PathVariables::set_use_env(false)` is called).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::{ErrorKind, Preprocessor};
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let src = r#"-include("$TESTS/bar.hrl")."#;
+/// let mut pp = Preprocessor::new(Lexer::new(src));
+/// pp.path_variables_mut().insert("TESTS", "tests");
+/// let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(tokens[0].text(), "bar");
+///
+/// let mut pp = Preprocessor::new(Lexer::new(src));
+/// pp.path_variables_mut().set_use_env(false);
+/// let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+/// if let ErrorKind::UndefinedPathVariable { ref name, ref position } = *e.kind() {
+///     assert_eq!(name, "TESTS");
+///     assert_eq!(position.offset(), 0);
+/// } else {
+///     panic!("{}", e);
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PathVariables {
+    variables: HashMap<String, OsString>,
+    use_env: bool,
+}
+impl PathVariables {
+    /// Makes a new empty `PathVariables` instance which falls back to the process environment.
+    pub fn new() -> Self {
+        PathVariables {
+            variables: HashMap::new(),
+            use_env: true,
+        }
+    }
+
+    /// Returns the value of the given variable.
+    pub fn get(&self, name: &str) -> Option<OsString> {
+        if let Some(value) = self.variables.get(name) {
+            Some(value.clone())
+        } else if self.use_env {
+            env::var_os(name)
+        } else {
+            None
+        }
+    }
+
+    /// Defines a variable.
+    ///
+    /// If the variable is already defined, the old value is returned.
+    pub fn insert<K, V>(&mut self, name: K, value: V) -> Option<OsString>
+    where
+        K: Into<String>,
+        V: Into<OsString>,
+    {
+        self.variables.insert(name.into(), value.into())
+    }
+
+    /// Removes a variable defined by `PathVariables::insert`.
+    pub fn remove(&mut self, name: &str) -> Option<OsString> {
+        self.variables.remove(name)
+    }
+
+    /// Returns `true` if the process environment is used for the variables
+    /// which are not defined in this map, otherwise `false`.
+    pub fn use_env(&self) -> bool {
+        self.use_env
+    }
+
+    /// Sets whether the process environment is used for the variables
+    /// which are not defined in this map.
+    pub fn set_use_env(&mut self, use_env: bool) {
+        self.use_env = use_env;
+    }
+
+    /// Substitutes the values of the variables for the `$VAR` components of `path`.
+    ///
+    /// `position` is the position of the directive which contains `path`, and
+    /// is used for reporting `ErrorKind::UndefinedPathVariable`.
+    pub fn substitute<P: AsRef<Path>>(&self, path: P, position: &Position) -> Result<PathBuf> {
+        let mut new = PathBuf::new();
+        for c in path.as_ref().components() {
+            if let Some(name) = c.as_os_str().to_str().and_then(|s| s.strip_prefix('$')) {
+                let value = track_assert_some!(
+                    self.get(name),
+                    ErrorKind::UndefinedPathVariable {
+                        name: name.to_string(),
+                        position: position.clone(),
+                    }
+                );
+                new.push(value);
+                continue;
+            }
+            new.push(c.as_os_str());
+        }
+        Ok(new)
+    }
+}
+impl Default for PathVariables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
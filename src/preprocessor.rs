@@ -1,12 +1,28 @@
-use erl_tokenize::tokens::{AtomToken, IntegerToken, StringToken};
 use erl_tokenize::values::Symbol;
 use erl_tokenize::{self, LexicalToken, Position, PositionRange};
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use crate::macros::Stringify;
+use crate::attributes::Attributes;
+use crate::directives::Define;
+use crate::expander::{self, AcyclicMacros, Expander};
+use crate::form::{Form, Forms};
+use crate::header_cache::HeaderCache;
+use crate::include_policy::IncludePolicy;
+use crate::limits::{Limits, Usage};
+use crate::path_variables::PathVariables;
+use crate::reader_lexer::ReaderLexer;
+use crate::records::Records;
+use crate::snapshot::{self, PrefixForm, Snapshot};
 use crate::token_reader::TokenReader;
-use crate::{Directive, Error, ErrorKind, MacroCall, MacroDef, Result};
+use crate::{
+    Directive, Error, ErrorKind, MacroCall, MacroCompletion, MacroDef, MacroEvent, MacroExpansion,
+    PredefinedMacro, Result,
+};
 
 /// Erlang source code [preprocessor][Preprocessor].
 ///
@@ -37,13 +53,32 @@ use crate::{Directive, Error, ErrorKind, MacroCall, MacroDef, Result};
 #[derive(Debug)]
 pub struct Preprocessor<T, E = erl_tokenize::Error> {
     reader: TokenReader<T, E>,
-    can_directive_start: bool,
     directives: BTreeMap<Position, Directive>,
     code_paths: VecDeque<PathBuf>,
+    include_paths: VecDeque<PathBuf>,
     branches: Vec<Branch>,
-    macros: HashMap<String, MacroDef>,
+    macros: Arc<HashMap<String, Vec<MacroDef>>>,
+    acyclic_macros: AcyclicMacros,
     macro_calls: BTreeMap<Position, MacroCall>,
     expanded_tokens: VecDeque<LexicalToken>,
+    allow_macro_redefinition: bool,
+    header_cache: Option<HeaderCache>,
+    included_files: Vec<PathBuf>,
+    prefix: Option<Vec<PrefixForm>>,
+    prefix_directives: HashSet<Position>,
+    replayed_forms: VecDeque<Vec<LexicalToken>>,
+    resumed: bool,
+    generation: u64,
+    form_span: Option<(Position, Position)>,
+    form_len: usize,
+    form_sites: Vec<(Range<usize>, Position)>,
+    usage: Usage,
+    include_policy: IncludePolicy,
+    path_variables: PathVariables,
+    timeline: Vec<TimelineEntry>,
+    timeline_checkpoints: BTreeMap<Position, usize>,
+    record_timeline: bool,
+    macros_reset: bool,
 }
 impl<T, E> Preprocessor<T, E>
 where
@@ -52,19 +87,183 @@ where
 {
     /// Makes a new `Preprocessor` instance.
     pub fn new(tokens: T) -> Self {
+        let mut reader = TokenReader::new(tokens);
+        reader.start_recording();
         Preprocessor {
-            reader: TokenReader::new(tokens),
-            can_directive_start: true,
+            reader,
             directives: BTreeMap::new(),
             code_paths: VecDeque::new(),
+            include_paths: VecDeque::new(),
             branches: Vec::new(),
-            macros: HashMap::new(),
+            macros: Arc::new(predefined_macros()),
+            acyclic_macros: AcyclicMacros::new(),
             macro_calls: BTreeMap::new(),
             expanded_tokens: VecDeque::new(),
+            allow_macro_redefinition: false,
+            header_cache: None,
+            included_files: Vec::new(),
+            prefix: Some(Vec::new()),
+            prefix_directives: HashSet::new(),
+            replayed_forms: VecDeque::new(),
+            resumed: false,
+            generation: 0,
+            form_span: None,
+            form_len: 0,
+            form_sites: Vec::new(),
+            usage: Usage::default(),
+            include_policy: IncludePolicy::new(),
+            path_variables: PathVariables::new(),
+            timeline: Vec::new(),
+            timeline_checkpoints: BTreeMap::new(),
+            record_timeline: false,
+            macros_reset: true,
         }
     }
 
-    fn ignore(&self) -> bool {
+    /// Makes a new `Preprocessor` instance which starts from the given snapshot.
+    ///
+    /// If the leading forms of `tokens` match the prefix covered by `snapshot`,
+    /// the forms are not processed again and the state of the preprocessor is restored from
+    /// the snapshot (the `-module` attribute in the prefix is processed as usual).
+    /// Otherwise, the resulting preprocessor behaves like one made by `Preprocessor::new`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::Lexer;
+    ///
+    /// # fn main() {
+    /// let mut pp = Preprocessor::new(Lexer::new("-module(foo). -define(A, 1)."));
+    /// assert_eq!(pp.by_ref().count(), 6);
+    /// let snapshot = pp.snapshot().unwrap();
+    ///
+    /// let src = "-module(bar). -define(A, 1). f() -> ?A.";
+    /// let pp = Preprocessor::from_snapshot(Lexer::new(src), &snapshot).unwrap();
+    /// assert!(pp.is_resumed());
+    ///
+    /// let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+    /// assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+    ///            ["-", "module", "(", "bar", ")", ".", "f", "(", ")", "->", "1", "."]);
+    /// # }
+    /// ```
+    pub fn from_snapshot(tokens: T, snapshot: &Snapshot) -> Result<Self> {
+        let mut pp = Preprocessor::new(tokens);
+        if !snapshot.is_up_to_date() {
+            return Ok(pp);
+        }
+
+        let mut forms = Vec::new();
+        for expected in snapshot.prefix.iter().filter(|f| f.is_toplevel()) {
+            let form = track!(pp.read_form())?;
+            let mut matched = expected.matches(&form);
+            if let (true, Some(included)) = (matched, expected.included_file()) {
+                // The same directive may include another file (e.g., in a different directory)
+                let resolved = pp.resolve_prefix_include(&form, &snapshot.state);
+                matched = resolved.ok().as_deref() == Some(included);
+            }
+            forms.push(form);
+            if !matched {
+                pp.reader.replay(forms.into_iter().flatten());
+                return Ok(pp);
+            }
+        }
+
+        pp.restore_state(&snapshot.state);
+        pp.directives = snapshot.included_directives.clone();
+        let mut forms = forms.into_iter();
+        for expected in &snapshot.prefix {
+            match *expected {
+                PrefixForm::Directive(..) => {
+                    let form = forms.next().expect("Never fails");
+                    let mut reader = TokenReader::new(form.into_iter().map(Ok::<_, Error>));
+                    let d: Directive = track!(reader.read())?;
+                    pp.directives.insert(d.start_position(), d);
+                }
+                PrefixForm::Ignored(_) => {
+                    forms.next();
+                }
+                PrefixForm::Module => {
+                    let form = forms.next().expect("Never fails");
+                    pp.replayed_forms.push_back(form);
+                }
+                PrefixForm::Included(ref form) => {
+                    pp.replayed_forms.push_back(form.clone());
+                }
+            }
+        }
+        pp.end_prefix();
+        pp.resumed = true;
+        Ok(pp)
+    }
+
+    /// Takes a snapshot of the current state of this preprocessor.
+    ///
+    /// The snapshot can be taken only if all the top-level forms (i.e., the forms which
+    /// are not in included files) consumed so far
+    /// are directives, forms skipped by conditional directives or a `-module` attribute
+    /// (otherwise `ErrorKind::InvalidInput` is returned).
+    ///
+    /// Preprocessors made by `Preprocessor::from_snapshot` can not take snapshots.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let prefix = track_assert_some!(
+            self.prefix.as_ref(),
+            ErrorKind::InvalidInput,
+            "The consumed forms are not a prefix of directives"
+        );
+        track_assert!(
+            self.reader.include_depth() == 0 && self.expanded_tokens.is_empty(),
+            ErrorKind::InvalidInput,
+            "The preprocessor is in the middle of a form"
+        );
+
+        let mut macros = HashMap::clone(&self.macros);
+        for name in &[
+            "MODULE",
+            "MODULE_STRING",
+            "BASE_MODULE",
+            "BASE_MODULE_STRING",
+        ] {
+            macros.remove(*name);
+        }
+        let included_directives = self
+            .directives
+            .iter()
+            .filter(|(p, _)| !self.prefix_directives.contains(p))
+            .map(|(p, d)| (p.clone(), d.clone()))
+            .collect();
+        let mut fingerprints = Vec::new();
+        for path in &self.included_files {
+            let fingerprint = track!(snapshot::fingerprint(path), "path={:?}", path)?;
+            fingerprints.push((path.clone(), fingerprint));
+        }
+        let mut state = self.state();
+        state.macros = Arc::new(macros);
+        Ok(Snapshot {
+            prefix: prefix.clone(),
+            state,
+            included_directives,
+            fingerprints,
+        })
+    }
+
+    /// Resolves the path of the file included by the given prefix directive
+    /// in the same way as `try_read_directive` does with the given state.
+    fn resolve_prefix_include(&self, form: &[LexicalToken], state: &State) -> Result<PathBuf> {
+        let mut reader = TokenReader::new(form.iter().cloned().map(Ok::<_, Error>));
+        match track!(reader.read())? {
+            Directive::Include(d) => {
+                track!(d.resolve(&state.include_paths, &self.path_variables))
+            }
+            Directive::IncludeLib(d) => track!(d.resolve(&state.code_paths, &self.path_variables)),
+            d => track_panic!(ErrorKind::InvalidInput, "Not an include directive: {}", d),
+        }
+    }
+
+    /// Returns `true` if the current form is in a branch which is not entered.
+    pub(crate) fn ignore(&self) -> bool {
         self.branches.iter().any(|b| !b.entered)
     }
     fn next_token(&mut self) -> Result<Option<LexicalToken>> {
@@ -72,124 +271,291 @@ where
             if let Some(token) = self.expanded_tokens.pop_front() {
                 return Ok(Some(token));
             }
-            if self.can_directive_start {
-                if let Some(d) = track!(self.try_read_directive())? {
-                    self.directives.insert(d.start_position(), d);
-                    continue;
-                }
+            if track!(self.process_form())?.is_none() {
+                return Ok(None);
             }
-            if !self.ignore() {
-                if let Some(m) = track!(self.reader.try_read_macro_call(&self.macros))? {
-                    self.macro_calls.insert(m.start_position(), m.clone());
-                    self.expanded_tokens = track!(self.expand_macro(m))?;
-                    continue;
-                }
+        }
+    }
+
+    /// Reads a form (or a directive) and processes it.
+    ///
+    /// The expanded tokens of the form are appended to `self.expanded_tokens`.
+    /// Returns the end position of the processed form, or `None` if the input is exhausted.
+    pub(crate) fn process_form(&mut self) -> Result<Option<Position>> {
+        track!(self.usage.check_deadline())?;
+        if let Some(form) = self.replayed_forms.pop_front() {
+            // The forms in the prefix skipped by `from_snapshot` are expanded one by one
+            return track!(self.emit_form(form)).map(Some);
+        }
+        if self.macros_reset && self.record_timeline {
+            self.timeline.push(TimelineEntry::Reset(self.macro_table()));
+            self.macros_reset = false;
+        }
+        let checkpoint = self.timeline.len();
+        // Leaves exhausted included files so that `include_depth()` is that of the next form
+        if let Some(token) = track!(self.reader.try_read_token())? {
+            self.reader.unread_token(token);
+        }
+        let in_prefix = self.prefix.is_some() && self.reader.include_depth() == 0;
+        let mark = self.reader.recorded().len();
+        if let Some(d) = track!(self.try_read_directive())? {
+            if in_prefix {
+                let texts = self.reader.recorded()[mark..].to_vec();
+                let included = match d {
+                    Directive::Include(_) | Directive::IncludeLib(_) if !self.ignore() => {
+                        self.included_files.last().cloned()
+                    }
+                    _ => None,
+                };
+                self.push_prefix(PrefixForm::Directive(texts, included));
+                self.prefix_directives.insert(d.start_position());
             }
-            if let Some(token) = track!(self.reader.try_read_token())? {
-                if self.ignore() {
-                    continue;
-                }
-                self.can_directive_start = token
-                    .as_symbol_token()
-                    .map_or(false, |s| s.value() == Symbol::Dot);
-                return Ok(Some(token));
-            } else {
-                break;
+            let end = d.end_position();
+            if self.record_timeline {
+                self.timeline_checkpoints
+                    .entry(d.start_position())
+                    .or_insert(checkpoint);
             }
+            self.directives.insert(d.start_position(), d);
+            return Ok(Some(end));
         }
-        Ok(None)
-    }
-    fn expand_macro(&self, call: MacroCall) -> Result<VecDeque<LexicalToken>> {
-        if let Some(expanded) = track!(self.try_expand_predefined_macro(&call))? {
-            Ok(vec![expanded].into())
+
+        let form = track!(self.read_form())?;
+        let end = if let Some(last) = form.last() {
+            last.end_position()
         } else {
-            track!(self.expand_userdefined_macro(call))
+            return Ok(None);
+        };
+        if self.record_timeline {
+            self.timeline_checkpoints
+                .entry(form[0].start_position())
+                .or_insert(checkpoint);
         }
-    }
-    fn try_expand_predefined_macro(&self, call: &MacroCall) -> Result<Option<LexicalToken>> {
-        let expanded = match call.name.value() {
-            "FILE" => {
-                let current = call.start_position();
-                let file = track_assert_some!(current.filepath(), ErrorKind::InvalidInput);
-                let file = track_assert_some!(file.to_str(), ErrorKind::InvalidInput);
-                StringToken::from_value(file, call.start_position()).into()
+        if self.ignore() {
+            if in_prefix {
+                let texts = form.iter().map(|t| t.text().to_string()).collect();
+                self.push_prefix(PrefixForm::Ignored(texts));
             }
-            "LINE" => {
-                let line = call.start_position().line();
-                IntegerToken::from_value(line.into(), call.start_position()).into()
+            return Ok(Some(end));
+        }
+        if in_prefix {
+            if snapshot::module_name(&form).is_some() {
+                self.push_prefix(PrefixForm::Module);
+            } else {
+                self.end_prefix();
             }
-            "MACHINE" => AtomToken::from_value("BEAM", call.start_position()).into(),
-            _ => return Ok(None),
-        };
-        Ok(Some(expanded))
-    }
-    fn expand_userdefined_macro(&self, call: MacroCall) -> Result<VecDeque<LexicalToken>> {
-        let definition =
-            track_assert_some!(self.macros.get(call.name.value()), ErrorKind::InvalidInput);
-        match *definition {
-            MacroDef::Dynamic(ref replacement) => Ok(replacement.clone().into()),
-            MacroDef::Static(ref definition) => {
-                track_assert_eq!(
-                    call.args.as_ref().map(|a| a.len()),
-                    definition.variables.as_ref().map(|v| v.len()),
-                    ErrorKind::InvalidInput
-                );
-                let bindings = definition
-                    .variables
-                    .as_ref()
-                    .iter()
-                    .flat_map(|i| i.iter().map(|v| v.value()))
-                    .zip(
-                        call.args
-                            .iter()
-                            .flat_map(|i| i.iter().map(|a| &a.tokens[..])),
-                    )
-                    .collect::<HashMap<_, _>>();
-                let expanded = track!(self.expand_replacement(bindings, &definition.replacement))?;
-                Ok(expanded)
+        } else if self.prefix.is_some() {
+            self.push_prefix(PrefixForm::Included(form.clone()));
+        }
+        track!(self.emit_form(form)).map(Some)
+    }
+
+    /// Expands the given (non-empty) form, and appends the resulting tokens to
+    /// `self.expanded_tokens`.
+    ///
+    /// Returns the end position of the form.
+    fn emit_form(&mut self, form: Vec<LexicalToken>) -> Result<Position> {
+        let start = form[0].start_position();
+        let end = form[form.len() - 1].end_position();
+        self.handle_module_attribute(&form);
+        self.form_span = Some((start, end.clone()));
+        let (expanded, sites) = track!(self.expand_form(form))?;
+        let offset = self.expanded_tokens.len();
+        self.form_sites = sites
+            .into_iter()
+            .map(|(r, site)| (r.start + offset..r.end + offset, site))
+            .collect();
+        self.expanded_tokens.extend(expanded);
+        self.form_len = self.expanded_tokens.len();
+        Ok(end)
+    }
+
+    /// Returns an iterator over the preprocessed top-level forms.
+    ///
+    /// Directives are not contained in the resulting forms.
+    /// If some tokens of the current form have already been consumed via `Iterator::next`,
+    /// the first form yielded by the iterator consists of the remaining tokens.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::{Lexer, PositionRange};
+    ///
+    /// # fn main() {
+    /// let src = "-define(R, #r{}.f). foo() -> ?R. bar() -> ok.";
+    /// let mut pp = Preprocessor::new(Lexer::new(src));
+    /// let forms = pp.forms().collect::<Result<Vec<_>, _>>().unwrap();
+    ///
+    /// assert_eq!(forms.len(), 2);
+    /// assert_eq!(forms[0].tokens().iter().map(|t| t.text()).collect::<String>(),
+    ///            "foo()->#r{}.f.");
+    /// assert_eq!(forms[0].start_position().offset(), 20);
+    /// assert_eq!(forms[0].end_position().offset(), 32);
+    /// # }
+    /// ```
+    pub fn forms(&mut self) -> Forms<'_, T, E> {
+        Forms::new(self)
+    }
+
+    /// Returns an iterator over the module attributes in the preprocessed forms
+    /// (including the ones in included files).
+    ///
+    /// The forms which are not attributes are skipped.
+    /// `include` and `include_lib` directives are also yielded when they are processed.
+    /// See `Attribute` for more details.
+    pub fn attributes(&mut self) -> Attributes<'_, T, E> {
+        Attributes::new(self)
+    }
+
+    /// Returns an iterator over the record definitions in the preprocessed forms
+    /// (including the ones in included files).
+    ///
+    /// The forms which are not `record` attributes are skipped.
+    /// See `Record` for more details.
+    pub fn records(&mut self) -> Records<'_, T, E> {
+        Records::new(self)
+    }
+
+    /// Reads the next top-level form.
+    pub(crate) fn next_form(&mut self) -> Result<Option<Form>> {
+        loop {
+            if let Some(form) = self.take_form() {
+                return Ok(Some(form));
+            }
+            if track!(self.process_form())?.is_none() {
+                return Ok(None);
             }
         }
     }
-    fn expand_replacement(
-        &self,
-        bindings: HashMap<&str, &[LexicalToken]>,
-        replacement: &[LexicalToken],
-    ) -> Result<VecDeque<LexicalToken>> {
-        let mut expanded = VecDeque::new();
-        let mut reader: TokenReader<_, Error> =
-            TokenReader::new(replacement.iter().map(|t| Ok(t.clone())));
+
+    /// Finds the top-level macro call at `position`, and expands it.
+    ///
+    /// The input is preprocessed up to the form which contains `position`
+    /// (the preprocessed tokens are discarded), and the call is expanded with
+    /// the macro definitions in effect at that point.
+    /// Like `Preprocessor::macro_calls`, only the calls which appear in the input
+    /// (including included files) can be found.
+    ///
+    /// Returns `None` if there is no macro call at `position`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::{Lexer, PositionRange};
+    ///
+    /// # fn main() {
+    /// let src = r#"-define(INC(X), X + ?ONE). -define(ONE, 1). foo() -> ?INC(2)."#;
+    /// let position = Lexer::new(src)
+    ///     .filter_map(|t| t.ok())
+    ///     .find(|t| t.text() == "INC" && t.start_position().offset() > 40)
+    ///     .unwrap()
+    ///     .start_position();
+    ///
+    /// let mut pp = Preprocessor::new(Lexer::new(src));
+    /// let expansion = pp.expand_macro_at(&position).unwrap().unwrap();
+    /// assert_eq!(expansion.call().to_string(), "?INC(2)");
+    /// assert_eq!(expansion.one_step_text(), "2 + ?ONE");
+    /// assert_eq!(expansion.full_text(), "2 + 1");
+    /// # }
+    /// ```
+    pub fn expand_macro_at(&mut self, position: &Position) -> Result<Option<MacroExpansion>> {
         loop {
-            if let Some(call) = track!(reader.try_read_macro_call(&self.macros))? {
-                let nested = track!(self.expand_macro(call))?;
-                for token in nested.into_iter().rev() {
-                    reader.unread_token(token);
-                }
-            } else if let Some(stringify) = track!(reader.try_read::<Stringify>())? {
-                let tokens = track_assert_some!(
-                    bindings.get(stringify.name.value()),
-                    ErrorKind::InvalidInput
-                );
-                let string = tokens.iter().map(|t| t.text()).collect::<String>();
-                let token = StringToken::from_value(&string, tokens[0].start_position());
-                expanded.push_back(token.into());
-            } else if let Some(token) = track!(reader.try_read_token())? {
-                if let Some(value) = token
-                    .as_variable_token()
-                    .and_then(|v| bindings.get(v.value()))
-                {
-                    let nested = track!(self.expand_replacement(HashMap::new(), value))?;
-                    expanded.extend(nested);
-                } else {
-                    expanded.push_back(token);
-                }
-            } else {
+            let macros = self.macro_table();
+            let end = match track!(self.process_form())? {
+                None => return Ok(None),
+                Some(end) => end,
+            };
+            self.take_form();
+
+            let call = self
+                .macro_calls
+                .range(..=position.clone())
+                .next_back()
+                .map(|(_, c)| c)
+                .filter(|c| {
+                    c.start_position().filepath() == position.filepath()
+                        && position.offset() < c.end_position().offset()
+                });
+            if let Some(call) = call {
+                let site = call.start_position();
+                let arity = call.args.as_ref().map(|a| a.len());
+                let definition = track!(expander::lookup(&macros, &call.name, arity, &site))?;
+                let mut acyclic = AcyclicMacros::new();
+                let mut expander = Expander::new(&macros, &mut acyclic, &mut self.usage);
+                let one_step = track!(expander.expand_call(call, false))?;
+                let full = track!(expander.expand_call(call, true))?;
+                return Ok(Some(MacroExpansion::new(
+                    call.clone(),
+                    definition.clone(),
+                    one_step,
+                    full,
+                )));
+            }
+            if end.filepath() == position.filepath() && position.offset() < end.offset() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Makes a new `Preprocessor` instance which starts from the given state.
+    pub(crate) fn with_state(tokens: T, state: &State) -> Self {
+        let mut pp = Preprocessor::new(tokens);
+        pp.restore_state(state);
+        pp.end_prefix();
+        pp
+    }
+
+    /// Returns the number of files being included.
+    pub(crate) fn include_depth(&self) -> usize {
+        self.reader.include_depth()
+    }
+
+    fn push_prefix(&mut self, form: PrefixForm) {
+        if let Some(ref mut prefix) = self.prefix {
+            prefix.push(form);
+        }
+    }
+    fn end_prefix(&mut self) {
+        self.prefix = None;
+        self.reader.stop_recording();
+    }
+    fn read_form(&mut self) -> Result<Vec<LexicalToken>> {
+        let mut form = Vec::new();
+        while let Some(token) = track!(self.reader.try_read_token())? {
+            let is_dot = token
+                .as_symbol_token()
+                .is_some_and(|s| s.value() == Symbol::Dot);
+            form.push(token);
+            if is_dot && track!(self.reader.is_form_end(&form[form.len() - 1]))? {
                 break;
             }
         }
-        Ok(expanded)
+        Ok(form)
+    }
+    #[allow(clippy::type_complexity)]
+    fn expand_form(
+        &mut self,
+        form: Vec<LexicalToken>,
+    ) -> Result<(Vec<LexicalToken>, Vec<(Range<usize>, Position)>)> {
+        let mut expander = Expander::new(&self.macros, &mut self.acyclic_macros, &mut self.usage);
+        let expanded = track!(expander.expand(form))?;
+        let (calls, sites) = expander.into_parts();
+        for call in calls {
+            self.macro_calls.insert(call.start_position(), call);
+        }
+        Ok((expanded, sites))
     }
     fn try_read_directive(&mut self) -> Result<Option<Directive>> {
-        let directive: Directive = if let Some(directive) = track!(self.reader.try_read())? {
+        let directive: Directive = if let Some(directive) = self.reader.try_read_cached_directive()
+        {
+            directive
+        } else if let Some(directive) = track!(self.reader.try_read())? {
             directive
         } else {
             return Ok(None);
@@ -198,19 +564,34 @@ where
         let ignore = self.ignore();
         match directive {
             Directive::Include(ref d) if !ignore => {
-                let (path, text) = track!(d.include())?;
-                self.reader.add_included_text(path, text);
+                let position = d.start_position();
+                track!(self
+                    .include_policy
+                    .check_variables(d.path.value(), &position))?;
+                let path = track!(d.resolve(&self.include_paths, &self.path_variables))?;
+                let path = track!(self.include_policy.check_path(path, &position))?;
+                track!(self.include(path))?;
             }
             Directive::IncludeLib(ref d) if !ignore => {
-                let (path, text) = track!(d.include_lib(&self.code_paths))?;
-                self.reader.add_included_text(path, text);
+                let position = d.start_position();
+                track!(self
+                    .include_policy
+                    .check_variables(d.path.value(), &position))?;
+                let path = track!(d.resolve(&self.code_paths, &self.path_variables))?;
+                let path = track!(self.include_policy.check_path(path, &position))?;
+                track!(self.include(path))?;
             }
             Directive::Define(ref d) if !ignore => {
-                self.macros
-                    .insert(d.name.value().to_string(), MacroDef::Static(d.clone()));
+                track!(self.define_macro(d))?;
             }
             Directive::Undef(ref d) if !ignore => {
-                self.macros.remove(d.name.value());
+                Arc::make_mut(&mut self.macros).remove(d.name.value());
+                self.record_event(MacroEvent::Undef {
+                    position: d.start_position(),
+                    name: d.name.value().to_string(),
+                });
+                self.acyclic_macros.clear();
+                self.generation += 1;
             }
             Directive::Ifdef(ref d) => {
                 let entered = self.macros.contains_key(d.name.value());
@@ -229,10 +610,218 @@ where
             }
             _ => {}
         }
+        if let Directive::Ifdef(_)
+        | Directive::Ifndef(_)
+        | Directive::Else(_)
+        | Directive::Endif(_) = directive
+        {
+            self.generation += 1;
+        }
         Ok(Some(directive))
     }
+    fn include(&mut self, path: PathBuf) -> Result<()> {
+        track!(self.usage.add_include(), "path={:?}", path)?;
+        self.included_files.push(path.clone());
+        self.generation += 1;
+        if let Some(ref cache) = self.header_cache {
+            let header = track!(
+                cache.load_with_budget(&path, self.usage.read_budget()),
+                "path={:?}",
+                path
+            )?;
+            self.reader.add_cached_header(header);
+        } else {
+            let file = track!(File::open(&path).map_err(Error::from), "path={:?}", path)?;
+            self.reader.add_included_file(path, file);
+        }
+        Ok(())
+    }
+    fn define_macro(&mut self, d: &Define) -> Result<()> {
+        let name = d.name.value();
+        let arity = d.variables.as_ref().map(|v| v.len());
+        let defs = Arc::make_mut(&mut self.macros)
+            .entry(name.to_string())
+            .or_default();
+        if let Some(i) = defs
+            .iter()
+            .position(|m| m.arity() == arity || matches!(m, MacroDef::Predefined(_)))
+        {
+            if !self.allow_macro_redefinition {
+                if let MacroDef::Predefined(_) = defs[i] {
+                    track_panic!(ErrorKind::PredefinedMacroRedefinition {
+                        name: name.to_string(),
+                        redefinition: d.start_position(),
+                    });
+                }
+                let original = match defs[i] {
+                    MacroDef::Static(ref o) => Some(o.start_position()),
+                    _ => None,
+                };
+                track_panic!(ErrorKind::MacroRedefinition {
+                    name: name.to_string(),
+                    original,
+                    redefinition: d.start_position(),
+                });
+            }
+            defs.remove(i);
+        }
+        let definition = MacroDef::Static(Arc::new(d.clone()));
+        defs.push(definition.clone());
+        self.record_event(MacroEvent::Define {
+            position: d.start_position(),
+            name: name.to_string(),
+            definition,
+        });
+        self.acyclic_macros.clear();
+        self.generation += 1;
+        Ok(())
+    }
+    fn record_event(&mut self, event: MacroEvent) {
+        if self.record_timeline {
+            self.timeline.push(TimelineEntry::Event(event));
+        }
+    }
+    fn handle_module_attribute(&mut self, form: &[LexicalToken]) {
+        let module = match snapshot::module_name(form) {
+            Some(m) => m.clone(),
+            None => return,
+        };
+
+        let is_first = !self.macros.contains_key("BASE_MODULE");
+        let position = form[0].start_position();
+        let mut define = |name: &str, m: PredefinedMacro| {
+            let definition = MacroDef::Predefined(m);
+            Arc::make_mut(&mut self.macros).insert(name.to_string(), vec![definition.clone()]);
+            self.record_event(MacroEvent::Define {
+                position: position.clone(),
+                name: name.to_string(),
+                definition,
+            });
+        };
+        if is_first {
+            define("BASE_MODULE", PredefinedMacro::BaseModule(module.clone()));
+            define(
+                "BASE_MODULE_STRING",
+                PredefinedMacro::BaseModuleString(module.clone()),
+            );
+        }
+        define("MODULE", PredefinedMacro::Module(module.clone()));
+        define("MODULE_STRING", PredefinedMacro::ModuleString(module));
+        self.acyclic_macros.clear();
+        self.generation += 1;
+    }
+}
+impl<R: Read> Preprocessor<ReaderLexer<R>, Error> {
+    /// Makes a new `Preprocessor` instance which reads the source code from `reader`.
+    ///
+    /// The input is tokenized incrementally (see `ReaderLexer`), and
+    /// the included files are also read in the same manner.
+    /// Thus, when the resulting tokens (or forms) are consumed one by one,
+    /// the memory usage of the preprocessor is proportional to the size of the largest form
+    /// rather than the size of the input.
+    /// Note, however, that the directives and the top level macro calls are still kept
+    /// (see `Preprocessor::directives` and `Preprocessor::macro_calls`).
+    ///
+    /// Unlike `Preprocessor::new`, the consumed forms are not recorded for `Preprocessor::snapshot`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// use erl_pp::Preprocessor;
+    /// use std::fs::File;
+    ///
+    /// # fn main() {
+    /// let file = File::open("tests/epp_suite/line.erl").unwrap();
+    /// let mut pp = Preprocessor::from_reader(file);
+    /// for form in pp.forms() {
+    ///     let form = form.unwrap();
+    ///     assert_eq!(form.tokens().last().map(|t| t.text()), Some("."));
+    /// }
+    /// # }
+    /// ```
+    pub fn from_reader(reader: R) -> Self {
+        let mut pp = Preprocessor::new(ReaderLexer::new(reader));
+        pp.end_prefix();
+        pp
+    }
 }
 impl<T, E> Preprocessor<T, E> {
+    /// Returns the current state.
+    ///
+    /// Note that the state is meaningful only at a boundary of top-level forms.
+    pub(crate) fn state(&self) -> State {
+        State {
+            macros: Arc::clone(&self.macros),
+            branches: self.branches.clone(),
+            code_paths: self.code_paths.clone(),
+            include_paths: self.include_paths.clone(),
+            included_files: self.included_files.clone(),
+        }
+    }
+
+    fn restore_state(&mut self, state: &State) {
+        self.macros = Arc::clone(&state.macros);
+        self.branches = state.branches.clone();
+        self.code_paths = state.code_paths.clone();
+        self.include_paths = state.include_paths.clone();
+        self.included_files = state.included_files.clone();
+        self.acyclic_macros.clear();
+        self.macros_reset = true;
+        self.generation += 1;
+    }
+
+    /// Returns the current macro table (shared with this preprocessor until it is modified).
+    pub(crate) fn macro_table(&self) -> Arc<HashMap<String, Vec<MacroDef>>> {
+        Arc::clone(&self.macros)
+    }
+
+    /// Returns a counter which is incremented every time the state is changed.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Takes the expanded tokens of the current form which have not been consumed yet.
+    pub(crate) fn take_form(&mut self) -> Option<Form> {
+        if self.expanded_tokens.is_empty() {
+            return None;
+        }
+        let (start, end) = self.form_span.clone().unwrap_or_else(|| {
+            // Falls back to the span of the expanded tokens
+            let first = &self.expanded_tokens[0];
+            let last = &self.expanded_tokens[self.expanded_tokens.len() - 1];
+            (first.start_position(), last.end_position())
+        });
+        let consumed = self.form_len - self.expanded_tokens.len();
+        let sites = self
+            .form_sites
+            .drain(..)
+            .filter(|(r, _)| r.end > consumed)
+            .map(|(r, site)| (r.start.saturating_sub(consumed)..r.end - consumed, site))
+            .collect();
+        let tokens = self.expanded_tokens.drain(..).collect();
+        Some(Form::new(tokens, start, end, sites))
+    }
+
+    /// Returns the directive which ends at `end` (i.e., the one just processed by `process_form`).
+    pub(crate) fn directive_ending_at(&self, end: &Position) -> Option<&Directive> {
+        self.directives
+            .range(..end.clone())
+            .next_back()
+            .map(|(_, d)| d)
+            .filter(|d| d.end_position() == *end)
+    }
+
+    /// Takes the directives encountered so far.
+    pub(crate) fn take_directives(&mut self) -> BTreeMap<Position, Directive> {
+        std::mem::take(&mut self.directives)
+    }
+
+    /// Takes the macro calls encountered so far.
+    pub(crate) fn take_macro_calls(&mut self) -> BTreeMap<Position, MacroCall> {
+        std::mem::take(&mut self.macro_calls)
+    }
+
     /// Returns a reference to the code path list which
     /// will be used by this preprocessor for handling `include_lib` directive.
     pub fn code_paths(&self) -> &VecDeque<PathBuf> {
@@ -242,9 +831,25 @@ impl<T, E> Preprocessor<T, E> {
     /// Returns a mutable reference to the code path list which
     /// will be used by this preprocessor for handling `include_lib` directive.
     pub fn code_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        self.generation += 1;
         &mut self.code_paths
     }
 
+    /// Returns a reference to the include path list which
+    /// will be used by this preprocessor for handling `include` directive.
+    ///
+    /// See `Include::resolve` for the order of the lookup.
+    pub fn include_paths(&self) -> &VecDeque<PathBuf> {
+        &self.include_paths
+    }
+
+    /// Returns a mutable reference to the include path list which
+    /// will be used by this preprocessor for handling `include` directive.
+    pub fn include_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        self.generation += 1;
+        &mut self.include_paths
+    }
+
     /// Returns a reference to the map containing the macro directives
     /// encountered by this preprocessor so far.
     ///
@@ -253,6 +858,19 @@ impl<T, E> Preprocessor<T, E> {
         &self.directives
     }
 
+    /// Returns the paths of the files included by this preprocessor so far.
+    pub fn included_files(&self) -> &[PathBuf] {
+        &self.included_files
+    }
+
+    /// Returns `true` if this preprocessor has been resumed from a snapshot,
+    /// otherwise `false`.
+    ///
+    /// See `Preprocessor::from_snapshot` for more details.
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
     /// Returns a reference to the map containing the macro calls
     /// encountered by this preprocessor so far.
     ///
@@ -265,13 +883,243 @@ impl<T, E> Preprocessor<T, E> {
     }
 
     /// Returns a reference to the map containing the current macro definitions.
-    pub fn macros(&self) -> &HashMap<String, MacroDef> {
+    ///
+    /// The values of this map are the definitions of the same name which have different arities
+    /// (macros can be overloaded by the number of variables).
+    ///
+    /// Predefined macros (e.g., `?LINE` and `?MODULE`) are also contained in this map
+    /// as `MacroDef::Predefined` entries.
+    pub fn macros(&self) -> &HashMap<String, Vec<MacroDef>> {
         &self.macros
     }
 
     /// Returns a mutable reference to the map containing the current macro definitions.
-    pub fn macros_mut(&mut self) -> &mut HashMap<String, MacroDef> {
-        &mut self.macros
+    pub fn macros_mut(&mut self) -> &mut HashMap<String, Vec<MacroDef>> {
+        self.acyclic_macros.clear();
+        self.macros_reset = true;
+        self.generation += 1;
+        Arc::make_mut(&mut self.macros)
+    }
+
+    /// Returns the changes of the macro definitions made by the directives
+    /// (including the ones in included files) processed so far, in the processed order.
+    ///
+    /// Note that the changes made via `Preprocessor::macros_mut` are not contained,
+    /// nor are the ones made while the recording is disabled
+    /// (see `Preprocessor::set_record_timeline`).
+    pub fn macro_events(&self) -> impl Iterator<Item = &MacroEvent> {
+        self.timeline.iter().filter_map(|e| match *e {
+            TimelineEntry::Event(ref e) => Some(e),
+            TimelineEntry::Reset(_) => None,
+        })
+    }
+
+    /// Returns the macro definitions in effect at the given position.
+    ///
+    /// The definitions made by the directives which precede the form containing `position`
+    /// (in the same file, or in the files included before the form) are visible.
+    ///
+    /// Returns `None` if `position` is not located in the forms processed so far
+    /// (e.g., it is in a file which has not been included yet,
+    /// or in the prefix skipped by `Preprocessor::from_snapshot`),
+    /// or if the recording of the changes was disabled when the form was processed
+    /// (see `Preprocessor::set_record_timeline`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::{Lexer, PositionRange};
+    ///
+    /// # fn main() {
+    /// let src = "-define(A, 1). foo() -> ?A. -undef(A). -define(B, 2). bar() -> ?B.";
+    /// let mut pp = Preprocessor::new(Lexer::new(src));
+    /// pp.set_record_timeline(true);
+    /// let positions = Lexer::new(src)
+    ///     .filter_map(|t| t.ok())
+    ///     .filter(|t| t.text() == "foo" || t.text() == "bar")
+    ///     .map(|t| t.start_position())
+    ///     .collect::<Vec<_>>();
+    /// pp.by_ref().count();
+    ///
+    /// let macros = pp.macros_at(&positions[0]).unwrap();
+    /// assert!(macros.contains_key("A") && !macros.contains_key("B"));
+    ///
+    /// let macros = pp.macros_at(&positions[1]).unwrap();
+    /// assert!(!macros.contains_key("A") && macros.contains_key("B"));
+    /// # }
+    /// ```
+    pub fn macros_at(&self, position: &Position) -> Option<HashMap<String, Vec<MacroDef>>> {
+        let (_, &end) = self
+            .timeline_checkpoints
+            .range(..=position.clone())
+            .next_back()
+            .filter(|(p, _)| p.filepath() == position.filepath())?;
+        let start = self.timeline[..end]
+            .iter()
+            .rposition(|e| matches!(*e, TimelineEntry::Reset(_)))?;
+        let mut macros = match self.timeline[start] {
+            TimelineEntry::Reset(ref macros) => HashMap::clone(macros),
+            TimelineEntry::Event(_) => unreachable!(),
+        };
+        for entry in &self.timeline[start + 1..end] {
+            if let TimelineEntry::Event(ref e) = *entry {
+                e.apply(&mut macros);
+            }
+        }
+        Some(macros)
+    }
+
+    /// Returns the completion candidates for a macro call `?PREFIX` at the given position.
+    ///
+    /// The candidates are the macros in effect at `position` (see `Preprocessor::macros_at`)
+    /// whose names start with `prefix`, including predefined macros (e.g., `?MODULE`).
+    /// An overloaded macro yields a candidate for each arity.
+    /// The result is sorted by the names and the arities.
+    ///
+    /// Returns `None` if `position` is not located in the forms processed so far
+    /// while the recording was enabled (see `Preprocessor::set_record_timeline`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate erl_pp;
+    /// # extern crate erl_tokenize;
+    /// use erl_pp::Preprocessor;
+    /// use erl_tokenize::{Lexer, PositionRange};
+    ///
+    /// # fn main() {
+    /// let src = "-define(MAX(A, B), max(A, B)). -define(MAX, 10). foo() -> ?MA.";
+    /// let mut pp = Preprocessor::new(Lexer::new(src));
+    /// pp.set_record_timeline(true);
+    /// pp.by_ref().count();
+    ///
+    /// let foo = Lexer::new(src).map(|t| t.unwrap()).find(|t| t.text() == "foo").unwrap();
+    /// let completions = pp.macro_completions(&foo.start_position(), "MA").unwrap();
+    /// assert_eq!(completions.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+    ///            ["?MACHINE", "?MAX", "?MAX(A, B)"]);
+    /// assert_eq!(completions[2].preview, "max(A, B)");
+    /// assert_eq!(completions[2].position.as_ref().map(|p| p.line()), Some(1));
+    /// # }
+    /// ```
+    pub fn macro_completions(
+        &self,
+        position: &Position,
+        prefix: &str,
+    ) -> Option<Vec<MacroCompletion>> {
+        let macros = self.macros_at(position)?;
+        let mut completions = macros
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .flat_map(|(name, defs)| {
+                defs.iter()
+                    .map(move |d| MacroCompletion::new(name, d, position))
+            })
+            .collect::<Vec<_>>();
+        completions.sort_by(|a, b| (&a.name, a.arity).cmp(&(&b.name, b.arity)));
+        Some(completions)
+    }
+
+    /// Returns `true` if this preprocessor allows to redefine macros, otherwise `false`.
+    ///
+    /// The default value is `false`.
+    pub fn allow_macro_redefinition(&self) -> bool {
+        self.allow_macro_redefinition
+    }
+
+    /// Sets whether this preprocessor allows to redefine macros.
+    ///
+    /// If `false` (the default), a `define` directive for a macro that
+    /// has already been defined with the same arity results in `ErrorKind::MacroRedefinition`,
+    /// and one for a predefined macro results in `ErrorKind::PredefinedMacroRedefinition`
+    /// (like `epp`).
+    ///
+    /// If `true`, the new definition silently replaces the old one.
+    pub fn set_allow_macro_redefinition(&mut self, allow: bool) {
+        self.allow_macro_redefinition = allow;
+    }
+
+    /// Returns `true` if this preprocessor records the changes of the macro definitions,
+    /// otherwise `false`.
+    ///
+    /// The default value is `false`.
+    pub fn record_timeline(&self) -> bool {
+        self.record_timeline
+    }
+
+    /// Sets whether this preprocessor records the changes of the macro definitions.
+    ///
+    /// The records are used by `Preprocessor::macro_events`, `Preprocessor::macros_at` and
+    /// `Preprocessor::macro_completions`, and grow with the number of the processed forms.
+    /// Only the forms processed while the recording is enabled are covered,
+    /// so this should usually be called before the first form is processed.
+    /// If `false`, the records made so far are discarded.
+    pub fn set_record_timeline(&mut self, record: bool) {
+        if record && !self.record_timeline {
+            self.macros_reset = true;
+        }
+        if !record {
+            self.timeline = Vec::new();
+            self.timeline_checkpoints = BTreeMap::new();
+        }
+        self.record_timeline = record;
+    }
+
+    /// Returns the header cache used by this preprocessor, if any.
+    pub fn header_cache(&self) -> Option<&HeaderCache> {
+        self.header_cache.as_ref()
+    }
+
+    /// Sets the header cache used by this preprocessor.
+    ///
+    /// Included files are loaded via the cache, so that
+    /// preprocessors sharing the same cache do not read and lex the same header repeatedly.
+    pub fn set_header_cache(&mut self, cache: HeaderCache) {
+        self.header_cache = Some(cache);
+    }
+
+    /// Returns the resource limits of this preprocessor.
+    pub fn limits(&self) -> &Limits {
+        self.usage.limits()
+    }
+
+    /// Sets the resource limits of this preprocessor.
+    ///
+    /// The resources consumed so far are reset by this call.
+    /// See `Limits` for more details.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.usage = Usage::new(limits);
+        self.reader.set_budget(self.usage.read_budget().clone());
+    }
+
+    /// Returns the policy which restricts the files included by this preprocessor.
+    pub fn include_policy(&self) -> &IncludePolicy {
+        &self.include_policy
+    }
+
+    /// Sets the policy which restricts the files included by this preprocessor.
+    ///
+    /// See `IncludePolicy` for more details.
+    pub fn set_include_policy(&mut self, policy: IncludePolicy) {
+        self.generation += 1;
+        self.include_policy = policy;
+    }
+
+    /// Returns a reference to the variables which will be used for
+    /// substituting `$VAR` in the paths of `include` and `include_lib` directives.
+    pub fn path_variables(&self) -> &PathVariables {
+        &self.path_variables
+    }
+
+    /// Returns a mutable reference to the variables which will be used for
+    /// substituting `$VAR` in the paths of `include` and `include_lib` directives.
+    ///
+    /// See `PathVariables` for more details.
+    pub fn path_variables_mut(&mut self) -> &mut PathVariables {
+        self.generation += 1;
+        &mut self.path_variables
     }
 }
 impl<T, E> Iterator for Preprocessor<T, E>
@@ -292,8 +1140,51 @@ where
     }
 }
 
-#[derive(Debug)]
-struct Branch {
+fn predefined_macros() -> HashMap<String, Vec<MacroDef>> {
+    let mut macros = HashMap::new();
+    for &(name, ref m) in &[
+        ("FILE", PredefinedMacro::File),
+        ("LINE", PredefinedMacro::Line),
+        ("MACHINE", PredefinedMacro::Machine),
+    ] {
+        macros.insert(name.to_string(), vec![MacroDef::Predefined(m.clone())]);
+    }
+    macros
+}
+
+/// An entry of the history of the macro table.
+#[derive(Debug, Clone)]
+enum TimelineEntry {
+    /// The whole table is replaced (e.g., via `Preprocessor::macros_mut`).
+    Reset(Arc<HashMap<String, Vec<MacroDef>>>),
+    Event(MacroEvent),
+}
+
+/// State of a preprocessor at a boundary of top-level forms.
+#[derive(Debug, Clone)]
+pub(crate) struct State {
+    pub macros: Arc<HashMap<String, Vec<MacroDef>>>,
+    pub branches: Vec<Branch>,
+    pub code_paths: VecDeque<PathBuf>,
+    pub include_paths: VecDeque<PathBuf>,
+    pub included_files: Vec<PathBuf>,
+}
+
+impl State {
+    /// Makes the state of a preprocessor which has not processed any forms.
+    pub fn new(code_paths: VecDeque<PathBuf>, include_paths: VecDeque<PathBuf>) -> Self {
+        State {
+            macros: Arc::new(predefined_macros()),
+            branches: Vec::new(),
+            code_paths,
+            include_paths,
+            included_files: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Branch {
     pub then_branch: bool,
     pub entered: bool,
 }
@@ -0,0 +1,318 @@
+use erl_tokenize::Lexer;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::batch::{BatchPreprocessor, PreprocessedFile};
+use crate::config::ErlOpts;
+use crate::util;
+use crate::{Error, MacroDef, Preprocessor, Result};
+
+/// Erlang project laid out in the manner of rebar3 or erlang.mk.
+///
+/// `Project::open` discovers the following directories under the project root:
+///
+/// - applications: the root itself (if it has `src/`), `apps/*` and `lib/*`,
+/// - modules: `*.erl` files under the `src/` directory of each application,
+/// - include paths: the `include/` and `src/` directories of each application,
+/// - code paths (for `include_lib`): `_build/<profile>/lib`, `deps`, `apps` and `lib`.
+///
+/// The `erl_opts` in `rebar.config` (of the root and of each application) are also read,
+/// and applied to the preprocessors (see `ErlOpts` for the recognized options).
+/// As in rebar3, the relative `{i, Dir}` paths in the root `rebar.config` are resolved
+/// against the directory of each application.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// use erl_pp::Project;
+///
+/// # fn main() {
+/// let project = Project::open("tests/project").unwrap();
+/// assert_eq!(project.applications().len(), 2);
+///
+/// for result in project.preprocess() {
+///     let file = result.unwrap();
+///     assert!(!file.forms().is_empty());
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Project {
+    root: PathBuf,
+    profile: String,
+    applications: Vec<Application>,
+    code_paths: VecDeque<PathBuf>,
+    batch: BatchPreprocessor,
+}
+impl Project {
+    /// Opens the project at `root` with the `default` profile.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        track!(Self::open_with_profile(root, "default"))
+    }
+
+    /// Opens the project at `root` with the given rebar3 profile.
+    ///
+    /// The profile determines the build directory (`_build/<profile>/lib`)
+    /// and the `erl_opts` of the profile in `rebar.config` are appended to the default ones.
+    pub fn open_with_profile<P: AsRef<Path>>(root: P, profile: &str) -> Result<Self> {
+        track!(Self::open_with_platform(root, profile, None))
+    }
+
+    /// Opens the project at `root` with the given rebar3 profile and platform string.
+    ///
+    /// The platform string (e.g., `"26.2-x86_64-pc-linux-gnu-64"`) is used for
+    /// `platform_define` options (see `ErlOpts` for details).
+    pub fn open_with_platform<P: AsRef<Path>>(
+        root: P,
+        profile: &str,
+        platform: Option<&str>,
+    ) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let mut app_dirs = Vec::new();
+        if root.join("src").is_dir() {
+            app_dirs.push(root.clone());
+        }
+        for parent in &["apps", "lib"] {
+            app_dirs.extend(track!(sub_dirs(&root.join(parent)))?);
+        }
+        let mut applications = Vec::new();
+        for dir in app_dirs.into_iter().filter(|d| d.join("src").is_dir()) {
+            // Like rebar3, the include paths in the root config are relative to each application
+            let mut opts = track!(read_erl_opts(&root, &dir, profile, platform))?;
+            if dir != root {
+                opts.extend(track!(read_erl_opts(&dir, &dir, profile, platform))?);
+            }
+            applications.push(track!(Application::new(dir, opts))?);
+        }
+
+        let code_paths = [
+            root.join("_build").join(profile).join("lib"),
+            root.join("deps"),
+            root.join("apps"),
+            root.join("lib"),
+        ]
+        .iter()
+        .filter(|p| p.is_dir())
+        .cloned()
+        .collect();
+        Ok(Project {
+            root,
+            profile: profile.to_string(),
+            applications,
+            code_paths,
+            batch: BatchPreprocessor::new(),
+        })
+    }
+
+    /// Returns the root directory of this project.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the name of the rebar3 profile.
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Returns the applications in this project.
+    pub fn applications(&self) -> &[Application] {
+        &self.applications
+    }
+
+    /// Returns an iterator over the paths of the modules in this project.
+    pub fn modules(&self) -> impl Iterator<Item = &Path> {
+        self.applications
+            .iter()
+            .flat_map(|a| a.modules.iter().map(|m| m.as_path()))
+    }
+
+    /// Returns the code paths used for handling `include_lib` directive.
+    pub fn code_paths(&self) -> &VecDeque<PathBuf> {
+        &self.code_paths
+    }
+
+    /// Returns a mutable reference to the code paths used for handling `include_lib` directive.
+    pub fn code_paths_mut(&mut self) -> &mut VecDeque<PathBuf> {
+        &mut self.code_paths
+    }
+
+    /// Returns a reference to the batch preprocessor used by `Project::preprocess`.
+    pub fn batch(&self) -> &BatchPreprocessor {
+        &self.batch
+    }
+
+    /// Returns a mutable reference to the batch preprocessor used by `Project::preprocess`.
+    ///
+    /// This is useful for configuring the number of the worker threads, the header cache,
+    /// the macros defined for all the modules, the resource limits, the include policy and
+    /// the path variables.
+    /// Note that the code paths and the include paths of the batch preprocessor
+    /// are overwritten by those of the project.
+    pub fn batch_mut(&mut self) -> &mut BatchPreprocessor {
+        &mut self.batch
+    }
+
+    /// Preprocesses all the modules in this project.
+    ///
+    /// The resulting vector contains the result for each module
+    /// in the same order as `Project::modules`.
+    pub fn preprocess(&self) -> Vec<Result<PreprocessedFile>> {
+        let mut results = Vec::new();
+        for app in &self.applications {
+            let mut batch = self.batch.clone();
+            *batch.code_paths_mut() = self.code_paths.clone();
+            *batch.include_paths_mut() = app.include_paths.clone();
+            batch.macros_mut().extend(app.macros.clone());
+            results.extend(batch.preprocess(&app.modules));
+        }
+        results
+    }
+
+    /// Makes a preprocessor for the given module, which is configured in the same way
+    /// as `Project::preprocess`.
+    pub fn preprocessor<P: AsRef<Path>>(&self, module: P) -> Result<Preprocessor<Lexer<String>>> {
+        let module = module.as_ref();
+        let text = track!(util::read_file(module))?;
+        let mut lexer = Lexer::new(text);
+        lexer.set_filepath(module);
+
+        let mut pp = Preprocessor::new(lexer);
+        self.batch.configure(&mut pp);
+        *pp.code_paths_mut() = self.code_paths.clone();
+        pp.macros_mut().extend(self.batch.macros().clone());
+        if let Some(app) = self
+            .applications
+            .iter()
+            .filter(|a| module.starts_with(&a.root))
+            .max_by_key(|a| a.root.components().count())
+        {
+            *pp.include_paths_mut() = app.include_paths.clone();
+            pp.macros_mut().extend(app.macros.clone());
+        }
+        pp.set_limits(self.batch.limits().clone());
+        Ok(pp)
+    }
+}
+
+/// Application in a `Project`.
+#[derive(Debug, Clone)]
+pub struct Application {
+    name: String,
+    root: PathBuf,
+    modules: Vec<PathBuf>,
+    include_paths: VecDeque<PathBuf>,
+    macros: HashMap<String, Vec<MacroDef>>,
+}
+impl Application {
+    fn new(root: PathBuf, opts: ErlOpts) -> Result<Self> {
+        let src = root.join("src");
+        let mut modules = Vec::new();
+        track!(find_files(&src, "erl", &mut modules))?;
+        modules.sort();
+
+        let mut app_srcs = Vec::new();
+        track!(find_files(&src, "src", &mut app_srcs))?;
+        let name = app_srcs
+            .iter()
+            .filter_map(|p| p.file_name()?.to_str()?.strip_suffix(".app.src"))
+            .next()
+            .or_else(|| root.file_name().and_then(|n| n.to_str()))
+            .unwrap_or_default()
+            .to_string();
+
+        let mut include_paths = VecDeque::new();
+        include_paths.push_back(root.join("include"));
+        include_paths.extend(opts.include_paths().iter().cloned());
+        include_paths.push_back(src);
+        let macros = opts
+            .macro_defs()
+            .map(|(k, v)| (k.to_string(), vec![v]))
+            .collect();
+        Ok(Application {
+            name,
+            root,
+            modules,
+            include_paths,
+            macros,
+        })
+    }
+
+    /// Returns the name of this application.
+    ///
+    /// The name is taken from the `src/*.app.src` file (or the directory name if there is none).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the root directory of this application.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the paths of the modules in this application.
+    pub fn modules(&self) -> &[PathBuf] {
+        &self.modules
+    }
+
+    /// Returns the include paths used for preprocessing the modules in this application.
+    pub fn include_paths(&self) -> &VecDeque<PathBuf> {
+        &self.include_paths
+    }
+
+    /// Returns the macros defined by the `erl_opts` of this application.
+    pub fn macros(&self) -> &HashMap<String, Vec<MacroDef>> {
+        &self.macros
+    }
+}
+
+/// Reads the `erl_opts` in the `rebar.config` in `dir`, resolving include paths against `base_dir`.
+fn read_erl_opts(
+    dir: &Path,
+    base_dir: &Path,
+    profile: &str,
+    platform: Option<&str>,
+) -> Result<ErlOpts> {
+    let config = dir.join("rebar.config");
+    if !config.is_file() {
+        return Ok(ErlOpts::new());
+    }
+    track!(ErlOpts::from_rebar_config_with_base_dir(
+        config,
+        base_dir,
+        Some(profile),
+        platform
+    ))
+}
+
+fn sub_dirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut dirs = Vec::new();
+    for entry in track!(fs::read_dir(dir).map_err(Error::from))? {
+        let path = track!(entry.map_err(Error::from))?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Recursively collects the files which have the given extension.
+fn find_files(dir: &Path, extension: &str, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in track!(fs::read_dir(dir).map_err(Error::from))? {
+        let path = track!(entry.map_err(Error::from))?.path();
+        if path.is_dir() {
+            track!(find_files(&path, extension, files))?;
+        } else if path.extension().is_some_and(|e| e == extension) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use erl_tokenize::{LexicalToken, Position, PositionRange, Token};
+use std::io::{ErrorKind as IoErrorKind, Read};
+use std::path::Path;
+use std::str;
+
+use crate::limits::ReadBudget;
+use crate::util;
+use crate::{Error, ErrorKind, Result};
+
+/// The number of bytes read from the underlying reader at a time.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// The number of bytes which must follow a token before the token is regarded as complete.
+///
+/// Some tokens need a few characters of lookahead to be determined
+/// (e.g., `1.` may be a part of the float `1.0e-3`).
+const LOOKAHEAD: usize = 16;
+
+/// Lexer which reads Erlang source code from a `std::io::Read` incrementally.
+///
+/// Unlike `erl_tokenize::Lexer`, the whole text is never loaded into memory at once.
+/// Only the text which has not been tokenized yet is buffered, so the memory usage is
+/// proportional to the size of the largest token (rather than the size of the input).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::ReaderLexer;
+/// use erl_tokenize::PositionRange;
+///
+/// # fn main() {
+/// let src = "foo(X) -> % comment\n  X + 1.0e-3.";
+/// let tokens = ReaderLexer::new(src.as_bytes()).collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+///            ["foo", "(", "X", ")", "->", "X", "+", "1.0e-3", "."]);
+/// assert_eq!(tokens[5].start_position().line(), 2);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ReaderLexer<R> {
+    reader: R,
+    buf: String,
+    start: usize,
+    undecoded: Vec<u8>,
+    next_pos: Position,
+    eos: bool,
+    failed: bool,
+    budget: Option<ReadBudget>,
+}
+impl<R: Read> ReaderLexer<R> {
+    /// Makes a new `ReaderLexer` instance which reads the source code from `reader`.
+    pub fn new(reader: R) -> Self {
+        ReaderLexer {
+            reader,
+            buf: String::new(),
+            start: 0,
+            undecoded: Vec::new(),
+            next_pos: Position::new(),
+            eos: false,
+            failed: false,
+            budget: None,
+        }
+    }
+
+    /// Sets the budget which is charged for the bytes read by this lexer.
+    pub(crate) fn set_budget(&mut self, budget: ReadBudget) {
+        self.budget = Some(budget);
+    }
+
+    /// Sets the file path of the tokens.
+    ///
+    /// Note that this must be called before the first token is read.
+    pub fn set_filepath<P: AsRef<Path>>(&mut self, filepath: P) {
+        debug_assert_eq!(self.next_pos.offset(), 0);
+        self.next_pos = util::start_position(Some(filepath.as_ref()));
+    }
+
+    /// Returns the cursor position from which this lexer will start to scan the next token.
+    pub fn next_position(&self) -> Position {
+        self.next_pos.clone()
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn remaining(&self) -> &str {
+        &self.buf[self.start..]
+    }
+
+    /// Reads the input until at least `size` bytes are buffered or the end of the input is reached.
+    fn fill(&mut self, size: usize) -> Result<()> {
+        if self.start >= CHUNK_SIZE && self.start * 2 >= self.buf.len() {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+
+        let mut chunk = [0; CHUNK_SIZE];
+        while !self.eos && self.remaining().len() < size {
+            let size = self
+                .budget
+                .as_ref()
+                .map_or(CHUNK_SIZE, |b| b.read_size(CHUNK_SIZE));
+            let n = match self.reader.read(&mut chunk[..size]) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == IoErrorKind::Interrupted => continue,
+                Err(e) => return Err(track!(Error::from(e))),
+            };
+            if let Some(ref budget) = self.budget {
+                track!(budget.charge(n as u64))?;
+            }
+            if n == 0 {
+                self.eos = true;
+                track_assert!(
+                    self.undecoded.is_empty(),
+                    ErrorKind::InvalidInput,
+                    "Incomplete UTF-8 sequence at the end of the input"
+                );
+                break;
+            }
+            self.undecoded.extend_from_slice(&chunk[..n]);
+
+            let valid = match str::from_utf8(&self.undecoded) {
+                Ok(s) => s.len(),
+                Err(e) => {
+                    track_assert!(
+                        e.error_len().is_none(),
+                        ErrorKind::InvalidInput,
+                        "Invalid UTF-8 sequence: offset={}",
+                        self.next_pos.offset() + self.remaining().len() + e.valid_up_to()
+                    );
+                    e.valid_up_to()
+                }
+            };
+            let text = str::from_utf8(&self.undecoded[..valid]).expect("Never fails");
+            self.buf.push_str(text);
+            self.undecoded.drain(..valid);
+        }
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        let mut size = CHUNK_SIZE;
+        loop {
+            track!(self.fill(size))?;
+            if self.remaining().is_empty() {
+                return Ok(None);
+            }
+            let result = Token::from_text(self.remaining(), self.next_pos.clone());
+            let complete = match result {
+                Ok(ref token) => token.text().len() + LOOKAHEAD <= self.remaining().len(),
+                Err(_) => false,
+            };
+            if complete || self.eos {
+                let token = track!(result.map_err(Error::from))?;
+                self.start += token.text().len();
+                self.next_pos = token.end_position();
+                return Ok(Some(token));
+            }
+
+            // The token may continue in the unread part of the input
+            size = self.remaining().len() * 2;
+        }
+    }
+}
+impl<R: Read> Iterator for ReaderLexer<R> {
+    type Item = Result<LexicalToken>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            match track!(self.next_token()) {
+                Err(e) => {
+                    // Stops the tokenization so that the error is reported only once
+                    self.failed = true;
+                    self.buf = String::new();
+                    self.start = 0;
+                    return Some(Err(e));
+                }
+                Ok(None) => return None,
+                Ok(Some(token)) => {
+                    if let Ok(token) = token.into_lexical_token() {
+                        return Some(Ok(token));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,185 @@
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{LexicalToken, Position, PositionRange};
+
+use crate::util;
+use crate::{Error, ErrorKind, Form, Preprocessor, Result};
+
+/// Record definition extracted from a preprocessed `-record(Name, {...}).` attribute.
+///
+/// Because the attribute is extracted after preprocessing,
+/// the macro calls in the definition have already been expanded.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate erl_pp;
+/// # extern crate erl_tokenize;
+/// use erl_pp::Preprocessor;
+/// use erl_tokenize::Lexer;
+///
+/// # fn main() {
+/// let src = r#"-define(DEFAULT, 10).
+/// -record(point, {x = ?DEFAULT :: integer(), y}).
+/// foo() -> ok."#;
+/// let mut pp = Preprocessor::new(Lexer::new(src));
+/// let records = pp.records().collect::<Result<Vec<_>, _>>().unwrap();
+///
+/// assert_eq!(records.len(), 1);
+/// assert_eq!(records[0].name, "point");
+/// assert_eq!(records[0].position.line(), 2);
+///
+/// let x = &records[0].fields[0];
+/// assert_eq!(x.name, "x");
+/// assert_eq!(x.default.as_ref().map(|d| d[0].text()), Some("10"));
+/// assert_eq!(x.field_type.as_ref().map(|t| t.len()), Some(3));
+/// assert!(records[0].fields[1].default.is_none());
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// The name of the record.
+    pub name: String,
+
+    /// The fields of the record.
+    pub fields: Vec<RecordField>,
+
+    /// The start position of the attribute in the source code (before expansion).
+    ///
+    /// If the attribute is in an included file, the position points to the file.
+    pub position: Position,
+}
+impl Record {
+    /// Extracts a record definition from the given form.
+    ///
+    /// Returns `None` if the form is not a `record` attribute.
+    pub fn from_form(form: &Form) -> Result<Option<Self>> {
+        let tokens = form.tokens();
+        let is_record = matches!(tokens, [hyphen, name, ..]
+            if util::symbol(hyphen) == Some(Symbol::Hyphen)
+                && name.as_atom_token().is_some_and(|a| a.value() == "record"));
+        if !is_record {
+            return Ok(None);
+        }
+
+        // -record ( Name , { Fields } ) .
+        let len = tokens.len();
+        track_assert!(
+            len >= 9,
+            ErrorKind::InvalidInput,
+            "Too short record: {:?}",
+            form
+        );
+        track!(expect(&tokens[2], Symbol::OpenParen))?;
+        let name = match tokens[3].as_atom_token() {
+            Some(name) => name.value().to_string(),
+            None => track_panic!(ErrorKind::UnexpectedToken(tokens[3].clone())),
+        };
+        track!(expect(&tokens[4], Symbol::Comma))?;
+        track!(expect(&tokens[5], Symbol::OpenBrace))?;
+        track!(expect(&tokens[len - 3], Symbol::CloseBrace))?;
+        track!(expect(&tokens[len - 2], Symbol::CloseParen))?;
+
+        let mut fields = Vec::new();
+        let body = &tokens[6..len - 3];
+        if !body.is_empty() {
+            for field in util::split_top_level(body, Symbol::Comma) {
+                fields.push(track!(RecordField::from_tokens(field))?);
+            }
+        }
+        Ok(Some(Record {
+            name,
+            fields,
+            position: form.start_position(),
+        }))
+    }
+}
+
+/// Field of a record definition.
+#[derive(Debug, Clone)]
+pub struct RecordField {
+    /// The name of the field.
+    pub name: String,
+
+    /// The tokens of the default value (`name = Default`).
+    pub default: Option<Vec<LexicalToken>>,
+
+    /// The tokens of the type annotation (`name :: Type`).
+    pub field_type: Option<Vec<LexicalToken>>,
+
+    /// The position of the name of the field.
+    pub position: Position,
+}
+impl RecordField {
+    fn from_tokens(tokens: &[LexicalToken]) -> Result<Self> {
+        let (name_token, rest) = track_assert_some!(tokens.split_first(), ErrorKind::InvalidInput);
+        let name = match name_token.as_atom_token() {
+            Some(name) => name.value().to_string(),
+            None => track_panic!(ErrorKind::UnexpectedToken(name_token.clone())),
+        };
+
+        // name [= Default] [:: Type]
+        let mut parts = util::split_top_level(rest, Symbol::DoubleColon);
+        let head = parts.next().unwrap_or(&[]);
+        let field_type = parts.next().map(|t| t.to_vec());
+        if let Some(t) = parts.next().and_then(|t| t.first()) {
+            track_panic!(ErrorKind::UnexpectedToken(t.clone()));
+        }
+        let default = match head.split_first() {
+            None => None,
+            Some((t, default)) if util::symbol(t) == Some(Symbol::Match) && !default.is_empty() => {
+                Some(default.to_vec())
+            }
+            Some((t, _)) => track_panic!(ErrorKind::UnexpectedToken(t.clone())),
+        };
+        if let Some(ref t) = field_type {
+            track_assert!(!t.is_empty(), ErrorKind::InvalidInput, "Empty field type");
+        }
+        Ok(RecordField {
+            name,
+            default,
+            field_type,
+            position: name_token.start_position(),
+        })
+    }
+}
+
+/// An iterator over the record definitions in the preprocessed forms.
+///
+/// This is created by `Preprocessor::records`.
+#[derive(Debug)]
+pub struct Records<'a, T: 'a, E: 'a> {
+    pp: &'a mut Preprocessor<T, E>,
+}
+impl<'a, T: 'a, E: 'a> Records<'a, T, E> {
+    pub(crate) fn new(pp: &'a mut Preprocessor<T, E>) -> Self {
+        Records { pp }
+    }
+}
+impl<'a, T: 'a, E: 'a> Iterator for Records<'a, T, E>
+where
+    T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
+    E: Into<Error>,
+{
+    type Item = Result<Record>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let form = match track!(self.pp.next_form()) {
+                Err(e) => return Some(Err(e)),
+                Ok(None) => return None,
+                Ok(Some(form)) => form,
+            };
+            match track!(Record::from_form(&form)) {
+                Err(e) => return Some(Err(e)),
+                Ok(None) => {}
+                Ok(Some(record)) => return Some(Ok(record)),
+            }
+        }
+    }
+}
+
+fn expect(token: &LexicalToken, expected: Symbol) -> Result<()> {
+    if util::symbol(token) != Some(expected) {
+        track_panic!(ErrorKind::UnexpectedToken(token.clone()));
+    }
+    Ok(())
+}
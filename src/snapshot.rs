@@ -0,0 +1,129 @@
+use erl_tokenize::tokens::AtomToken;
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{LexicalToken, Position};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::preprocessor::State;
+use crate::{Directive, Error, MacroDef, Result};
+
+/// Snapshot of the state of a `Preprocessor` taken after a prefix of a file.
+///
+/// This works like precompiled headers of C compilers:
+/// a snapshot is taken by `Preprocessor::snapshot` after the leading directives of a file
+/// have been processed, and `Preprocessor::from_snapshot` starts preprocessing another file
+/// from the snapshot if the file begins with the same prefix.
+///
+/// The prefix may consist of directives, forms skipped by conditional directives and
+/// a `-module` attribute (which matches any module name).
+/// The forms in the files included by the prefix (e.g., record definitions) are
+/// expanded again when a preprocessor is resumed from the snapshot,
+/// using the macro definitions at the end of the prefix.
+///
+/// The include directives in the prefix match only if they resolve to the same files
+/// (e.g., a `-include("x.hrl").` in a file in another directory may include another file),
+/// and the snapshot is not used at all if the contents of the included files have changed
+/// since it was taken.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub(crate) prefix: Vec<PrefixForm>,
+    pub(crate) state: State,
+    pub(crate) included_directives: BTreeMap<Position, Directive>,
+    pub(crate) fingerprints: Vec<(PathBuf, u64)>,
+}
+impl Snapshot {
+    /// Returns the number of the top-level forms in the prefix covered by this snapshot.
+    pub fn prefix_len(&self) -> usize {
+        self.prefix.iter().filter(|f| f.is_toplevel()).count()
+    }
+
+    /// Returns the macro definitions at the end of the prefix.
+    ///
+    /// Module specific predefined macros (e.g., `?MODULE`) are excluded.
+    pub fn macros(&self) -> &HashMap<String, Vec<MacroDef>> {
+        &self.state.macros
+    }
+
+    /// Returns the code paths at the end of the prefix.
+    pub fn code_paths(&self) -> &VecDeque<PathBuf> {
+        &self.state.code_paths
+    }
+
+    /// Returns the include paths at the end of the prefix.
+    pub fn include_paths(&self) -> &VecDeque<PathBuf> {
+        &self.state.include_paths
+    }
+
+    /// Returns the files included in the prefix.
+    pub fn included_files(&self) -> &[PathBuf] {
+        &self.state.included_files
+    }
+
+    /// Returns `true` if the files included in the prefix have not been changed.
+    pub(crate) fn is_up_to_date(&self) -> bool {
+        self.fingerprints
+            .iter()
+            .all(|(path, f)| fingerprint(path).ok() == Some(*f))
+    }
+}
+
+/// A top-level form in the prefix of a file.
+#[derive(Debug, Clone)]
+pub(crate) enum PrefixForm {
+    /// A directive and the file included by it (if any).
+    Directive(Vec<String>, Option<PathBuf>),
+    Ignored(Vec<String>),
+    Module,
+    Included(Vec<LexicalToken>),
+}
+impl PrefixForm {
+    /// Returns `true` if this form corresponds to a form in the file (not in included files).
+    pub fn is_toplevel(&self) -> bool {
+        !matches!(*self, PrefixForm::Included(_))
+    }
+
+    /// Returns the file included by this form (if any).
+    pub fn included_file(&self) -> Option<&Path> {
+        match *self {
+            PrefixForm::Directive(_, ref included) => included.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the texts of the tokens of `form` match this form.
+    pub fn matches(&self, form: &[LexicalToken]) -> bool {
+        match *self {
+            PrefixForm::Directive(ref texts, _) | PrefixForm::Ignored(ref texts) => {
+                texts.len() == form.len() && texts.iter().zip(form).all(|(a, b)| a == b.text())
+            }
+            PrefixForm::Module => module_name(form).is_some(),
+            PrefixForm::Included(_) => false,
+        }
+    }
+}
+
+/// Returns the module name if the given form is a `-module` attribute.
+pub(crate) fn module_name(form: &[LexicalToken]) -> Option<&AtomToken> {
+    match form {
+        [LexicalToken::Symbol(h), LexicalToken::Atom(a), LexicalToken::Symbol(o), LexicalToken::Atom(m), LexicalToken::Symbol(c), ..]
+            if h.value() == Symbol::Hyphen
+                && a.value() == "module"
+                && o.value() == Symbol::OpenParen
+                && c.value() == Symbol::CloseParen =>
+        {
+            Some(m)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the hash value of the contents of the given file.
+pub(crate) fn fingerprint(path: &Path) -> Result<u64> {
+    let bytes = track!(fs::read(path).map_err(Error::from))?;
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    Ok(hasher.finish())
+}
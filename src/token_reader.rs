@@ -1,21 +1,39 @@
 use erl_tokenize::tokens::{AtomToken, StringToken, SymbolToken, VariableToken};
 use erl_tokenize::values::Symbol;
-use erl_tokenize::{Lexer, LexicalToken};
-use std::collections::{HashMap, VecDeque};
+use erl_tokenize::{LexicalToken, PositionRange};
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::fs::File;
 use std::marker::PhantomData;
 use std::path::Path;
+use std::sync::Arc;
 
-use crate::macros::NoArgsMacroCall;
-use crate::{Error, ErrorKind, MacroCall, MacroDef, Result};
+use crate::header_cache::CachedHeader;
+use crate::limits::ReadBudget;
+use crate::reader_lexer::ReaderLexer;
+use crate::{Directive, Error, ErrorKind, Result};
+
+/// The number of tokens read between checks of the deadline.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
 
 #[derive(Debug)]
 pub struct TokenReader<T, E> {
     tokens: T,
-    included_tokens: Vec<Lexer<String>>,
+    budget: ReadBudget,
+    read_count: usize,
+    included_tokens: Vec<Included>,
     unread: VecDeque<LexicalToken>,
+    replayed: VecDeque<LexicalToken>,
+    recorded: Option<Vec<String>>,
     _phantom: PhantomData<E>,
 }
+impl<T, E> TokenReader<T, E> {
+    /// Sets the budget which is charged for the bytes of included files and whose deadline
+    /// is checked while reading tokens.
+    pub fn set_budget(&mut self, budget: ReadBudget) {
+        self.budget = budget;
+    }
+}
 impl<T, E> TokenReader<T, E>
 where
     T: Iterator<Item = ::std::result::Result<LexicalToken, E>>,
@@ -24,16 +42,89 @@ where
     pub fn new(tokens: T) -> Self {
         TokenReader {
             tokens,
+            budget: ReadBudget::default(),
+            read_count: 0,
             included_tokens: Vec::new(),
             unread: VecDeque::new(),
+            replayed: VecDeque::new(),
+            recorded: None,
             _phantom: PhantomData,
         }
     }
 
-    pub fn add_included_text<P: AsRef<Path>>(&mut self, path: P, text: String) {
-        let mut lexer = Lexer::new(text);
+    pub fn add_included_file<P: AsRef<Path>>(&mut self, path: P, file: File) {
+        let mut lexer = ReaderLexer::new(file);
         lexer.set_filepath(path);
-        self.included_tokens.push(lexer);
+        lexer.set_budget(self.budget.clone());
+        self.included_tokens.push(Included::Text(lexer));
+    }
+
+    pub fn add_cached_header(&mut self, header: Arc<CachedHeader>) {
+        self.included_tokens
+            .push(Included::Cached { header, next: 0 });
+    }
+
+    /// Returns the number of files being included.
+    pub fn include_depth(&self) -> usize {
+        self.included_tokens.len()
+    }
+
+    /// Starts recording the texts of the tokens read from the top-level token stream.
+    pub fn start_recording(&mut self) {
+        self.recorded = Some(Vec::new());
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorded = None;
+    }
+
+    /// Returns the texts of the tokens recorded so far.
+    ///
+    /// Tokens which have been unread are excluded.
+    /// Note that this is only meaningful when no files are being included.
+    pub fn recorded(&self) -> &[String] {
+        let recorded = self.recorded.as_ref().map_or(&[][..], |r| &r[..]);
+        let pending = self.unread.len() + self.replayed.len();
+        &recorded[..recorded.len().saturating_sub(pending)]
+    }
+
+    /// Pushes back the given tokens, which have been read from the top-level token stream,
+    /// so that they are read again.
+    ///
+    /// Unlike `unread_token()`, the tokens of files included by the replayed tokens are read
+    /// before the rest of the replayed tokens.
+    /// Note that this must not be called while files are being included.
+    pub fn replay<I>(&mut self, tokens: I)
+    where
+        I: IntoIterator<Item = LexicalToken>,
+    {
+        let mut replayed = tokens.into_iter().collect::<VecDeque<_>>();
+        replayed.extend(self.unread.drain(..));
+        replayed.extend(self.replayed.drain(..));
+        self.replayed = replayed;
+    }
+
+    /// Returns the pre-parsed directive which starts at the current position, if any.
+    pub fn try_read_cached_directive(&mut self) -> Option<Directive> {
+        if let Some(Included::Cached { header, next }) = self.included_tokens.last_mut() {
+            // The first token of a form may have been unread by `is_form_end()`
+            let start = match self.unread.len() {
+                0 => *next,
+                1 if *next > 0
+                    && self.unread[0].start_position()
+                        == header.tokens()[*next - 1].start_position() =>
+                {
+                    *next - 1
+                }
+                _ => return None,
+            };
+            if let Some((directive, end)) = header.directive_at(start) {
+                self.unread.clear();
+                *next = end;
+                return Some(directive.clone());
+            }
+        }
+        None
     }
 
     pub fn read<V>(&mut self) -> Result<V>
@@ -48,27 +139,6 @@ where
     {
         track!(V::try_read_from(self))
     }
-    pub fn try_read_macro_call(
-        &mut self,
-        macros: &HashMap<String, MacroDef>,
-    ) -> Result<Option<MacroCall>> {
-        if let Some(call) = track!(self.try_read::<NoArgsMacroCall>())? {
-            let mut call = MacroCall {
-                _question: call._question,
-                name: call.name,
-                args: None,
-            };
-            if macros
-                .get(call.name.value())
-                .map_or(false, |m| m.has_variables())
-            {
-                call.args = Some(track!(self.read())?);
-            }
-            Ok(Some(call))
-        } else {
-            Ok(None)
-        }
-    }
     pub fn read_expected<V>(&mut self, expected: &V::Value) -> Result<V>
     where
         V: ReadFrom + Expect + Into<LexicalToken>,
@@ -82,22 +152,41 @@ where
         track!(V::try_read_expected(self, expected))
     }
     pub fn try_read_token(&mut self) -> Result<Option<LexicalToken>> {
+        self.read_count += 1;
+        if self.read_count.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+            track!(self.budget.check_deadline())?;
+        }
         if let Some(token) = self.unread.pop_front() {
             Ok(Some(token))
         } else if !self.included_tokens.is_empty() {
-            match self.included_tokens.last_mut().expect("Never fails").next() {
+            let next = match self.included_tokens.last_mut().expect("Never fails") {
+                Included::Text(lexer) => lexer.next(),
+                Included::Cached { header, next } => {
+                    let token = header.tokens().get(*next).cloned();
+                    *next += 1;
+                    token.map(Ok)
+                }
+            };
+            match next {
                 None => {
                     self.included_tokens.pop();
                     self.try_read_token()
                 }
-                Some(Err(e)) => Err(e.into()),
+                Some(Err(e)) => Err(e),
                 Some(Ok(t)) => Ok(Some(t)),
             }
+        } else if let Some(token) = self.replayed.pop_front() {
+            Ok(Some(token))
         } else {
             match self.tokens.next() {
                 None => Ok(None),
                 Some(Err(e)) => Err(e.into()),
-                Some(Ok(t)) => Ok(Some(t)),
+                Some(Ok(t)) => {
+                    if let Some(ref mut recorded) = self.recorded {
+                        recorded.push(t.text().to_string());
+                    }
+                    Ok(Some(t))
+                }
             }
         }
     }
@@ -111,6 +200,30 @@ where
     pub fn unread_token(&mut self, token: LexicalToken) {
         self.unread.push_front(token);
     }
+
+    /// Returns `true` if the given dot token terminates a form.
+    ///
+    /// A dot which is immediately followed by an atom (e.g., `R#rec.field`) is
+    /// not regarded as a terminator.
+    pub fn is_form_end(&mut self, dot: &LexicalToken) -> Result<bool> {
+        if let Some(token) = track!(self.try_read_token())? {
+            let is_end =
+                token.as_atom_token().is_none() || token.start_position() != dot.end_position();
+            self.unread_token(token);
+            Ok(is_end)
+        } else {
+            Ok(true)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Included {
+    Text(ReaderLexer<File>),
+    Cached {
+        header: Arc<CachedHeader>,
+        next: usize,
+    },
 }
 
 pub trait ReadFrom: Sized {
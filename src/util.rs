@@ -1,28 +1,208 @@
-use std::env;
+use erl_tokenize::values::{Keyword, Symbol};
+use erl_tokenize::{Lexer, LexicalToken, Position, PositionRange, Token};
 use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use std::path::Path;
 
+use crate::limits::ReadBudget;
 use crate::{Error, Result};
 
-pub fn substitute_path_variables<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
-    let mut new = PathBuf::new();
-    for c in path.as_ref().components() {
-        if let Some(s) = c.as_os_str().to_str() {
-            if s.as_bytes().get(0) == Some(&b'$') {
-                let c = track!(env::var(s.split_at(1).1).map_err(Error::from))?;
-                new.push(c);
-                continue;
-            }
-        }
-        new.push(c.as_os_str());
-    }
-    Ok(new)
-}
-
 pub fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
     let mut buf = String::new();
     let mut file = track!(File::open(&path).map_err(Error::from))?;
     track!(file.read_to_string(&mut buf).map_err(Error::from))?;
     Ok(buf)
 }
+
+/// Reads the content of the given file while charging `budget` for the bytes read.
+///
+/// The reading stops as soon as the budget is exceeded.
+pub fn read_file_with_budget<P: AsRef<Path>>(path: P, budget: &ReadBudget) -> Result<String> {
+    let mut file = track!(File::open(&path).map_err(Error::from))?;
+    let mut bytes = Vec::new();
+    let mut chunk = [0; 8 * 1024];
+    loop {
+        let size = budget.read_size(chunk.len());
+        let n = match file.read(&mut chunk[..size]) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(track!(Error::from(e))),
+        };
+        if n == 0 {
+            break;
+        }
+        track!(budget.charge(n as u64))?;
+        bytes.extend_from_slice(&chunk[..n]);
+    }
+    let text = String::from_utf8(bytes)
+        .map_err(|e| Error::from(io::Error::new(io::ErrorKind::InvalidData, e)));
+    track!(text)
+}
+
+/// Lexer which starts tokenization from an arbitrary position of a text.
+///
+/// `start.offset()` must be a character boundary of `text`.
+#[derive(Debug)]
+pub struct ResumedLexer<'a> {
+    text: &'a str,
+    next_pos: Position,
+}
+impl<'a> ResumedLexer<'a> {
+    pub fn new(text: &'a str, start: Position) -> Self {
+        ResumedLexer {
+            text,
+            next_pos: start,
+        }
+    }
+}
+impl Iterator for ResumedLexer<'_> {
+    type Item = erl_tokenize::Result<LexicalToken>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next_pos.offset() < self.text.len() {
+            let text = &self.text[self.next_pos.offset()..];
+            match Token::from_text(text, self.next_pos.clone()) {
+                Err(e) => {
+                    // Skips the remaining text so that the error is reported only once
+                    self.next_pos = Position::new();
+                    self.text = "";
+                    return Some(Err(e));
+                }
+                Ok(token) => {
+                    self.next_pos = token.end_position();
+                    if let Ok(token) = token.into_lexical_token() {
+                        return Some(Ok(token));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns the position of the beginning of a file.
+pub fn start_position(filepath: Option<&Path>) -> Position {
+    let mut lexer = Lexer::new("");
+    if let Some(filepath) = filepath {
+        lexer.set_filepath(filepath);
+    }
+    lexer.next_position()
+}
+
+/// Formats the given tokens as Erlang source code text.
+///
+/// The tokens are separated by a space unless the space would hurt readability
+/// (e.g., after `(` or before `,`).
+pub fn format_tokens(tokens: &[LexicalToken]) -> String {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && needs_space(&tokens[i - 1], token) {
+            text.push(' ');
+        }
+        text.push_str(token.text());
+    }
+    text
+}
+
+fn needs_space(prev: &LexicalToken, next: &LexicalToken) -> bool {
+    match symbol(prev) {
+        Some(Symbol::OpenParen)
+        | Some(Symbol::OpenSquare)
+        | Some(Symbol::OpenBrace)
+        | Some(Symbol::DoubleLeftAngle)
+        | Some(Symbol::Sharp)
+        | Some(Symbol::Question)
+        | Some(Symbol::DoubleQuestion)
+        | Some(Symbol::Colon) => return false,
+        _ => {}
+    }
+    match symbol(next) {
+        Some(Symbol::CloseParen)
+        | Some(Symbol::CloseSquare)
+        | Some(Symbol::CloseBrace)
+        | Some(Symbol::DoubleRightAngle)
+        | Some(Symbol::Comma)
+        | Some(Symbol::Dot)
+        | Some(Symbol::Semicolon)
+        | Some(Symbol::Colon) => false,
+        Some(Symbol::OpenParen) => match *prev {
+            // Function calls (e.g., `foo(`, `F(`, `fun(` and `(X)(`)
+            LexicalToken::Atom(_) | LexicalToken::Variable(_) => false,
+            LexicalToken::Keyword(ref k) => k.value() != Keyword::Fun,
+            LexicalToken::Symbol(ref s) => s.value() != Symbol::CloseParen,
+            _ => true,
+        },
+        // Records (e.g., `#foo{`)
+        Some(Symbol::OpenBrace) => prev.as_atom_token().is_none(),
+        _ => true,
+    }
+}
+
+/// Splits the given tokens by the separator which is not enclosed in brackets or blocks.
+pub fn split_top_level(
+    tokens: &[LexicalToken],
+    separator: Symbol,
+) -> impl Iterator<Item = &[LexicalToken]> {
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut parts = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            LexicalToken::Symbol(ref s) => match s.value() {
+                v if v == separator && depth == 0 => {
+                    parts.push(&tokens[start..i]);
+                    start = i + 1;
+                }
+                Symbol::OpenParen
+                | Symbol::OpenSquare
+                | Symbol::OpenBrace
+                | Symbol::DoubleLeftAngle => depth += 1,
+                Symbol::CloseParen
+                | Symbol::CloseSquare
+                | Symbol::CloseBrace
+                | Symbol::DoubleRightAngle => depth = depth.saturating_sub(1),
+                _ => {}
+            },
+            LexicalToken::Keyword(ref k) => match k.value() {
+                Keyword::Begin
+                | Keyword::If
+                | Keyword::Case
+                | Keyword::Receive
+                | Keyword::Try
+                | Keyword::Cond => depth += 1,
+                Keyword::Fun if is_fun_expr(&tokens[i + 1..]) => depth += 1,
+                Keyword::End => depth = depth.saturating_sub(1),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts.into_iter()
+}
+
+/// Returns `true` if the tokens following `fun` form a fun expression closed by `end`.
+///
+/// `fun M:F/A`, `fun F/A` and fun types (e.g., `fun()`, `fun((A) -> B)` and `fun((...) -> B)`)
+/// are not closed by `end`.
+fn is_fun_expr(rest: &[LexicalToken]) -> bool {
+    let rest = match rest.first() {
+        Some(LexicalToken::Variable(_)) => &rest[1..], // Named fun: `fun Name(...) -> ... end`
+        _ => rest,
+    };
+    if rest.first().and_then(symbol) != Some(Symbol::OpenParen) {
+        return false;
+    }
+    match rest.get(1).and_then(symbol) {
+        Some(Symbol::OpenParen) | Some(Symbol::TripleDot) => false,
+        Some(Symbol::CloseParen) => {
+            rest.get(2).and_then(symbol) == Some(Symbol::RightArrow)
+                || rest.get(2).and_then(|t| t.as_keyword_token()).is_some()
+        }
+        _ => true,
+    }
+}
+
+/// Returns the value of `token` if it is a symbol.
+pub fn symbol(token: &LexicalToken) -> Option<Symbol> {
+    token.as_symbol_token().map(|s| s.value())
+}
@@ -0,0 +1,156 @@
+//! Conformance tests against the output of Erlang's `epp` module.
+//!
+//! Each `tests/conformance/NAME.erl` is preprocessed and the resulting tokens are compared
+//! with `tests/conformance/NAME.epp`, which holds the forms returned by `epp:scan_file/2`
+//! (one form per line, `-file` attributes and the `eof` marker being excluded).
+//! The golden files can be regenerated by running `tests/conformance/generate.escript`
+//! from the top directory of this crate.
+//!
+//! Note that only the categories and values of the tokens are compared.
+//! `epp` relocates the tokens of a macro body to the call site,
+//! whereas this crate keeps their original positions.
+extern crate erl_pp;
+extern crate erl_tokenize;
+#[macro_use]
+extern crate trackable;
+
+use erl_pp::{HeaderCache, Preprocessor};
+use erl_tokenize::values::Symbol;
+use erl_tokenize::{Lexer, LexicalToken, PositionRange};
+use std::fs;
+
+const DIR: &str = "tests/conformance";
+
+/// A token normalized to the form `CATEGORY` or `CATEGORY VALUE`, paired with its line.
+#[derive(Debug)]
+struct Token {
+    line: usize,
+    text: String,
+}
+
+fn preprocess(name: &str, cache: Option<&HeaderCache>) -> Vec<Token> {
+    let path = format!("{}/{}.erl", DIR, name);
+    let src = fs::read_to_string(&path).unwrap();
+    let mut lexer = Lexer::new(&src);
+    lexer.set_filepath(&path);
+
+    let mut pp = Preprocessor::new(lexer);
+    pp.code_paths_mut().push_back(format!("{}/lib", DIR).into());
+    if let Some(cache) = cache {
+        pp.set_header_cache(cache.clone());
+    }
+    let tokens = track_try_unwrap!(pp.collect::<erl_pp::Result<Vec<_>>>());
+    tokens
+        .iter()
+        .map(|t| Token {
+            line: t.start_position().line(),
+            text: normalize(t),
+        })
+        .collect()
+}
+
+fn normalize(token: &LexicalToken) -> String {
+    match *token {
+        LexicalToken::Atom(ref t) => format!("atom {}", t.value()),
+        LexicalToken::Char(ref t) => format!("char {}", t.value() as u32),
+        LexicalToken::Float(ref t) => format!("float {:?}", t.value()),
+        LexicalToken::Integer(ref t) => format!("integer {}", t.value()),
+        LexicalToken::Keyword(ref t) => t.text().to_string(),
+        LexicalToken::String(ref t) => format!("string {:?}", t.value()),
+        LexicalToken::Symbol(ref t) if t.value() == Symbol::Dot => "dot".to_string(),
+        LexicalToken::Symbol(ref t) => t.text().to_string(),
+        LexicalToken::Variable(ref t) => format!("var {}", t.value()),
+    }
+}
+
+/// Parses a golden file, which is a sequence of Erlang terms like `[{atom,1,foo},{dot,1}].`.
+fn golden(name: &str) -> Vec<Token> {
+    let path = format!("{}/{}.epp", DIR, name);
+    let src = fs::read_to_string(&path).unwrap();
+    let terms = Lexer::new(&src)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| panic!("{}: {}", path, e));
+
+    let mut tokens = Vec::new();
+    let mut terms = terms.iter();
+    while let Some(t) = terms.next() {
+        if t.as_symbol_token().map(|t| t.value()) != Some(Symbol::OpenBrace) {
+            continue;
+        }
+        let tuple = terms
+            .by_ref()
+            .take_while(|t| t.as_symbol_token().map(|t| t.value()) != Some(Symbol::CloseBrace))
+            .filter(|t| t.as_symbol_token().map(|t| t.value()) != Some(Symbol::Comma))
+            .collect::<Vec<_>>();
+        let category = tuple[0]
+            .as_atom_token()
+            .map(|a| a.value().to_string())
+            .unwrap_or_else(|| tuple[0].text().to_string());
+        let line = tuple[1].text().parse().unwrap();
+        let text = match (category.as_str(), tuple.get(2)) {
+            (_, None) => category,
+            ("atom", Some(v)) | ("var", Some(v)) => {
+                format!("{} {}", category, v.as_atom_token().unwrap().value())
+            }
+            ("string", Some(v)) => format!("string {:?}", v.as_string_token().unwrap().value()),
+            ("float", Some(LexicalToken::Float(v))) => format!("float {:?}", v.value()),
+            (_, Some(v)) => format!("{} {}", category, v.text()),
+        };
+        tokens.push(Token { line, text });
+    }
+    tokens
+}
+
+fn check(name: &str) {
+    let expected = golden(name);
+    let cache = HeaderCache::new();
+    compare(name, &preprocess(name, None), &expected);
+    compare(name, &preprocess(name, Some(&cache)), &expected);
+    compare(name, &preprocess(name, Some(&cache)), &expected);
+}
+
+fn compare(name: &str, actual: &[Token], expected: &[Token]) {
+    for (i, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
+        assert_eq!(
+            a.text, e.text,
+            "{}: token #{} differs (line {} in the output, line {} in the golden file)",
+            name, i, a.line, e.line
+        );
+    }
+    assert_eq!(
+        actual.len(),
+        expected.len(),
+        "{}: the number of tokens differs",
+        name
+    );
+}
+
+#[test]
+fn includes() {
+    check("includes");
+}
+
+#[test]
+fn include_lib() {
+    check("include_lib");
+}
+
+#[test]
+fn conditionals() {
+    check("conditionals");
+}
+
+#[test]
+fn stringify() {
+    check("stringify");
+}
+
+#[test]
+fn overloading() {
+    check("overloading");
+}
+
+#[test]
+fn predefined() {
+    check("predefined");
+}
@@ -0,0 +1,25 @@
+-module(conditionals).
+-define(DEBUG, true).
+
+-ifdef(DEBUG).
+log(X) -> io:format("~p~n", [X]).
+-else.
+log(_) -> ok.
+-endif.
+
+-ifndef(DEBUG).
+mode() -> release.
+-else.
+-ifdef(VERBOSE).
+mode() -> verbose.
+-else.
+mode() -> debug.
+-endif.
+-endif.
+
+-undef(DEBUG).
+-ifdef(DEBUG).
+undefined() -> false.
+-else.
+undefined() -> true.
+-endif.
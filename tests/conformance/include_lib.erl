@@ -0,0 +1,8 @@
+-module(include_lib).
+-include_lib("conflib/include/lib.hrl").
+-include_lib("conflib/include/lib.hrl").
+
+call(X) ->
+    ?LIB_CALL(run, X).
+
+name() -> ?LIB_NAME.
@@ -0,0 +1,8 @@
+-module(includes).
+-include("include/conf.hrl").
+
+new(Name) ->
+    #conf{name = ?CONF_PAIR(name, Name)}.
+
+version(#conf{version = V}) ->
+    V =:= ?CONF_VERSION.
@@ -0,0 +1,8 @@
+-module(overloading).
+-define(M, m0).
+-define(M(), m1).
+-define(M(A), {m2, A}).
+-define(M(A, B), {m3, A, B}).
+
+t() ->
+    [?M, ?M(), ?M(x), ?M(x, ?M(y)), ?M({p, q}, [r, s])].
@@ -0,0 +1,8 @@
+-module(predefined).
+
+info() ->
+    {?MODULE, ?MODULE_STRING, ?FILE, ?LINE, ?MACHINE}.
+
+base() ->
+    {?BASE_MODULE, ?BASE_MODULE_STRING,
+     ?LINE}.
@@ -0,0 +1,9 @@
+-module(stringify).
+-define(S(X), ??X).
+-define(ASSERT(E), {assert, ??E, E}).
+
+strings() ->
+    [?S(foo(Bar, "baz")), ?S($a), ?S('Quoted atom'), ?S(1.5e3), ?S([a|B])].
+
+check(X) ->
+    ?ASSERT(X =:= 2 * 21).
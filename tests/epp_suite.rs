@@ -0,0 +1,164 @@
+//! Conformance tests for the macro expansion algorithm.
+//!
+//! The source files in the `tests/epp_suite/` directory are hand-written cases modelled on
+//! the macro expansion scenarios of OTP's `epp_SUITE` (they are not copies of the OTP sources).
+#![allow(clippy::result_large_err)]
+extern crate erl_pp;
+extern crate erl_tokenize;
+#[macro_use]
+extern crate trackable;
+
+use erl_pp::{ErrorKind, Preprocessor};
+use erl_tokenize::Lexer;
+use std::fs;
+
+fn pp(name: &str) -> erl_pp::Result<Vec<String>> {
+    let path = format!("tests/epp_suite/{}.erl", name);
+    let src = fs::read_to_string(&path).unwrap();
+    let mut lexer = Lexer::new(&src);
+    lexer.set_filepath(&path);
+    let tokens = Preprocessor::new(lexer).collect::<erl_pp::Result<Vec<_>>>()?;
+    Ok(tokens.iter().map(|t| t.text().to_string()).collect())
+}
+
+fn body(name: &str) -> Vec<String> {
+    let tokens = track_try_unwrap!(pp(name));
+    let module = ["-", "module", "(", name, ")", "."];
+    assert_eq!(&tokens[..module.len()], &module[..]);
+    tokens[module.len()..].to_vec()
+}
+
+#[test]
+fn overload_mac() {
+    assert_eq!(
+        body("overload_mac"),
+        [
+            "t", "(", ")", "->", "{", "a", ",", "{", "a", ",", "1", "}", ",", "{", "a", ",", "1",
+            ",", "2", "}", "}", "."
+        ]
+    );
+
+    let e = pp("overload_mismatch").err().unwrap();
+    if let ErrorKind::MacroArgumentMismatch { ref name, .. } = *e.kind() {
+        assert_eq!(name, "B");
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+}
+
+#[test]
+fn nested_args() {
+    assert_eq!(
+        body("nested_args"),
+        ["t", "(", ")", "->", "{", "3", ",", "1", ",", "3", "}", "."]
+    );
+}
+
+#[test]
+fn trailing_call() {
+    assert_eq!(
+        body("trailing_call"),
+        ["t", "(", ")", "->", "{", "g", ",", "b", "}", "."]
+    );
+}
+
+#[test]
+fn keyword_args() {
+    assert_eq!(
+        body("keyword_args").join(" "),
+        concat!(
+            "t ( ) -> case x of x -> a , b end . ",
+            "u ( ) -> fun ( A ) -> A , A end . ",
+            "v ( ) -> begin a , b end . ",
+            "w ( ) -> << 1 , 2 >> . ",
+            "x ( ) -> fun lists : map / 2 ."
+        )
+    );
+}
+
+#[test]
+fn stringify() {
+    assert_eq!(
+        body("stringify"),
+        [
+            "t",
+            "(",
+            ")",
+            "->",
+            "[",
+            r#""foo ( X , Y )""#,
+            ",",
+            r#""\"str\"""#,
+            ",",
+            r#""$a""#,
+            ",",
+            r#""'A b'""#,
+            ",",
+            r#""1 + 2.5""#,
+            "]",
+            ".",
+            "u",
+            "(",
+            ")",
+            "->",
+            "{",
+            r#""a""#,
+            ",",
+            r#""? S ( a )""#,
+            "}",
+            "."
+        ]
+    );
+}
+
+#[test]
+fn circular() {
+    let e = pp("circular").err().unwrap();
+    if let ErrorKind::CircularMacro {
+        ref name, arity, ..
+    } = *e.kind()
+    {
+        assert_eq!(name, "FOO");
+        assert_eq!(arity, None);
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+
+    assert_eq!(
+        body("self_reference"),
+        ["t", "(", ")", "->", "{", "f", ",", "0", "}", "."]
+    );
+}
+
+#[test]
+fn line() {
+    assert_eq!(
+        body("line"),
+        ["t", "(", ")", "->", "{", "5", ",", "{", "6", ",", "6", "}", "}", "."]
+    );
+}
+
+#[test]
+fn record_field() {
+    assert_eq!(
+        body("record_field"),
+        ["t", "(", "R", ")", "->", "R", "#", "r", ".", "g", "#", "r", ".", "f", "."]
+    );
+}
+
+#[test]
+fn undefined() {
+    let e = pp("undefined").err().unwrap();
+    if let ErrorKind::UndefinedMacro {
+        ref name,
+        arity,
+        ref position,
+    } = *e.kind()
+    {
+        assert_eq!(name, "NOPE");
+        assert_eq!(arity, Some(1));
+        assert_eq!(position.line(), 2);
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+}
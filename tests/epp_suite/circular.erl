@@ -0,0 +1,4 @@
+-module(circular).
+-define(FOO, ?BAR).
+-define(BAR, ?FOO).
+t() -> ?FOO.
@@ -0,0 +1,7 @@
+-module(keyword_args).
+-define(ARG(X), X).
+t() -> ?ARG(case x of x -> a, b end).
+u() -> ?ARG(fun(A) -> A, A end).
+v() -> ?ARG(begin a, b end).
+w() -> ?ARG(<<1, 2>>).
+x() -> ?ARG(fun lists:map/2).
@@ -0,0 +1,6 @@
+-module(line).
+-define(L, ?LINE).
+-define(P(X), {X, ?LINE}).
+t() ->
+    {?L,
+     ?P(?LINE)}.
@@ -0,0 +1,7 @@
+-module(nested_args).
+-define(M1(A), A).
+-define(M2(A), ?M1(A)).
+-define(M3, ?M2(3)).
+-define(ID(X), X).
+-define(APPLY(F), ?F(1)).
+t() -> {?M3, ?APPLY(ID), ?M1(?M2(?M3))}.
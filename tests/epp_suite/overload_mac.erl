@@ -0,0 +1,5 @@
+-module(overload_mac).
+-define(A, a).
+-define(A(X), {a, X}).
+-define(A(X, Y), {a, X, Y}).
+t() -> {?A, ?A(1), ?A(1, 2)}.
@@ -0,0 +1,4 @@
+-module(overload_mismatch).
+-define(B(X), X).
+-define(B(X, Y), {X, Y}).
+t() -> ?B(1, 2, 3).
@@ -0,0 +1,3 @@
+-module(record_field).
+-define(GET(R), R#r.f).
+t(R) -> ?GET(R#r.g).
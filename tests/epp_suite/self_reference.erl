@@ -0,0 +1,4 @@
+-module(self_reference).
+-define(F, ?F(0)).
+-define(F(X), {f, X}).
+t() -> ?F.
@@ -0,0 +1,5 @@
+-module(stringify).
+-define(S(X), ??X).
+-define(T(X), {X, ??X}).
+t() -> [?S(foo(X, Y)), ?S("str"), ?S($a), ?S('A b'), ?S(1 + 2.5)].
+u() -> ?T(?S(a)).
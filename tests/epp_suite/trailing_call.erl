@@ -0,0 +1,4 @@
+-module(trailing_call).
+-define(F(X), ?G).
+-define(G(Y), {g, Y}).
+t() -> ?F(a)(b).
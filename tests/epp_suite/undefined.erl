@@ -0,0 +1,2 @@
+-module(undefined).
+t() -> ?NOPE(1).
@@ -3,8 +3,10 @@ extern crate erl_tokenize;
 #[macro_use]
 extern crate trackable;
 
-use erl_pp::Preprocessor;
-use erl_tokenize::Lexer;
+use erl_pp::{
+    AttributeValue, BatchPreprocessor, HeaderCache, IncrementalPreprocessor, Preprocessor, Project,
+};
+use erl_tokenize::{Lexer, PositionRange};
 
 fn pp(text: &str) -> Preprocessor<Lexer<&str>> {
     let lexer = Lexer::new(text);
@@ -148,6 +150,21 @@ fn include_lib_works() {
     );
 }
 
+#[test]
+fn include_relative_to_including_file_works() {
+    // `bar.hrl` is looked up in the directory of the including file (i.e., `tests/`)
+    let mut lexer = Lexer::new(r#"foo.-include("bar.hrl").baz."#);
+    lexer.set_filepath("tests/including.erl");
+    let tokens = track_try_unwrap!(Preprocessor::new(lexer).collect::<Result<Vec<_>, _>>());
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "bar", ".", "baz", "."]
+    );
+
+    // Without the path of the including file, it is looked up in the current directory
+    assert!(pp(r#"-include("bar.hrl")."#).next().unwrap().is_err());
+}
+
 #[test]
 fn macro_expansion_works() {
     let src = r#"-define(foo,bar).aaa.?foo.bbb."#;
@@ -174,7 +191,7 @@ fn macro_expansion_works() {
             "{",
             "bar",
             ",",
-            r#""[1,2]""#,
+            r#""[ 1 , 2 ]""#,
             "}",
             ".",
             "bbb",
@@ -193,7 +210,7 @@ fn macro_expansion_works() {
     let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
     assert_eq!(
         tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
-        ["{", "baz", ",", r#""?bar""#, "}", "."]
+        ["{", "baz", ",", r#""? bar""#, "}", "."]
     );
 
     let src = r#"-define(foo(A), ?bar(A)). -define(bar(A), A). ?foo(baz)."#;
@@ -202,6 +219,14 @@ fn macro_expansion_works() {
         tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
         ["baz", "."]
     );
+
+    let src =
+        r#"-define(foo(A, B), ?bar(A, [B])). -define(bar(A, B), {A, B, A, ??A}). ?foo(x, y)."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["{", "x", ",", "[", "y", "]", ",", "x", ",", r#""x""#, "}", "."]
+    );
 }
 
 #[test]
@@ -226,3 +251,1202 @@ fn args_for_expanded_tokens_test() {
         ["-", "module", "(", "prog", ")", "."]
     );
 }
+
+#[test]
+fn macro_redefinition_works() {
+    use erl_pp::ErrorKind;
+
+    let src = r#"-define(foo, 1). -define(foo, 2)."#;
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    if let ErrorKind::MacroRedefinition {
+        ref name,
+        ref original,
+        ref redefinition,
+    } = *e.kind()
+    {
+        assert_eq!(name, "foo");
+        assert_eq!(original.as_ref().map(|p| p.offset()), Some(0));
+        assert_eq!(redefinition.offset(), 17);
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+
+    let src = r#"-define(LINE, 1)."#;
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    if let ErrorKind::PredefinedMacroRedefinition { ref name, .. } = *e.kind() {
+        assert_eq!(name, "LINE");
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+
+    // Definitions with different arities are not redefinitions
+    let src = r#"-define(foo, 0). -define(foo(), 1). -define(foo(A), A). {?foo, ?foo(), ?foo(2)}."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["{", "0", ",", "1", ",", "2", "}", "."]
+    );
+
+    let src = r#"-define(foo, 1). -undef(foo). -define(foo, 2). ?foo."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["2", "."]
+    );
+
+    let src = r#"-define(foo, 1). -define(foo, 2). ?foo."#;
+    let mut pp = pp(src);
+    pp.set_allow_macro_redefinition(true);
+    let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["2", "."]
+    );
+}
+
+#[test]
+fn predefined_macro_table_works() {
+    let src = r#"-ifdef(MODULE). a. -else. b. -endif.
+-module(foo).
+-ifdef(MODULE). c. -else. d. -endif.
+?MODULE. ?MODULE_STRING. ?BASE_MODULE."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        [
+            "b", ".", "-", "module", "(", "foo", ")", ".", "c", ".", "'foo'", ".", r#""foo""#, ".",
+            "'foo'", "."
+        ]
+    );
+
+    let src = r#"-module(foo). -undef(MODULE). -ifdef(MODULE). a. -else. b. -endif."#;
+    let tokens = pp(src).collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["-", "module", "(", "foo", ")", ".", "b", "."]
+    );
+
+    let src = r#"-undef(LINE). ?LINE."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_err());
+
+    let src = r#"-module(foo). -define(MODULE, bar)."#;
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    if let erl_pp::ErrorKind::PredefinedMacroRedefinition { ref name, .. } = *e.kind() {
+        assert_eq!(name, "MODULE");
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+
+    let src = r#"-define(FILE, "foo.erl")."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_err());
+}
+
+#[test]
+fn stringify_works() {
+    fn stringify(arg: &str) -> String {
+        let src = format!("-define(S(A), ??A). ?S({}).", arg);
+        let tokens = pp(&src).collect::<Result<Vec<_>, _>>().unwrap();
+        tokens[0].as_string_token().unwrap().value().to_string()
+    }
+
+    assert_eq!(stringify("foo(X, Y)"), "foo ( X , Y )");
+    assert_eq!(stringify("a - b"), "a - b");
+    assert_eq!(
+        stringify("'foo bar' ++ 'case' ++ \"s\\n\""),
+        r#"'foo bar' ++ 'case' ++ "s\n""#
+    );
+    assert_eq!(stringify("$a + $\\s + $\\n"), r#"$a + $\s + $\n"#);
+    assert_eq!(
+        stringify("[16#ff, 1.0e3, 100.0, 0.001]"),
+        "[ 255 , 1.0e3 , 100.0 , 0.001 ]"
+    );
+    assert_eq!(stringify("?S(x)"), "? S ( x )");
+    assert_eq!(stringify("case X of _ -> ok end"), "case X of _ -> ok end");
+
+    let src = r#"-define(S(A), ??B). ?S(a)."#;
+    let e = pp(src).collect::<Result<Vec<_>, _>>().err().unwrap();
+    if let erl_pp::ErrorKind::StringifyNonParameter { ref name, .. } = *e.kind() {
+        assert_eq!(name, "B");
+    } else {
+        panic!("Unexpected error: {}", e);
+    }
+}
+
+#[test]
+fn header_cache_works() {
+    let cache = HeaderCache::new();
+    for _ in 0..2 {
+        let src = r#"-include("tests/bar.hrl"). -include("tests/bar.hrl"). baz."#;
+        let mut pp = pp(src);
+        pp.set_header_cache(cache.clone());
+        let tokens = pp.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+            ["bar", ".", "bar", ".", "baz", "."]
+        );
+    }
+    assert_eq!(cache.len(), 1);
+
+    let header = cache.load("tests/bar.hrl").unwrap();
+    assert_eq!(header.tokens().len(), 2);
+    assert_eq!(header.directives().count(), 0);
+}
+
+#[test]
+fn snapshot_works() {
+    let prefix = r#"-module(foo). -include("tests/bar.hrl"). -define(A, 1). -ifdef(B). x. -endif."#;
+    let mut preprocessor = pp(prefix);
+    assert_eq!(preprocessor.by_ref().count(), 8);
+    let snapshot = track_try_unwrap!(preprocessor.snapshot());
+    assert_eq!(snapshot.prefix_len(), 6);
+    assert_eq!(snapshot.included_files().len(), 1);
+
+    // The prefix matches
+    let src =
+        r#"-module(baz). -include("tests/bar.hrl"). -define(A, 1). -ifdef(B). x. -endif. ?A."#;
+    let resumed = track_try_unwrap!(Preprocessor::from_snapshot(Lexer::new(src), &snapshot));
+    assert!(resumed.is_resumed());
+    assert_eq!(resumed.directives().len(), 4);
+    let tokens = resumed.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["-", "module", "(", "baz", ")", ".", "bar", ".", "1", "."]
+    );
+
+    // The forms in the prefix are yielded one by one
+    let src = r#"-module(baz). -include("tests/bar.hrl"). -define(A, 1). -ifdef(B). x. -endif. f() -> ?A."#;
+    let mut resumed = track_try_unwrap!(Preprocessor::from_snapshot(Lexer::new(src), &snapshot));
+    assert!(resumed.is_resumed());
+    let forms = track_try_unwrap!(resumed.forms().collect::<Result<Vec<_>, _>>());
+    assert_eq!(
+        forms
+            .iter()
+            .map(|f| f.tokens().iter().map(|t| t.text()).collect::<String>())
+            .collect::<Vec<_>>(),
+        ["-module(baz).", "bar.", "f()->1."]
+    );
+    assert_eq!(forms[0].start_position().offset(), 0);
+    assert_eq!(forms[0].end_position().offset(), 13);
+    assert_eq!(forms[1].filepath().unwrap().to_str(), Some("tests/bar.hrl"));
+    assert_eq!(forms[2].start_position().offset(), 78);
+
+    let mut preprocessor = pp("-module(foo). -define(A, 1).");
+    assert_eq!(preprocessor.by_ref().count(), 6);
+    let module_snapshot = track_try_unwrap!(preprocessor.snapshot());
+    let src = "-module(bar). -define(A, 1). f() -> ?A.";
+    let mut resumed = track_try_unwrap!(Preprocessor::from_snapshot(
+        Lexer::new(src),
+        &module_snapshot
+    ));
+    assert_eq!(resumed.attributes().count(), 1);
+    let mut resumed = track_try_unwrap!(Preprocessor::from_snapshot(
+        Lexer::new(src),
+        &module_snapshot
+    ));
+    assert_eq!(resumed.records().count(), 0);
+
+    // The prefix does not match
+    let src = r#"-module(baz). -include("tests/bar.hrl"). -define(A, 2). ?A."#;
+    let resumed = track_try_unwrap!(Preprocessor::from_snapshot(Lexer::new(src), &snapshot));
+    assert!(!resumed.is_resumed());
+    let tokens = resumed.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["-", "module", "(", "baz", ")", ".", "bar", ".", "2", "."]
+    );
+
+    // Only directives can be in the prefix
+    let mut preprocessor = pp("foo. -define(A, 1).");
+    assert_eq!(preprocessor.by_ref().count(), 2);
+    assert!(preprocessor.snapshot().is_err());
+}
+
+#[test]
+fn snapshot_include_resolution_works() {
+    use std::fs;
+
+    let lexer = |path: &str, src: &'static str| {
+        let mut lexer = Lexer::new(src);
+        lexer.set_filepath(path);
+        lexer
+    };
+    let texts = |pp: Preprocessor<Lexer<&str>>| {
+        let tokens = track_try_unwrap!(pp.collect::<Result<Vec<_>, _>>());
+        tokens
+            .iter()
+            .map(|t| t.text().to_string())
+            .collect::<Vec<_>>()
+    };
+    let mut preprocessor =
+        Preprocessor::new(lexer("tests/snapshot/h1/a.erl", r#"-include("x.hrl")."#));
+    assert_eq!(preprocessor.by_ref().count(), 0);
+    let snapshot = track_try_unwrap!(preprocessor.snapshot());
+
+    // A form following an included file is not a part of the prefix
+    let src = r#"-include("x.hrl"). f() -> ?X."#;
+    let mut preprocessor = Preprocessor::new(lexer("tests/snapshot/h1/a.erl", src));
+    assert_eq!(preprocessor.by_ref().count(), 6);
+    assert!(preprocessor.snapshot().is_err());
+
+    // `x.hrl` is resolved to the same file
+    let pp = track_try_unwrap!(Preprocessor::from_snapshot(
+        lexer("tests/snapshot/h1/b.erl", src),
+        &snapshot
+    ));
+    assert!(pp.is_resumed());
+    assert_eq!(texts(pp), ["f", "(", ")", "->", "one", "."]);
+
+    // `x.hrl` is resolved to another file
+    let pp = track_try_unwrap!(Preprocessor::from_snapshot(
+        lexer("tests/snapshot/h2/b.erl", src),
+        &snapshot
+    ));
+    assert!(!pp.is_resumed());
+    assert_eq!(texts(pp), ["f", "(", ")", "->", "two", "."]);
+
+    // The included file is changed after the snapshot is taken
+    let dir = std::env::temp_dir().join(format!("erl_pp_snapshot_{}", std::process::id()));
+    track_try_unwrap!(fs::create_dir_all(&dir).map_err(erl_pp::Error::from));
+    let header = dir.join("y.hrl");
+    track_try_unwrap!(fs::write(&header, "-define(Y, old).").map_err(erl_pp::Error::from));
+    let module = dir.join("a.erl");
+    let module = module.to_str().unwrap();
+    let mut preprocessor = Preprocessor::new(lexer(module, r#"-include("y.hrl")."#));
+    assert_eq!(preprocessor.by_ref().count(), 0);
+    let src = r#"-include("y.hrl"). f() -> ?Y."#;
+    let snapshot = track_try_unwrap!(preprocessor.snapshot());
+    track_try_unwrap!(fs::write(&header, "-define(Y, new).").map_err(erl_pp::Error::from));
+    let pp = track_try_unwrap!(Preprocessor::from_snapshot(lexer(module, src), &snapshot));
+    assert!(!pp.is_resumed());
+    assert_eq!(texts(pp), ["f", "(", ")", "->", "new", "."]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn incremental_preprocessor_works() {
+    let src = r#"-define(A, 1). foo() -> ?A. -include("tests/bar.hrl"). baz() -> 1."#;
+    let mut pp = IncrementalPreprocessor::new(src);
+    let changes = track_try_unwrap!(pp.preprocess());
+    assert_eq!(changes.inserted, 0..3);
+
+    // Changes a macro definition
+    let changes = track_try_unwrap!(pp.edit(11..12, "2"));
+    assert_eq!(changes.removed, 0..1);
+    assert_eq!(changes.inserted, 0..1);
+    assert_eq!(pp.forms().next().unwrap().tokens()[4].text(), "2");
+
+    // Edits just after the end of a form
+    let offset = pp.text().len();
+    let changes = track_try_unwrap!(pp.edit(offset..offset, "5."));
+    assert_eq!(changes.removed, 2..3);
+    assert_eq!(changes.inserted, 2..3);
+    assert_eq!(pp.forms().nth(2).unwrap().tokens()[4].text(), "1.5");
+
+    // Errors
+    assert!(pp.edit(15..18, "?B").is_err());
+    assert_eq!(pp.forms().count(), 0);
+    let changes = track_try_unwrap!(pp.edit(15..17, "foo"));
+    assert_eq!(changes.inserted, 0..3);
+    assert_eq!(
+        pp.forms()
+            .map(|f| f.tokens().iter().map(|t| t.text()).collect::<String>())
+            .collect::<Vec<_>>(),
+        ["foo()->2.", "bar.", "baz()->1.5."]
+    );
+}
+
+#[test]
+fn forms_works() {
+    let src = r#"-define(A, a.b). foo() -> R#r.f, ?A. -include("tests/bar.hrl"). baz."#;
+    let mut pp = pp(src);
+    let forms = track_try_unwrap!(pp.forms().collect::<Result<Vec<_>, _>>());
+    assert_eq!(
+        forms
+            .iter()
+            .map(|f| f.tokens().iter().map(|t| t.text()).collect::<String>())
+            .collect::<Vec<_>>(),
+        ["foo()->R#r.f,a.b.", "bar.", "baz."]
+    );
+    assert_eq!(forms[0].filepath(), None);
+    assert_eq!(forms[0].start_position().offset(), 17);
+    assert_eq!(forms[1].filepath().unwrap().to_str(), Some("tests/bar.hrl"));
+    assert_eq!(forms[1].start_position().offset(), 0);
+    assert_eq!(forms[2].start_position().offset(), 64);
+    assert_eq!(pp.directives().len(), 2);
+
+    // The last form may not be terminated by a dot
+    let mut pp = Preprocessor::new(Lexer::new("foo. bar"));
+    let forms = track_try_unwrap!(pp.forms().collect::<Result<Vec<_>, _>>());
+    assert_eq!(forms[1].tokens().last().map(|t| t.text()), Some("bar"));
+}
+
+#[test]
+fn batch_preprocessor_works() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Preprocessor<Lexer<String>>>();
+    assert_send_sync::<erl_pp::Error>();
+    assert_send_sync::<erl_pp::MacroDef>();
+    assert_send_sync::<erl_pp::Directive>();
+    assert_send_sync::<erl_pp::Snapshot>();
+    assert_send_sync::<BatchPreprocessor>();
+
+    let files = [
+        "tests/conformance/includes.erl",
+        "tests/conformance/conditionals.erl",
+        "tests/conformance/no_such_file.erl",
+        "tests/conformance/stringify.erl",
+    ];
+    let mut batch = BatchPreprocessor::new();
+    batch.set_threads(3);
+    let results = batch.preprocess(&files);
+    assert_eq!(results.len(), files.len());
+    for (file, result) in files.iter().zip(&results) {
+        if file.contains("no_such_file") {
+            assert!(result.is_err());
+        } else {
+            let result = result.as_ref().unwrap();
+            assert_eq!(result.path().to_str(), Some(*file));
+            assert!(!result.forms().is_empty());
+        }
+    }
+    assert_eq!(
+        results[0].as_ref().unwrap().included_files(),
+        [std::path::Path::new("tests/conformance/include/conf.hrl")]
+    );
+    assert_eq!(batch.header_cache().len(), 1);
+
+    // The limits, the include policy and the path variables are applied to each file
+    let mut batch = BatchPreprocessor::new();
+    batch.set_limits(erl_pp::Limits {
+        max_includes: Some(0),
+        ..erl_pp::Limits::default()
+    });
+    let e = batch.preprocess(&files[..1]).remove(0).err().unwrap();
+    assert!(matches!(
+        *e.kind(),
+        erl_pp::ErrorKind::LimitExceeded(erl_pp::Limit::Includes)
+    ));
+
+    let mut batch = BatchPreprocessor::new();
+    batch.set_include_policy(erl_pp::IncludePolicy::sandboxed(["tests/project"]));
+    let e = batch.preprocess(&files[..1]).remove(0).err().unwrap();
+    assert!(matches!(
+        *e.kind(),
+        erl_pp::ErrorKind::IncludeNotAllowed { .. }
+    ));
+    assert!(batch.preprocess(&files[1..2]).remove(0).is_ok());
+
+    let file = std::env::temp_dir().join(format!("erl_pp_batch_{}.erl", std::process::id()));
+    track_try_unwrap!(
+        std::fs::write(&file, r#"-include("$CONF/conf.hrl")."#).map_err(erl_pp::Error::from)
+    );
+    let mut batch = BatchPreprocessor::new();
+    batch
+        .path_variables_mut()
+        .insert("CONF", "tests/conformance/include");
+    batch.path_variables_mut().set_use_env(false);
+    let result = batch.preprocess(&[&file]).remove(0);
+    let _ = std::fs::remove_file(&file);
+    assert_eq!(track_try_unwrap!(result).included_files().len(), 1);
+}
+
+#[test]
+fn project_works() {
+    fn texts(file: &erl_pp::PreprocessedFile) -> Vec<String> {
+        file.forms()
+            .iter()
+            .map(|f| f.tokens().iter().map(|t| t.text()).collect::<String>())
+            .collect()
+    }
+
+    let project = track_try_unwrap!(Project::open("tests/project"));
+    assert_eq!(
+        project
+            .applications()
+            .iter()
+            .map(|a| a.name())
+            .collect::<Vec<_>>(),
+        ["top", "sub"]
+    );
+    assert_eq!(
+        project
+            .modules()
+            .map(|m| m.to_str().unwrap())
+            .collect::<Vec<_>>(),
+        [
+            "tests/project/src/top.erl",
+            "tests/project/apps/sub/src/sub.erl"
+        ]
+    );
+
+    let results = project.preprocess();
+    let top = track_try_unwrap!(results[0].clone());
+    assert_eq!(
+        texts(&top),
+        [
+            "-module(top).",
+            "-record(top,{name::atom()}).",
+            "mode()->common.",
+            r#"info()->{"1.0",true,default_dep,sub,#top{}}."#
+        ]
+    );
+    let sub = track_try_unwrap!(results[1].clone());
+    assert_eq!(texts(&sub), ["-module(sub).", r#"value()->{sub,1,"1.0"}."#]);
+
+    let project = track_try_unwrap!(Project::open_with_profile("tests/project", "prod"));
+    let pp = track_try_unwrap!(project.preprocessor("tests/project/src/top.erl"));
+    let tokens = track_try_unwrap!(pp.collect::<Result<Vec<_>, _>>());
+    let text = tokens.iter().map(|t| t.text()).collect::<String>();
+    assert!(text.contains("mode()->prod."));
+    assert!(text.contains("prod_dep"));
+}
+
+#[test]
+fn umbrella_project_works() {
+    use std::path::Path;
+
+    // `{i, "priv_inc"}` in the root config is resolved against each application
+    let project = track_try_unwrap!(Project::open("tests/umbrella"));
+    let apps = project.applications();
+    assert_eq!(apps.len(), 2);
+    assert!(apps[0]
+        .include_paths()
+        .contains(&Path::new("tests/umbrella/apps/a/priv_inc").to_path_buf()));
+    assert!(apps[1]
+        .include_paths()
+        .contains(&Path::new("tests/umbrella/apps/b/priv_inc").to_path_buf()));
+
+    let results = project.preprocess();
+    for (result, name) in results.iter().zip(&["a", "b"]) {
+        let file = track_try_unwrap!(result.clone());
+        let last = file.forms().last().unwrap();
+        let text = last.tokens().iter().map(|t| t.text()).collect::<String>();
+        assert_eq!(text, format!("name()->{}.", name));
+    }
+}
+
+#[test]
+fn erl_opts_works() {
+    use erl_pp::ErlOpts;
+    use std::path::Path;
+
+    fn expand(opts: &ErlOpts, src: &str) -> String {
+        let mut pp = pp(src);
+        opts.apply(&mut pp);
+        let tokens = track_try_unwrap!(pp.collect::<Result<Vec<_>, _>>());
+        tokens.iter().map(|t| t.text()).collect()
+    }
+
+    let platform = "26.2.1-x86_64-pc-linux-gnu-64";
+    let opts = track_try_unwrap!(ErlOpts::from_rebar_config(
+        "tests/project/rebar.config",
+        Some("prod"),
+        Some(platform)
+    ));
+    assert_eq!(opts.include_paths(), [Path::new("tests/project/common")]);
+    assert_eq!(
+        expand(&opts, "{?TEST, ?VSN, ?OTP_2X, ?PROD}."),
+        r#"{true,"1.0",true,true}."#
+    );
+    let opts = track_try_unwrap!(ErlOpts::from_rebar_config(
+        "tests/project/rebar.config",
+        None,
+        Some("19.3-x86_64-pc-linux-gnu-64")
+    ));
+    assert!(expand(&opts, "-ifdef(OTP_2X). a. -else. b. -endif.") == "b.");
+    assert!(expand(&opts, "-ifdef(PROD). a. -else. b. -endif.") == "b.");
+
+    let opts = track_try_unwrap!(ErlOpts::from_emakefile(
+        "tests/project/Emakefile",
+        Some(platform)
+    ));
+    assert_eq!(
+        opts.include_paths(),
+        [
+            Path::new("tests/project/include"),
+            Path::new("tests/project/apps/sub/include")
+        ]
+    );
+    assert_eq!(
+        expand(
+            &opts,
+            r#"-include("top.hrl"). -include("sub.hrl"). {?EMAKE, ?WORDSIZE, ?SUB_NAME}."#
+        ),
+        "-record(top,{name::atom()}).{true,64,sub}."
+    );
+}
+
+#[test]
+fn from_reader_works() {
+    use std::io::{self, Read};
+
+    /// Reader which returns at most a few bytes at a time.
+    struct Trickle<'a>(&'a [u8]);
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let mut src = String::from("-module(foo).\n-include(\"tests/bar.hrl\").\n");
+    for i in 0..1000 {
+        src += &format!(
+            "f{i}(X) -> % 関数 {i}\n    {{?LINE, \"αβγ {i}\", X =:= 1.0e-3, 16#FF, $\\n, 'a b'}}.\n",
+            i = i
+        );
+    }
+
+    let expected = track_try_unwrap!(pp(&src).collect::<Result<Vec<_>, _>>());
+    let tokens = track_try_unwrap!(
+        Preprocessor::from_reader(Trickle(src.as_bytes())).collect::<Result<Vec<_>, _>>()
+    );
+    assert_eq!(tokens.len(), expected.len());
+    for (a, b) in tokens.iter().zip(expected.iter()) {
+        assert_eq!(a.text(), b.text());
+        assert_eq!(a.start_position(), b.start_position());
+    }
+
+    let mut pp = Preprocessor::from_reader(&b"foo(\"unterminated)."[..]);
+    assert!(pp.next().unwrap().is_err());
+    assert!(pp.next().is_none());
+
+    let mut pp = Preprocessor::from_reader(&b"foo(\xff)."[..]);
+    assert!(pp.next().unwrap().is_err());
+}
+
+#[test]
+fn limits_works() {
+    use erl_pp::{ErrorKind, Limit, Limits};
+    use std::time::Duration;
+
+    fn exceeded(src: &str, limits: Limits) -> Option<Limit> {
+        let mut pp = pp(src);
+        pp.set_limits(limits);
+        match pp.collect::<Result<Vec<_>, _>>() {
+            Ok(_) => None,
+            Err(e) => match *e.kind() {
+                ErrorKind::LimitExceeded(limit) => Some(limit),
+                _ => panic!("{}", e),
+            },
+        }
+    }
+
+    // Each level doubles the size of the expansion
+    let mut bomb = String::from("-define(M0, x).\n");
+    for i in 1..=40 {
+        bomb += &format!("-define(M{}, {{?M{}, ?M{}}}).\n", i, i - 1, i - 1);
+    }
+    bomb += "?M40.";
+
+    let limits = Limits {
+        max_output_tokens: Some(1000),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(&bomb, limits.clone()), Some(Limit::OutputTokens));
+    assert_eq!(exceeded("foo(?LINE).", limits), None);
+
+    let limits = Limits {
+        max_expansion_steps: Some(100),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(&bomb, limits), Some(Limit::ExpansionSteps));
+
+    let limits = Limits {
+        max_duration: Some(Duration::from_millis(10)),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(&bomb, limits), Some(Limit::Duration));
+
+    let src = r#"-include("tests/bar.hrl"). -include("tests/bar.hrl"). -include("tests/bar.hrl")."#;
+    let limits = Limits {
+        max_includes: Some(2),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(src, limits), Some(Limit::Includes));
+
+    let limits = Limits {
+        max_included_bytes: Some(8),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(src, limits), Some(Limit::IncludedBytes));
+
+    let limits = Limits {
+        max_includes: Some(3),
+        max_included_bytes: Some(100),
+        ..Limits::default()
+    };
+    assert_eq!(exceeded(src, limits), None);
+
+    // The size of a device file is not known in advance
+    if cfg!(unix) {
+        let limits = Limits {
+            max_included_bytes: Some(1000),
+            ..Limits::default()
+        };
+        let src = r#"-include("/dev/zero")."#;
+        assert_eq!(exceeded(src, limits.clone()), Some(Limit::IncludedBytes));
+
+        let mut pp = pp(src);
+        pp.set_limits(limits);
+        pp.set_header_cache(erl_pp::HeaderCache::new());
+        let e = pp.collect::<Result<Vec<_>, _>>().err().unwrap();
+        assert!(matches!(
+            *e.kind(),
+            ErrorKind::LimitExceeded(Limit::IncludedBytes)
+        ));
+    }
+}
+
+#[test]
+fn include_policy_works() {
+    use erl_pp::{ErrorKind, IncludePolicy};
+    use std::collections::HashSet;
+
+    fn check(src: &str, policy: &IncludePolicy) -> Result<String, ErrorKind> {
+        let mut pp = pp(src);
+        pp.set_include_policy(policy.clone());
+        *pp.code_paths_mut() = vec!["tests/project/_build/default/lib".into()].into();
+        match pp.collect::<Result<Vec<_>, _>>() {
+            Ok(tokens) => Ok(tokens.iter().map(|t| t.text()).collect()),
+            Err(e) => Err(e.kind().clone()),
+        }
+    }
+
+    let mut policy = IncludePolicy::sandboxed(["tests/project"]);
+    assert_eq!(
+        check(r#"-include("tests/project/include/top.hrl")."#, &policy).ok(),
+        Some("-record(top,{name::atom()}).".to_string())
+    );
+    assert_eq!(
+        check(r#"-include_lib("dep/include/dep.hrl"). ?DEP."#, &policy).ok(),
+        Some("default_dep.".to_string())
+    );
+
+    let cwd = std::env::current_dir().unwrap();
+    for src in [
+        r#"-include("tests/bar.hrl")."#,
+        r#"-include("tests/project/../bar.hrl")."#,
+        &format!(r#"-include("{}/tests/bar.hrl")."#, cwd.display()),
+    ] {
+        match check(src, &policy) {
+            Err(ErrorKind::IncludeNotAllowed { path, .. }) => assert!(path.ends_with("bar.hrl")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    let src = r#"-include("$CARGO_MANIFEST_DIR/tests/project/include/top.hrl")."#;
+    match check(src, &policy) {
+        Err(ErrorKind::PathVariableNotAllowed { name, .. }) => {
+            assert_eq!(name, "CARGO_MANIFEST_DIR")
+        }
+        other => panic!("{:?}", other),
+    }
+    let allowed = ["CARGO_MANIFEST_DIR".to_string()];
+    policy.set_allowed_variables(Some(allowed.iter().cloned().collect::<HashSet<_>>()));
+    assert!(check(src, &policy).is_ok());
+
+    // The default policy imposes no restrictions
+    assert!(check(
+        r#"-include("tests/project/../bar.hrl")."#,
+        &IncludePolicy::new()
+    )
+    .is_ok());
+}
+
+#[test]
+fn path_variables_works() {
+    use erl_pp::ErrorKind;
+
+    // Falls back to the process environment by default
+    let src = r#"-include("$CARGO_MANIFEST_DIR/tests/bar.hrl")."#;
+    assert!(pp(src).collect::<Result<Vec<_>, _>>().is_ok());
+
+    let src = r#"foo. -include_lib("$LIB/include/dep.hrl"). ?DEP."#;
+    let mut p = pp(src);
+    p.path_variables_mut()
+        .insert("LIB", "tests/project/_build/prod/lib/dep");
+    let tokens = track_try_unwrap!(p.collect::<Result<Vec<_>, _>>());
+    assert_eq!(
+        tokens.iter().map(|t| t.text()).collect::<Vec<_>>(),
+        ["foo", ".", "prod_dep", "."]
+    );
+
+    let mut p = pp(src);
+    p.path_variables_mut().set_use_env(false);
+    let e = p.collect::<Result<Vec<_>, _>>().err().unwrap();
+    match *e.kind() {
+        ErrorKind::UndefinedPathVariable {
+            ref name,
+            ref position,
+        } => {
+            assert_eq!(name, "LIB");
+            assert_eq!(position.offset(), 5);
+        }
+        _ => panic!("{}", e),
+    }
+}
+
+#[test]
+fn expand_macro_at_works() {
+    let src = r#"-module(m).
+-define(A, 1).
+-define(F(X), {X, ?A, ?LINE}).
+foo() -> ?F(x).
+-undef(A).
+-define(A, 2).
+bar() -> ?F(y) + ?MODULE.
+baz() -> ok."#;
+    let at = |offset: usize| {
+        let t = Lexer::new(src)
+            .filter_map(|t| t.ok())
+            .find(|t| t.start_position().offset() >= offset)
+            .unwrap();
+        t.start_position()
+    };
+    let expand = |offset: usize| {
+        let mut p = pp(src);
+        track_try_unwrap!(p.expand_macro_at(&at(offset)))
+    };
+
+    let e = expand(src.find("?F(x)").unwrap() + 1).unwrap();
+    assert_eq!(e.call().to_string(), "?F(x)");
+    assert_eq!(e.definition().arity(), Some(1));
+    assert_eq!(e.one_step_text(), "{x, ?A, ?LINE}");
+    assert_eq!(e.full_text(), "{x, 1, 4}");
+    assert_eq!(e.full().len(), 7);
+
+    // The environment at the call is used
+    let e = expand(src.find("?F(y)").unwrap()).unwrap();
+    assert_eq!(e.full_text(), "{y, 2, 7}");
+
+    // Predefined macros
+    let e = expand(src.find("?MODULE").unwrap()).unwrap();
+    assert_eq!(e.full_text(), "'m'");
+
+    // No macro call
+    assert!(expand(src.find("baz").unwrap()).is_none());
+}
+
+#[test]
+fn macros_at_works() {
+    use erl_pp::MacroEvent;
+
+    let src = r#"-define(A, 1).
+foo() -> ?A.
+-include("tests/conformance/include/conf.hrl").
+-undef(A).
+bar() -> ?CONF_VERSION."#;
+    let position_of = |text: &str| {
+        Lexer::new(src)
+            .filter_map(|t| t.ok())
+            .find(|t| t.text() == text)
+            .unwrap()
+            .start_position()
+    };
+    let mut p = pp(src);
+    assert!(!p.record_timeline());
+    p.set_record_timeline(true);
+    track_try_unwrap!(p.by_ref().collect::<Result<Vec<_>, _>>());
+
+    let macros = p.macros_at(&position_of("foo")).unwrap();
+    assert!(macros.contains_key("A"));
+    assert!(macros.contains_key("LINE"));
+    assert!(!macros.contains_key("CONF_VERSION"));
+
+    let macros = p.macros_at(&position_of("bar")).unwrap();
+    assert!(!macros.contains_key("A"));
+    assert!(macros.contains_key("CONF_VERSION"));
+    assert_eq!(macros["CONF_PAIR"][0].arity(), Some(2));
+
+    // Positions in the included file
+    let record = p
+        .directives()
+        .keys()
+        .find(|k| k.filepath().is_some() && k.line() == 2)
+        .unwrap()
+        .clone();
+    let macros = p.macros_at(&record).unwrap();
+    assert!(macros.contains_key("A"));
+    assert!(macros.contains_key("CONF_VERSION"));
+    assert!(!macros.contains_key("CONF_PAIR"));
+
+    let events = p
+        .macro_events()
+        .map(|e| match *e {
+            MacroEvent::Define { ref name, .. } => format!("+{}", name),
+            MacroEvent::Undef { ref name, .. } => format!("-{}", name),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(events, ["+A", "+CONF_VERSION", "+CONF_PAIR", "-A"]);
+
+    // Changes made via `macros_mut` are reflected
+    let src = "foo. bar.";
+    let mut p = pp(src);
+    p.set_record_timeline(true);
+    assert!(p.next().is_some());
+    let line = p.macros()["LINE"].clone();
+    p.macros_mut().insert("X".to_string(), line);
+    track_try_unwrap!(p.by_ref().collect::<Result<Vec<_>, _>>());
+    let positions = Lexer::new(src)
+        .map(|t| t.unwrap().start_position())
+        .collect::<Vec<_>>();
+    assert!(!p.macros_at(&positions[0]).unwrap().contains_key("X"));
+    assert!(p.macros_at(&positions[2]).unwrap().contains_key("X"));
+
+    // Recording can be enabled in the middle of the input
+    let src = "-define(A, 1). foo. -define(B, 2). bar.";
+    let mut p = Preprocessor::from_reader(src.as_bytes());
+    assert!(!p.record_timeline());
+    assert!(p.next().is_some());
+    p.set_record_timeline(true);
+    track_try_unwrap!(p.by_ref().collect::<Result<Vec<_>, _>>());
+    let positions = Lexer::new(src)
+        .map(|t| t.unwrap().start_position())
+        .collect::<Vec<_>>();
+    assert!(p.macros_at(&positions[8]).is_none());
+    let macros = p.macros_at(&positions[18]).unwrap();
+    assert!(macros.contains_key("A") && macros.contains_key("B"));
+    assert_eq!(p.macro_events().count(), 1);
+}
+
+#[test]
+fn macro_completions_works() {
+    let src = r#"-module(foo).
+-include("tests/conformance/include/conf.hrl").
+bar() -> ?CONF_PAIR(a, ?CONF_VERSION) + ?LINE."#;
+    let mut p = pp(src);
+    p.set_record_timeline(true);
+    track_try_unwrap!(p.by_ref().collect::<Result<Vec<_>, _>>());
+    let bar = Lexer::new(src)
+        .map(|t| t.unwrap())
+        .find(|t| t.text() == "bar")
+        .unwrap()
+        .start_position();
+
+    let completions = p.macro_completions(&bar, "CONF").unwrap();
+    assert_eq!(
+        completions
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>(),
+        ["?CONF_PAIR(K, V)", "?CONF_VERSION"]
+    );
+    let pair = &completions[0];
+    assert_eq!(pair.arity, Some(2));
+    assert_eq!(pair.parameters, ["K", "V"]);
+    assert_eq!(pair.preview, "{K, V}");
+    let position = pair.position.as_ref().unwrap();
+    assert!(position.filepath().unwrap().ends_with("conf.hrl"));
+    assert_eq!(position.line(), 2);
+
+    // Predefined macros
+    let completions = p.macro_completions(&bar, "").unwrap();
+    let preview = |name: &str| {
+        completions
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.preview.clone())
+            .unwrap()
+    };
+    assert_eq!(preview("MODULE"), "'foo'");
+    assert_eq!(preview("MODULE_STRING"), r#""foo""#);
+    assert_eq!(preview("LINE"), "3");
+    assert!(completions
+        .iter()
+        .all(|c| c.name != "FILE" || c.position.is_none()));
+
+    assert!(p.macro_completions(&bar, "UNKNOWN").unwrap().is_empty());
+}
+
+#[test]
+fn records_works() {
+    let src = r#"-module(foo).
+-include("tests/conformance/include/conf.hrl").
+-record(state, {
+    conf = #conf{} :: #conf{},
+    callback = fun(X) -> {X, 1} end :: fun((term()) -> {term(), 1}),
+    table = #{a => [1, 2]} :: #{atom() => list()},
+    name :: atom()
+}).
+-record(empty, {}).
+foo() -> #state{}."#;
+    let text = |tokens: &Option<Vec<erl_tokenize::LexicalToken>>| {
+        tokens
+            .as_ref()
+            .map(|t| t.iter().map(|t| t.text()).collect::<Vec<_>>().join(" "))
+    };
+    let records = track_try_unwrap!(pp(src).records().collect::<Result<Vec<_>, _>>());
+    assert_eq!(
+        records.iter().map(|r| &r.name[..]).collect::<Vec<_>>(),
+        ["conf", "state", "empty"]
+    );
+
+    // Records in included files
+    let conf = &records[0];
+    assert!(conf.position.filepath().unwrap().ends_with("conf.hrl"));
+    assert_eq!(conf.position.line(), 4);
+    assert_eq!(text(&conf.fields[1].default).unwrap(), "3");
+
+    let state = &records[1];
+    assert_eq!(state.position.filepath(), None);
+    assert_eq!(state.position.line(), 3);
+    assert_eq!(
+        state.fields.iter().map(|f| &f.name[..]).collect::<Vec<_>>(),
+        ["conf", "callback", "table", "name"]
+    );
+    assert_eq!(
+        text(&state.fields[1].default).unwrap(),
+        "fun ( X ) -> { X , 1 } end"
+    );
+    assert_eq!(
+        text(&state.fields[1].field_type).unwrap(),
+        "fun ( ( term ( ) ) -> { term ( ) , 1 } )"
+    );
+    assert_eq!(
+        text(&state.fields[2].field_type).unwrap(),
+        "# { atom ( ) => list ( ) }"
+    );
+    assert!(state.fields[3].default.is_none());
+    assert_eq!(state.fields[3].position.line(), 7);
+    assert!(records[2].fields.is_empty());
+
+    // Malformed records
+    assert!(pp("-record(foo, {1}).").records().next().unwrap().is_err());
+}
+
+#[test]
+fn attributes_works() {
+    let src = r#"-module(foo).
+-include("tests/conformance/include/conf.hrl").
+-define(API, start/0, stop/1).
+-export([?API]).
+-export_type([t/1]).
+-behaviour(gen_server).
+-compile([export_all, {inline, [f/1]}]).
+-spec(start() -> ok).
+-callback handle(term(), #{a => [1, 2]}) -> ok.
+-type t(A) :: [A].
+-custom({1, 2}).
+start() -> ok."#;
+    let attrs = track_try_unwrap!(pp(src).attributes().collect::<Result<Vec<_>, _>>());
+    assert_eq!(
+        attrs.iter().map(|a| &a.name[..]).collect::<Vec<_>>(),
+        [
+            "module",
+            "include",
+            "record",
+            "export",
+            "export_type",
+            "behaviour",
+            "compile",
+            "spec",
+            "callback",
+            "type",
+            "custom"
+        ]
+    );
+
+    match attrs[1].value {
+        AttributeValue::Include {
+            ref path,
+            ref resolved,
+        } => {
+            assert_eq!(path, "tests/conformance/include/conf.hrl");
+            assert!(resolved.ends_with("conf.hrl"));
+        }
+        _ => panic!("{:?}", attrs[1]),
+    }
+    assert!(attrs[2].position.filepath().unwrap().ends_with("conf.hrl"));
+
+    // The positions of macro-produced entries point to the call site
+    match attrs[3].value {
+        AttributeValue::Export(ref functions) => {
+            let names = functions
+                .iter()
+                .map(|f| (&f.name[..], f.arity, f.position.line(), f.position.column()))
+                .collect::<Vec<_>>();
+            assert_eq!(names, [("start", 0, 4, 10), ("stop", 1, 4, 10)]);
+        }
+        _ => panic!("{:?}", attrs[3]),
+    }
+    match attrs[6].value {
+        AttributeValue::Compile(ref options) => {
+            let options = options.as_list().unwrap();
+            assert_eq!(options[0].as_atom(), Some("export_all"));
+            assert_eq!(options[1].as_tuple().unwrap()[0].as_atom(), Some("inline"));
+        }
+        _ => panic!("{:?}", attrs[6]),
+    }
+    match attrs[7].value {
+        AttributeValue::Spec {
+            ref name, arity, ..
+        } => assert_eq!((&name[..], arity), ("start", 0)),
+        _ => panic!("{:?}", attrs[7]),
+    }
+    match attrs[8].value {
+        AttributeValue::Spec {
+            ref name, arity, ..
+        } => assert_eq!((&name[..], arity), ("handle", 2)),
+        _ => panic!("{:?}", attrs[8]),
+    }
+    match attrs[9].value {
+        AttributeValue::Type {
+            ref name, arity, ..
+        } => assert_eq!((&name[..], arity), ("t", 1)),
+        _ => panic!("{:?}", attrs[9]),
+    }
+
+    // Malformed attributes
+    assert!(pp("-export([foo]).").attributes().next().unwrap().is_err());
+}
+
+#[test]
+fn include_graph_works() {
+    use erl_pp::IncludeGraph;
+    use std::path::Path;
+
+    let project = track_try_unwrap!(Project::open("tests/project"));
+    let mut graph = track_try_unwrap!(IncludeGraph::from_project(&project));
+    let top = Path::new("tests/project/src/top.erl");
+    let top_hrl = Path::new("tests/project/include/top.hrl");
+    let prod_hrl = Path::new("tests/project/include/prod.hrl");
+
+    let includes = graph
+        .includes(top)
+        .map(|e| (&e.path[..], e.include_lib, e.position.line(), e.active))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        includes,
+        [
+            ("top.hrl", false, 2, true),
+            ("common.hrl", false, 3, true),
+            ("dep/include/dep.hrl", true, 4, true),
+            ("sub/include/sub.hrl", true, 5, true)
+        ]
+    );
+    assert_eq!(graph.includes(top).next().unwrap().to, top_hrl);
+
+    // `prod.hrl` is included only if `PROD` is defined
+    let edge = graph.included_by(prod_hrl).next().unwrap();
+    assert_eq!(edge.from, top_hrl);
+    assert!(!edge.active);
+    assert_eq!(graph.transitive_includers(prod_hrl, false), [top_hrl, top]);
+    assert_eq!(graph.modules_including(prod_hrl, false), [top]);
+    assert!(graph.modules_including(prod_hrl, true).is_empty());
+
+    // Files included only by inactive directives are scanned as well
+    let prod_extra_hrl = Path::new("tests/project/include/prod_extra.hrl");
+    let edge = graph.included_by(prod_extra_hrl).next().unwrap();
+    assert_eq!(edge.from, prod_hrl);
+    assert!(!edge.active);
+    assert_eq!(graph.modules_including(prod_extra_hrl, false), [top]);
+    assert!(graph.modules_including(prod_extra_hrl, true).is_empty());
+    assert_eq!(
+        graph.defined_macros(prod_extra_hrl).collect::<Vec<_>>(),
+        ["PROD_EXTRA"]
+    );
+
+    // Paths are normalized
+    let mut lexer = Lexer::new(r#"-include("../include/top.hrl")."#);
+    lexer.set_filepath("tests/project/src/a.erl");
+    let mut normalized = IncludeGraph::new();
+    track_try_unwrap!(normalized.add_module(
+        Path::new("tests/project/./src/a.erl"),
+        &mut Preprocessor::new(lexer)
+    ));
+    let a = Path::new("tests/project/src/a.erl");
+    assert_eq!(normalized.modules().collect::<Vec<_>>(), [a]);
+    assert_eq!(normalized.includes(a).next().unwrap().to, top_hrl);
+    let dotted = Path::new("tests/project/src/../include/top.hrl");
+    assert_eq!(normalized.included_by(dotted).count(), 1);
+    assert_eq!(normalized.transitive_includers(dotted, true), [a]);
+    assert_eq!(normalized.modules_including(dotted, false), [a]);
+
+    // Exports
+    let dot = graph.to_dot();
+    assert!(dot.contains(r#"    "tests/project/src/top.erl" [shape=box];"#));
+    assert!(dot.contains(
+        r#"    "tests/project/include/top.hrl" -> "tests/project/include/prod.hrl" [label="4", style=dashed];"#
+    ));
+    let json = graph.to_json();
+    assert!(json.starts_with(r#"{"nodes":[{"path":"#));
+    assert!(json.contains(
+        r#"{"from":"tests/project/include/top.hrl","to":"tests/project/include/prod.hrl","path":"prod.hrl","include_lib":false,"line":4,"column":1,"active":false}"#
+    ));
+
+    // Special characters in paths are escaped
+    let mut escaped = IncludeGraph::new();
+    let module = Path::new("tests/a\"b\\c\t\n.erl");
+    let mut lexer = Lexer::new(r#"-include("tests/bar.hrl")."#);
+    lexer.set_filepath(module);
+    track_try_unwrap!(escaped.add_module(module, &mut Preprocessor::new(lexer)));
+    assert!(escaped
+        .to_json()
+        .contains(r#"{"path":"tests/a\"b\\c\u0009\n.erl","module":true}"#));
+    assert!(escaped.to_json().contains(
+        r#"{"from":"tests/a\"b\\c\u0009\n.erl","to":"tests/bar.hrl","path":"tests/bar.hrl","#
+    ));
+    assert!(escaped
+        .to_dot()
+        .contains(r#"    "tests/a\"b\\c\u0009\n.erl" -> "tests/bar.hrl" [label="1"];"#));
+
+    // Reverse dependencies
+    let sub_hrl = Path::new("tests/project/apps/sub/include/sub.hrl");
+    let src = r#"-include("tests/project/include/top.hrl").
+-include("tests/project/apps/sub/include/sub.hrl").
+-define(NAME, ?SUB_NAME).
+-ifdef(PROD_ONLY).
+-endif.
+f() -> ?NAME."#;
+    track_try_unwrap!(graph.add_module(Path::new("m.erl"), &mut pp(src)));
+    assert_eq!(
+        graph.used_macros(Path::new("m.erl")).collect::<Vec<_>>(),
+        ["NAME", "PROD", "PROD_ONLY", "SUB_NAME"]
+    );
+    assert_eq!(
+        graph.defined_macros(sub_hrl).collect::<Vec<_>>(),
+        ["SUB_NAME"]
+    );
+    assert_eq!(graph.affected_modules(sub_hrl).len(), 3);
+    assert_eq!(graph.affected_modules_using(sub_hrl, ["SUB_NAME"]).len(), 3);
+    assert_eq!(
+        graph.affected_modules_using(top_hrl, ["PROD_ONLY"]),
+        [Path::new("m.erl")]
+    );
+    assert_eq!(graph.affected_modules(prod_hrl), [Path::new("m.erl"), top]);
+
+    // The same header reached by different paths
+    let mut lexer = Lexer::new(r#"-include("top.hrl"). -ifdef(B_ONLY). -endif."#);
+    lexer.set_filepath("tests/project/src/b.erl");
+    let mut b_pp = Preprocessor::new(lexer);
+    b_pp.include_paths_mut()
+        .push_back("tests/project/include".into());
+    let b = Path::new("tests/project/src/b.erl");
+    track_try_unwrap!(normalized.add_module(b, &mut b_pp));
+    assert_eq!(normalized.includes(b).next().unwrap().to, top_hrl);
+    assert_eq!(normalized.affected_modules(top_hrl), [a, b]);
+    assert_eq!(normalized.affected_modules(dotted), [a, b]);
+    let dotted = Path::new("tests/project/include/./top.hrl");
+    assert_eq!(normalized.affected_modules_using(dotted, ["B_ONLY"]), [b]);
+
+    // Merges the graph of another profile
+    let project = track_try_unwrap!(Project::open_with_profile("tests/project", "prod"));
+    graph.merge(track_try_unwrap!(IncludeGraph::from_project(&project)));
+    assert_eq!(
+        graph.modules_including(prod_hrl, true),
+        [Path::new("m.erl"), top]
+    );
+    assert_eq!(
+        graph
+            .files()
+            .filter(|f| f.ends_with("dep.hrl"))
+            .collect::<Vec<_>>(),
+        [
+            Path::new("tests/project/_build/default/lib/dep/include/dep.hrl"),
+            Path::new("tests/project/_build/prod/lib/dep/include/dep.hrl")
+        ]
+    );
+}
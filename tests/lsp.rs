@@ -0,0 +1,182 @@
+#![cfg(feature = "lsp")]
+extern crate erl_pp;
+extern crate serde_json;
+
+use erl_pp::lsp::Server;
+use serde_json::{json, Value};
+use std::path::Path;
+
+const SRC: &str = r#"-module(foo).
+-include("bar.hrl").
+-define(ADD(A, B), A + B).
+-ifdef(UNDEFINED).
+old() -> ok.
+-endif.
+-warning("check me").
+f() -> ?ADD(1, 2).
+g() -> ?ADD(3, ?LINE).
+"#;
+
+/// Runs a server with the given messages, and returns the messages written by the server.
+fn run(messages: &[Value]) -> Vec<Value> {
+    let mut input = Vec::new();
+    for m in messages {
+        let content = m.to_string();
+        input.extend(format!("Content-Length: {}\r\n\r\n{}", content.len(), content).bytes());
+    }
+    let mut output = Vec::new();
+    Server::new(&input[..], &mut output).run().unwrap();
+
+    let mut reader = &output[..];
+    let mut messages = Vec::new();
+    while !reader.is_empty() {
+        let header_end = reader.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let header = std::str::from_utf8(&reader[..header_end]).unwrap();
+        let length: usize = header["Content-Length: ".len()..].parse().unwrap();
+        let content = &reader[header_end + 4..header_end + 4 + length];
+        messages.push(serde_json::from_slice(content).unwrap());
+        reader = &reader[header_end + 4 + length..];
+    }
+    messages
+}
+
+fn response(messages: &[Value], id: u64) -> &Value {
+    let m = messages.iter().find(|m| m["id"] == json!(id)).unwrap();
+    &m["result"]
+}
+
+#[test]
+fn lsp_server_works() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lsp_doc.erl");
+    let uri = format!("file://{}", path.display());
+    let doc = json!({ "uri": uri });
+    let at = |id: u64, method: &str, line: u64, character: u64| {
+        json!({
+            "jsonrpc": "2.0", "id": id, "method": method,
+            "params": {
+                "textDocument": doc,
+                "position": {"line": line, "character": character},
+                "context": {"includeDeclaration": true},
+            },
+        })
+    };
+    let tests = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let vars_uri = format!("file://{}", tests.join("lsp_vars.erl").display());
+    let messages = run(&[
+        json!({
+            "jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": {"initializationOptions": {"pathVariables": {"TESTS": tests}}},
+        }),
+        json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+        json!({
+            "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": "erlang", "version": 1, "text": SRC}},
+        }),
+        at(2, "textDocument/hover", 7, 9),
+        at(3, "textDocument/definition", 7, 9),
+        at(4, "textDocument/references", 7, 9),
+        json!({
+            "jsonrpc": "2.0", "id": 5, "method": "textDocument/semanticTokens/full",
+            "params": {"textDocument": doc},
+        }),
+        json!({"jsonrpc": "2.0", "id": 6, "method": "textDocument/unknown", "params": {}}),
+        json!({
+            "jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": {"textDocument": {
+                "uri": vars_uri, "languageId": "erlang", "version": 1,
+                "text": "-include(\"$TESTS/bar.hrl\").\n",
+            }},
+        }),
+        json!({
+            "jsonrpc": "2.0", "id": 8, "method": "textDocument/definition",
+            "params": {"textDocument": {"uri": vars_uri}, "position": {"line": 0, "character": 12}},
+        }),
+        json!({"jsonrpc": "2.0", "id": 7, "method": "shutdown"}),
+        json!({"jsonrpc": "2.0", "method": "exit"}),
+    ]);
+
+    // initialize
+    let capabilities = &response(&messages, 1)["capabilities"];
+    assert_eq!(capabilities["hoverProvider"], json!(true));
+    assert_eq!(capabilities["textDocumentSync"], json!(1));
+
+    // textDocument/publishDiagnostics
+    let diagnostics = messages
+        .iter()
+        .find(|m| m["method"] == json!("textDocument/publishDiagnostics"))
+        .unwrap();
+    let diagnostics = diagnostics["params"]["diagnostics"].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0]["severity"], json!(2));
+    assert_eq!(diagnostics[0]["message"], json!("check me"));
+    assert_eq!(diagnostics[0]["range"]["start"]["line"], json!(6));
+
+    // textDocument/hover
+    let hover = response(&messages, 2);
+    let contents = hover["contents"]["value"].as_str().unwrap();
+    assert!(
+        contents.contains("-define(ADD(A,B), A + B)."),
+        "{}",
+        contents
+    );
+    assert!(contents.contains("1 + 2"), "{}", contents);
+    assert_eq!(
+        hover["range"],
+        json!({"start": {"line": 7, "character": 7}, "end": {"line": 7, "character": 11}})
+    );
+
+    // textDocument/definition
+    let definition = response(&messages, 3);
+    assert_eq!(definition["uri"], json!(uri));
+    assert_eq!(
+        definition["range"],
+        json!({"start": {"line": 2, "character": 8}, "end": {"line": 2, "character": 11}})
+    );
+
+    // textDocument/references
+    let references = response(&messages, 4).as_array().unwrap();
+    let lines = references
+        .iter()
+        .map(|r| r["range"]["start"]["line"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines, [2, 7, 8]);
+
+    // textDocument/semanticTokens/full
+    assert_eq!(response(&messages, 5)["data"], json!([4, 0, 12, 0, 0]));
+
+    // Unknown method
+    let unknown = messages.iter().find(|m| m["id"] == json!(6)).unwrap();
+    assert_eq!(unknown["error"]["code"], json!(-32601));
+
+    // textDocument/definition (an included file whose path contains a variable)
+    let bar = tests.join("bar.hrl").canonicalize().unwrap();
+    assert_eq!(
+        response(&messages, 8)["uri"],
+        json!(format!("file://{}", bar.display()))
+    );
+
+    // shutdown
+    assert_eq!(*response(&messages, 7), Value::Null);
+}
+
+#[test]
+fn lsp_parse_error_works() {
+    let mut input = Vec::new();
+    for (header, content) in &[
+        ("Content-Length: 9", "{invalid}"),
+        ("Content-Type: application/json", ""),
+        ("Content-Length: x", ""),
+        ("Content-Length: 2", "{}"),
+    ] {
+        input.extend(format!("{}\r\n\r\n{}", header, content).bytes());
+    }
+    let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#;
+    input.extend(format!("Content-Length: {}\r\n\r\n{}", initialize.len(), initialize).bytes());
+    input.extend(b"Content-Length: 100\r\n\r\n{\"jsonrpc\"");
+
+    let mut output = Vec::new();
+    Server::new(&input[..], &mut output).run().unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.matches(r#""code":-32700"#).count(), 4);
+    assert!(output.contains(r#""hoverProvider":true"#));
+}
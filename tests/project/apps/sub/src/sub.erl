@@ -0,0 +1,4 @@
+-module(sub).
+-include("sub.hrl").
+
+value() -> {?SUB_NAME, ?SUB, ?VSN}.
@@ -0,0 +1,13 @@
+-module(top).
+-include("top.hrl").
+-include("common.hrl").
+-include_lib("dep/include/dep.hrl").
+-include_lib("sub/include/sub.hrl").
+
+-ifdef(PROD).
+mode() -> prod.
+-else.
+mode() -> ?COMMON.
+-endif.
+
+info() -> {?VSN, ?TEST, ?DEP, ?SUB_NAME, #top{}}.
@@ -0,0 +1,4 @@
+-module(a).
+-include("priv.hrl").
+
+name() -> ?PRIV_NAME.
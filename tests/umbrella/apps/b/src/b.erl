@@ -0,0 +1,4 @@
+-module(b).
+-include("priv.hrl").
+
+name() -> ?PRIV_NAME.